@@ -0,0 +1,88 @@
+use crate::geometry::{Triangle, Transform};
+use crate::world::Material;
+use crate::units::{self, Unit};
+use crate::mesh;
+use crate::repair;
+use crate::error::Result;
+use crate::THE_BOX;
+
+use std::path::Path;
+
+use cgmath::Vector3;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+/// Edge of `THE_BOX`
+const BOX_SIZE: f32 = 5.0;
+
+/// Triangles, placement and material of an enclosure
+pub type Shell = (Vec<Triangle>, Transform, Material);
+
+/// What surrounds a scene and stops the rays leaving it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Enclosure {
+    /// A box `size` across centred on `pos`. Without a size the box is 5 cm
+    /// across, without a position it is centred on the x axis with its floor
+    /// 1 cm below it.
+    Box {
+        #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_point")]
+        #[schemars(with = "Option<[units::Length; 3]>")]
+        size: Option<[f32; 3]>,
+        #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_point")]
+        #[schemars(with = "Option<[units::Length; 3]>")]
+        pos: Option<[f32; 3]>,
+        #[serde(default = "solid")]
+        material: Material,
+    },
+    /// A ply, obj or stl mesh, relative to the scene file, moved to `pos`
+    Mesh {
+        file: String,
+        #[serde(default, deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        pos: [f32; 3],
+        #[serde(default = "solid")]
+        material: Material,
+    },
+    /// Nothing, rays that leave the scene escape
+    None,
+}
+
+fn solid() -> Material {
+    Material::Solid
+}
+
+impl Default for Enclosure {
+    fn default() -> Self {
+        Self::Box { size: None, pos: None, material: Material::Solid }
+    }
+}
+
+impl Enclosure {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The triangles, placement and material of the enclosure in a scene of
+    /// `unit` read from `dir`, or none when there is no enclosure
+    pub fn model(&self, dir: &Path, unit: Option<Unit>) -> Result<Option<Shell>> {
+        let s = units::scale(unit);
+
+        Ok(match self {
+            Self::Box { size, pos, material } => {
+                let size = size.map_or(Vector3::new(BOX_SIZE, BOX_SIZE, BOX_SIZE) * s, Vector3::from);
+                let pos = pos.map_or(Vector3::new(0.0, size.y / 2.0 - s, 0.0), Vector3::from);
+
+                // `THE_BOX` runs from the origin to `BOX_SIZE` on every axis
+                let transform = Transform::at(pos - size / 2.0).scaled(size / BOX_SIZE);
+                Some((THE_BOX.to_vec(), transform, *material))
+            }
+            Self::Mesh { file, pos, material } => {
+                let file = dir.join(file).to_string_lossy().into_owned();
+                let mut mesh = mesh::read_mesh(&file)?;
+                repair::repair(&mut mesh);
+                Some((mesh.triangles(), Transform::at((*pos).into()), *material))
+            }
+            Self::None => None,
+        })
+    }
+}
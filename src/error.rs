@@ -0,0 +1,125 @@
+use crate::validate::Diagnostic;
+
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong loading, tracing or rendering a scene
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be read or written
+    Io { path: String, source: io::Error },
+    /// A scene or settings file is not valid, at the line and column when
+    /// they are known
+    Parse { path: String, line: Option<usize>, column: Option<usize>, message: String },
+    /// A mesh file is not valid
+    Mesh { path: String, line: Option<usize>, message: String },
+    /// The scene failed validation, with every error found in it
+    Invalid { path: String, diagnostics: Vec<Diagnostic> },
+    /// The scene can not be traced or analysed as asked
+    Scene(String),
+    /// The world was traced before its kd-tree was built
+    NoKdTree,
+    /// Setting up or drawing with the gpu failed
+    Vulkan(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Self::Io { path: path.to_string(), source }
+    }
+
+    pub fn yaml(path: &str, e: serde_yaml::Error) -> Self {
+        let location = e.location();
+        Self::Parse {
+            path: path.to_string(),
+            line: location.as_ref().map(|l| l.line()),
+            column: location.as_ref().map(|l| l.column()),
+            message: e.to_string(),
+        }
+    }
+
+    pub fn json(path: &str, e: serde_json::Error) -> Self {
+        let known = e.line() > 0;
+        Self::Parse {
+            path: path.to_string(),
+            line: known.then(|| e.line()),
+            column: known.then(|| e.column()),
+            message: e.to_string(),
+        }
+    }
+
+    pub fn toml(path: &str, e: toml::de::Error) -> Self {
+        let location = e.line_col();
+        Self::Parse {
+            path: path.to_string(),
+            line: location.map(|(line, _)| line + 1),
+            column: location.map(|(_, column)| column + 1),
+            message: e.to_string(),
+        }
+    }
+
+    pub fn ron(path: &str, e: ron::error::SpannedError) -> Self {
+        Self::Parse {
+            path: path.to_string(),
+            line: Some(e.position.line),
+            column: Some(e.position.col),
+            message: e.code.to_string(),
+        }
+    }
+
+    pub fn mesh(path: &str, line: Option<usize>, message: impl Into<String>) -> Self {
+        Self::Mesh { path: path.to_string(), line, message: message.into() }
+    }
+
+    pub fn vulkan<E: fmt::Debug>(e: E) -> Self {
+        Self::Vulkan(format!("{e:?}"))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{path}: {source}"),
+            Self::Parse { path, line, column, message } => {
+                write!(f, "{path}")?;
+                if let Some(line) = line {
+                    write!(f, ":{line}")?;
+                }
+                if let Some(column) = column {
+                    write!(f, ":{column}")?;
+                }
+
+                // serde_yaml, serde_json and toml repeat the location at the
+                // end of their messages
+                let message = match message.rfind(" at line ") {
+                    Some(i) if line.is_some() => &message[..i],
+                    _ => message,
+                };
+                write!(f, ": {message}")
+            }
+            Self::Mesh { path, line: Some(line), message } => write!(f, "{path}:{line}: {message}"),
+            Self::Mesh { path, line: None, message } => write!(f, "{path}: {message}"),
+            Self::Invalid { path, diagnostics } => {
+                write!(f, "{path}: invalid scene")?;
+                for d in diagnostics {
+                    write!(f, "\n  {}", d.describe(path))?;
+                }
+                Ok(())
+            }
+            Self::Scene(message) => write!(f, "{message}"),
+            Self::NoKdTree => write!(f, "the kd-tree must be built before tracing"),
+            Self::Vulkan(message) => write!(f, "vulkan: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
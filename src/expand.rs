@@ -0,0 +1,286 @@
+use crate::light::Light;
+use crate::scene::{Scene, Format};
+use crate::validate::Diagnostic;
+use crate::units;
+use crate::error::{Error, Result};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use cgmath::{Vector3, VectorSpace};
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+/// A copy of a defined lens, optionally moved or tilted
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Instance {
+    /// Name of the lens in the scene's definitions
+    pub lens: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_point")]
+    #[schemars(with = "Option<[units::Length; 3]>")]
+    pub pos: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tilt: Option<[f32; 2]>,
+}
+
+/// A block of lenses or lights generated when the scene is loaded
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub enum Generator {
+    /// `count` lasers shining along `dir`, spaced evenly from `from` to `to`
+    Lasers {
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        from: [f32; 3],
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        to: [f32; 3],
+        count: usize,
+        dir: [f32; 3],
+    },
+    /// `count` point lights spaced evenly from `from` to `to`
+    Points {
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        from: [f32; 3],
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        to: [f32; 3],
+        count: usize,
+    },
+    /// `rows` by `columns` copies of a defined lens across the yz plane,
+    /// `pitch` apart and centred on `pos`. Rows run along y and columns
+    /// along z.
+    LensArray {
+        lens: String,
+        rows: usize,
+        columns: usize,
+        #[serde(deserialize_with = "units::length")]
+        #[schemars(with = "units::Length")]
+        pitch: f32,
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        pos: [f32; 3],
+    },
+}
+
+/// Where every lens and light of an expanded scene came from, as paths into
+/// the scene as written
+#[derive(Clone, Default)]
+pub struct Origins {
+    pub lenses: Vec<String>,
+    pub lights: Vec<String>,
+    pub meshes: Vec<String>,
+}
+
+impl Origins {
+    /// Index in the scene's own `lenses` of expanded lens `i`, none when it
+    /// came from an include, instance or generator
+    pub fn written_lens(&self, i: usize) -> Option<usize> {
+        self.lenses.get(i)?
+            .strip_prefix("lenses[")?
+            .strip_suffix(']')?
+            .parse().ok()
+    }
+}
+
+/// Expand the includes, instances and generators of a scene read from
+/// `path` into its lenses, lights and meshes. Includes come first, then the
+/// scene's own lenses, lights and meshes, then instances and then
+/// generators, each in the order they are written. Problems with the scene's own templates are
+/// returned as diagnostics, included files that can not be loaded are errors.
+pub fn expand(scene: &mut Scene, path: &str) -> Result<Vec<Diagnostic>> {
+    expand_in(scene, path, &mut vec![])
+}
+
+fn expand_in(scene: &mut Scene, path: &str, stack: &mut Vec<PathBuf>) -> Result<Vec<Diagnostic>> {
+    let canonical = Path::new(path).canonicalize().map_err(|e| Error::io(path, e))?;
+    if stack.contains(&canonical) {
+        return Err(Error::Scene(format!("{path}: is part of an include cycle")));
+    }
+    stack.push(canonical);
+
+    let mut problems = vec![];
+    let mut lenses = vec![];
+    let mut lights = vec![];
+    let mut meshes = vec![];
+    let mut origins = Origins::default();
+    let mut definitions = BTreeMap::new();
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    for (k, include) in scene.include.iter().enumerate() {
+        let file = dir.join(include).to_string_lossy().into_owned();
+        let source = std::fs::read_to_string(&file).map_err(|e| Error::io(&file, e))?;
+        let mut included = Scene::parse_in(&file, &source, Format::from_path(&file), scene.unit)?;
+
+        let nested = expand_in(&mut included, &file, stack)?;
+        if !nested.is_empty() {
+            return Err(Error::Invalid { path: file, diagnostics: nested });
+        }
+
+        let origin = format!("include[{k}]");
+        origins.lenses.extend(included.lenses.iter().map(|_| origin.clone()));
+        origins.lights.extend(included.lights.iter().map(|_| origin.clone()));
+        origins.meshes.extend(included.meshes.iter().map(|_| origin.clone()));
+        lenses.append(&mut included.lenses);
+        lights.append(&mut included.lights);
+
+        // Mesh files are named relative to the included scene
+        let base = Path::new(include).parent().unwrap_or(Path::new(""));
+        meshes.extend(included.meshes.into_iter().map(|mut m| {
+            m.file = base.join(&m.file).to_string_lossy().into_owned();
+            m
+        }));
+        definitions.append(&mut included.definitions);
+    }
+
+    definitions.append(&mut scene.definitions.clone());
+
+    origins.lenses.extend((0..scene.lenses.len()).map(|i| format!("lenses[{i}]")));
+    origins.lights.extend((0..scene.lights.len()).map(|i| format!("lights[{i}]")));
+    origins.meshes.extend((0..scene.meshes.len()).map(|i| format!("meshes[{i}]")));
+    lenses.append(&mut scene.lenses);
+    lights.append(&mut scene.lights);
+    meshes.append(&mut scene.meshes);
+
+    let define = |name: &str, path: String, problems: &mut Vec<Diagnostic>| {
+        let lens = definitions.get(name).cloned();
+        if lens.is_none() {
+            problems.push(Diagnostic::error(path, format!("no lens is defined as `{name}`")));
+        }
+        lens
+    };
+
+    for (i, instance) in scene.instances.iter().enumerate() {
+        if let Some(mut lens) = define(&instance.lens, format!("instances[{i}].lens"), &mut problems) {
+            lens.pos = instance.pos.unwrap_or(lens.pos);
+            lens.tilt = instance.tilt.unwrap_or(lens.tilt);
+            lenses.push(lens);
+            origins.lenses.push(format!("instances[{i}]"));
+        }
+    }
+
+    for (i, generator) in scene.generate.iter().enumerate() {
+        let origin = format!("generate[{i}]");
+        match generator {
+            Generator::Lasers { from, to, count, dir } => {
+                lights.extend(spaced(*from, *to, *count).map(|p| Light::Laser(p, *dir)));
+                origins.lights.extend((0..*count).map(|_| origin.clone()));
+            }
+            Generator::Points { from, to, count } => {
+                lights.extend(spaced(*from, *to, *count).map(Light::Point));
+                origins.lights.extend((0..*count).map(|_| origin.clone()));
+            }
+            Generator::LensArray { lens, rows, columns, pitch, pos } => {
+                let Some(lens) = define(lens, format!("{origin}.lens"), &mut problems) else { continue };
+                for r in 0..*rows {
+                    for c in 0..*columns {
+                        let mut lens = lens.clone();
+                        lens.pos = [
+                            pos[0],
+                            pos[1] + pitch * (r as f32 - (*rows as f32 - 1.0) / 2.0),
+                            pos[2] + pitch * (c as f32 - (*columns as f32 - 1.0) / 2.0),
+                        ];
+                        lenses.push(lens);
+                        origins.lenses.push(origin.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    // The expanded scene stands on its own, the definitions are kept only
+    // for reference. Mesh files stay relative to this scene.
+    scene.lenses = lenses;
+    scene.lights = lights;
+    scene.meshes = meshes;
+    scene.definitions = definitions;
+    scene.include.clear();
+    scene.instances.clear();
+    scene.generate.clear();
+    scene.origins = origins;
+
+    stack.pop();
+    Ok(problems)
+}
+
+/// `count` points evenly spaced from `from` to `to`, both included
+fn spaced(from: [f32; 3], to: [f32; 3], count: usize) -> impl Iterator<Item = [f32; 3]> {
+    let (from, to) = (Vector3::from(from), Vector3::from(to));
+    (0..count).map(move |i| {
+        let t = if count > 1 { i as f32 / (count - 1) as f32 } else { 0.0 };
+        from.lerp(to, t).into()
+    })
+}
+
+/// Map a diagnostic about the expanded scene back onto the template it was
+/// expanded from
+pub fn relocate(d: &mut Diagnostic, origins: &Origins) {
+    for (list, origin) in [("lenses", &origins.lenses), ("lights", &origins.lights), ("meshes", &origins.meshes)] {
+        let Some(rest) = d.path.strip_prefix(list).and_then(|r| r.strip_prefix('[')) else { continue };
+        let Some(end) = rest.find(']') else { continue };
+        let Some(from) = rest[..end].parse::<usize>().ok().and_then(|i| origin.get(i)) else { continue };
+
+        if *from != format!("{list}[{}]", &rest[..end]) {
+            d.message = format!("{}: {}", d.path, d.message);
+            d.path = from.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the scenes to a directory of their own in the temporary
+    /// directory, then load and expand the first one
+    fn expanded(name: &str, scenes: &[(&str, &str)]) -> Scene {
+        let dir = std::env::temp_dir().join(format!("lenses-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, source) in scenes {
+            std::fs::write(dir.join(file), source).unwrap();
+        }
+
+        let path = dir.join(scenes[0].0).to_string_lossy().into_owned();
+        let scene = Scene::load(&path).map(|(scene, _)| scene);
+        std::fs::remove_dir_all(&dir).unwrap();
+        scene.unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    const LIBRARY_MM: &str = "version: 5\nunit: mm\nlenses:\n  - { radius: 5, left: !Convex 1, right: Flat, pos: [0, -1, 0] }\n";
+    const LIBRARY: &str = "version: 5\nlenses:\n  - { radius: 0.5, left: !Convex 0.1, right: Flat, pos: [0, -0.1, 0] }\n";
+
+    #[test]
+    fn includes_are_converted_to_the_unit_of_the_scene() {
+        let scene = expanded("includes-m", &[
+            ("scene.yaml", "version: 5\nunit: m\ninclude: [library.yaml]\n"),
+            ("library.yaml", LIBRARY_MM),
+        ]);
+        assert_eq!(scene.unit, Some(units::Unit::M));
+        assert!((scene.lenses[0].radius - 0.005).abs() < 1e-7);
+        assert!((scene.lenses[0].pos[1] + 0.001).abs() < 1e-7);
+    }
+
+    #[test]
+    fn scenes_without_a_unit_count_as_centimetres() {
+        // A scene in mm including one without a unit
+        let scene = expanded("includes-unitless", &[
+            ("scene.yaml", "version: 5\nunit: mm\ninclude: [library.yaml]\n"),
+            ("library.yaml", LIBRARY),
+        ]);
+        assert!((scene.lenses[0].radius - 5.0).abs() < 1e-5);
+        assert!((scene.lenses[0].pos[1] + 1.0).abs() < 1e-5);
+
+        // A scene without a unit including one in mm, next to its own lens
+        let scene = expanded("includes-mm", &[
+            ("scene.yaml", &format!("{}include: [library.yaml]\n", LIBRARY.replace("[0,", "[2,"))),
+            ("library.yaml", LIBRARY_MM),
+        ]);
+        assert!(scene.unit.is_none());
+        assert_eq!(scene.origins.lenses, ["include[0]", "lenses[0]"]);
+        for lens in scene.lenses.iter() {
+            assert!((lens.radius - 0.5).abs() < 1e-6);
+            assert!((lens.pos[1] + 0.1).abs() < 1e-6);
+        }
+        assert_eq!(scene.lenses[1].pos[0], 2.0);
+    }
+}
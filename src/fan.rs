@@ -0,0 +1,126 @@
+use crate::geometry::Plane;
+use crate::lenses::Lens;
+use crate::paraxial::System;
+use crate::focus::exit_ray;
+use crate::world::World;
+
+use std::io::{self, Write};
+
+/// Aberrations of a single ray of a meridional fan
+#[derive(Debug, Clone, Copy)]
+pub struct FanRay {
+    /// Height of the ray entering the system, relative to the lens radius
+    pub pupil: f32,
+    /// Height of the ray entering the system
+    pub height: f32,
+    /// Height above the axis where the ray meets the image plane
+    pub transverse: f32,
+    /// x coordinate where the ray crosses the axis
+    pub crossing: Option<f32>,
+    /// Distance from the paraxial focus to the axis crossing, along the
+    /// direction of travel
+    pub longitudinal: Option<f32>,
+}
+
+/// Measure the rays of a meridional fan in y traced through the system
+pub fn ray_fan(world: &World, lenses: &[Lens], system: &System, image: &Plane) -> Vec<FanRay> {
+    let first = &lenses[system.order[0]];
+    let axis_y = first.pos[1];
+    let focus = system.focal_point();
+
+    let mut fan = world.paths.iter()
+        .filter_map(|path| {
+            let height = path.segments.first()?.ray.origin.y - axis_y;
+            let (_, exit) = exit_ray(path)?;
+
+            // Follow the ray backwards for virtual images
+            let t = image.intersect_line(&exit)?;
+            let transverse = (exit.origin + exit.dir * t).y - axis_y;
+
+            // Rays along the axis or leaving parallel to it never cross it
+            let crossing = if height.abs() > 1e-6 && exit.dir.y.abs() > 1e-6 {
+                let t = (axis_y - exit.origin.y) / exit.dir.y;
+                Some(exit.origin.x + exit.dir.x * t)
+            } else {
+                None
+            };
+
+            Some(FanRay {
+                pupil: height / first.radius,
+                height,
+                transverse,
+                crossing,
+                longitudinal: crossing.map(|x| focus - x),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    fan.sort_by(|a, b| a.pupil.total_cmp(&b.pupil));
+    fan
+}
+
+pub fn write_csv<W: Write>(fan: &[FanRay], mut w: W) -> io::Result<()> {
+    writeln!(w, "pupil,height,transverse,crossing,longitudinal")?;
+    for r in fan {
+        let opt = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+        writeln!(
+            w, "{},{},{},{},{}",
+            r.pupil, r.height, r.transverse, opt(r.crossing), opt(r.longitudinal)
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::LensSide;
+    use crate::paraxial::axial_fan;
+    use crate::world::testing::{lens, traced};
+
+    use cgmath::Vector3;
+
+    #[test]
+    fn fans_are_symmetric_with_undercorrected_spherical_aberration() {
+        let lenses = [lens(1.0, LensSide::Convex(0.15), LensSide::Convex(0.15), None, [0.0, 0.2, 0.0])];
+        let system = System::new(&lenses).unwrap();
+        let world = traced(&lenses, axial_fan(&lenses, &system, 0.8, 16));
+
+        let image = Plane::new(Vector3::new(system.focal_point(), 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let fan = ray_fan(&world, &lenses, &system, &image);
+        assert_eq!(fan.len(), 16);
+        assert!(fan.windows(2).all(|w| w[0].pupil < w[1].pupil));
+        assert!((fan[0].pupil + 0.75).abs() < 1e-4 && (fan[15].pupil - 0.75).abs() < 1e-4);
+
+        for (a, b) in fan.iter().zip(fan.iter().rev()) {
+            assert!((a.pupil + b.pupil).abs() < 1e-4);
+            assert!((a.transverse + b.transverse).abs() < 1e-3, "{} {}", a.transverse, b.transverse);
+        }
+
+        // Marginal rays cross the axis before the paraxial focus, and well
+        // before rays near the axis. Those land within the few percent the
+        // facets of the surfaces move them by.
+        let (marginal, inner) = (fan[15], fan[8]);
+        let efl = system.first_order().efl;
+        assert!(marginal.transverse < 0.0);
+        assert!(marginal.longitudinal.unwrap() < -0.05 * efl);
+        assert!(inner.longitudinal.unwrap().abs() < 0.05 * efl);
+        assert!(inner.crossing.unwrap() < lenses[0].pos[0]);
+    }
+
+    #[test]
+    fn writes_a_row_per_ray() {
+        let fan = [
+            FanRay { pupil: -1.0, height: -0.5, transverse: 0.01, crossing: Some(-4.0), longitudinal: Some(-0.1) },
+            FanRay { pupil: 0.0, height: 0.0, transverse: 0.0, crossing: None, longitudinal: None },
+        ];
+
+        let mut out = vec![];
+        write_csv(&fan, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "pupil,height,transverse,crossing,longitudinal\n-1,-0.5,0.01,-4,-0.1\n0,0,0,,\n"
+        );
+    }
+}
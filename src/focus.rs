@@ -0,0 +1,215 @@
+use crate::geometry::{Plane, Ray};
+use crate::light::Field;
+use crate::world::{World, RayPath};
+
+use cgmath::prelude::*;
+use cgmath::{Matrix3, Vector3};
+use cgmath::dot;
+
+/// Where a bundle of rays leaving a lens comes to focus
+#[derive(Debug, Clone)]
+pub struct Focus {
+    pub field: Field,
+    /// Entity of the lens the rays left last
+    pub lens: usize,
+    pub rays: usize,
+    /// Least squares point of closest approach of all the rays
+    pub point: Vector3<f32>,
+    /// Centre and normal of the plane of minimum RMS spot size
+    pub best: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    /// RMS spot radius on the best focus plane
+    pub rms: f32,
+    /// Distance from the lens position, the centre of the lens rather than
+    /// a principal plane, to the best focus plane. This is not the effective
+    /// focal length, which `paraxial::FirstOrder` gives.
+    pub lens_distance: f32,
+    /// Distance from the rear vertex of the lens to the best focus plane
+    pub bfl: f32,
+}
+
+impl Focus {
+    pub fn plane(&self) -> Plane {
+        Plane::new(self.best, self.normal)
+    }
+}
+
+/// The ray leaving the last glass entity on a path, along with that entity
+pub fn exit_ray(path: &RayPath) -> Option<(usize, Ray)> {
+    let exit = &path.segments[path.exit()?];
+    let lens = path.segments[exit.parent?].medium?;
+
+    Some((lens, exit.ray))
+}
+
+/// Point minimising the summed squared distance to every ray, or None if
+/// the rays are all parallel
+pub fn closest_approach(rays: &[Ray]) -> Option<Vector3<f32>> {
+    let mut a = Matrix3::from_value(0.0);
+    let mut b = Vector3::new(0.0, 0.0, 0.0);
+
+    for r in rays {
+        let d = r.dir;
+        let proj = Matrix3::identity() - Matrix3::from_cols(d * d.x, d * d.y, d * d.z);
+        a += proj;
+        b += proj * r.origin;
+    }
+
+    let inv = a.invert()?;
+    Some(inv * b)
+}
+
+/// Find the focus of the rays of every field leaving each lens converging
+/// or diverging. Collimated output has no focus and is skipped.
+pub fn find_focus(world: &World) -> Vec<Focus> {
+    let mut fields: Vec<(Field, usize, Vec<Ray>)> = vec![];
+
+    for path in world.paths.iter() {
+        let (lens, r) = match exit_ray(path) {
+            Some(e) => e,
+            None => continue,
+        };
+
+        let field = world.lights[path.light].field();
+        match fields.iter_mut().find(|f| f.0 == field && f.1 == lens) {
+            Some(f) => f.2.push(r),
+            None => fields.push((field, lens, vec![r])),
+        }
+    }
+
+    fields.into_iter()
+        .filter_map(|(field, lens, rays)| focus_bundle(world, field, lens, &rays))
+        .collect()
+}
+
+fn focus_bundle(world: &World, field: Field, lens: usize, rays: &[Ray]) -> Option<Focus> {
+    if rays.len() < 2 {
+        return None;
+    }
+
+    let point = closest_approach(rays)?;
+
+    // Measure along the mean direction of the bundle from the lens
+    let c = world.transforms[lens].position;
+    let m = rays.iter()
+        .fold(Vector3::new(0.0, 0.0, 0.0), |a, r| a + r.dir)
+        .normalize();
+
+    // Each ray crosses the plane at distance s from the lens at a + s*b,
+    // so the spot variance is quadratic in s
+    let (a, b): (Vec<_>, Vec<_>) = rays.iter()
+        .map(|r| {
+            let cos = dot(r.dir, m);
+            (r.origin + r.dir * (dot(c - r.origin, m) / cos), r.dir / cos)
+        })
+        .unzip();
+
+    let n = rays.len() as f32;
+    let a_mean = a.iter().fold(Vector3::new(0.0, 0.0, 0.0), |s, v| s + v) / n;
+    let b_mean = b.iter().fold(Vector3::new(0.0, 0.0, 0.0), |s, v| s + v) / n;
+
+    let (ab, bb) = a.iter().zip(b.iter())
+        .map(|(a, b)| (a - a_mean, b - b_mean))
+        .fold((0.0, 0.0), |(ab, bb), (a, b)| (ab + dot(a, b), bb + dot(b, b)));
+
+    if bb <= f32::EPSILON {
+        return None;
+    }
+
+    let s = -ab / bb;
+    let rms = a.iter().zip(b.iter())
+        .map(|(a, b)| (a - a_mean + (b - b_mean) * s).magnitude2())
+        .sum::<f32>();
+    let rms = (rms / n).sqrt();
+
+    let best = a_mean + b_mean * s;
+
+    // The rear vertex is where the ray nearest the axis left the lens
+    let vertex = rays.iter()
+        .map(|r| r.origin)
+        .min_by(|p, q| {
+            let dp = (p - c) - m * dot(p - c, m);
+            let dq = (q - c) - m * dot(q - c, m);
+            dp.magnitude2().total_cmp(&dq.magnitude2())
+        })?;
+
+    Some(Focus {
+        field,
+        lens,
+        rays: rays.len(),
+        point,
+        best,
+        normal: m,
+        rms,
+        lens_distance: s,
+        bfl: dot(best - vertex, m),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::{Lens, LensSide};
+    use crate::light::Light;
+    use crate::paraxial::System;
+    use crate::world::testing::{lens, traced};
+
+    /// Lasers along -x on a square grid within `r` of the axis of `lens`
+    fn bundle(lens: &Lens, r: f32) -> Vec<Light> {
+        let n = 9;
+        (0..n).flat_map(|i| (0..n).map(move |j| (i, j)))
+            .map(|(i, j)| (r * (2.0 * i as f32 / (n - 1) as f32 - 1.0), r * (2.0 * j as f32 / (n - 1) as f32 - 1.0)))
+            .filter(|(y, z)| y * y + z * z <= r * r)
+            .map(|(y, z)| Light::Laser([lens.pos[0] + 1.0, lens.pos[1] + y, lens.pos[2] + z], [-1.0, 0.0, 0.0]))
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_paraxial_focus_of_a_symmetric_bundle() {
+        let l = lens(1.0, LensSide::Convex(0.1), LensSide::Convex(0.1), None, [0.0, 0.5, 0.0]);
+        let system = System::new(std::slice::from_ref(&l)).unwrap();
+        let first_order = system.first_order();
+
+        let world = traced(std::slice::from_ref(&l), bundle(&l, 0.3));
+        let foci = find_focus(&world);
+        assert_eq!(foci.len(), 1);
+
+        let f = &foci[0];
+        assert_eq!(f.rays, world.paths.len());
+        assert!((f.normal - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 1e-3);
+        // On the axis, within the blur of the faceted surfaces and the
+        // spherical aberration of the outer rays
+        assert!((f.best.y - 0.5).abs() < 1e-3 && f.best.z.abs() < 1e-3, "{:?}", f.best);
+        assert!((f.best.x - system.focal_point()).abs() < 0.02 * first_order.efl, "{} != {}", f.best.x, system.focal_point());
+        assert!((f.bfl - first_order.bfl).abs() < 0.02 * first_order.efl, "{} != {}", f.bfl, first_order.bfl);
+        assert!((f.lens_distance - (l.pos[0] - f.best.x)).abs() < 1e-3);
+        assert!(f.rms < 0.01 * first_order.efl);
+    }
+
+    #[test]
+    fn finds_a_focus_for_each_lens() {
+        let lenses = [
+            lens(0.4, LensSide::Convex(0.05), LensSide::Flat, None, [0.0, -0.5, 0.0]),
+            lens(0.4, LensSide::Convex(0.1), LensSide::Flat, None, [0.0, 0.5, 0.0]),
+        ];
+        let lights = lenses.iter().flat_map(|l| bundle(l, 0.1)).collect();
+
+        let foci = find_focus(&traced(&lenses, lights));
+        assert_eq!(foci.len(), 2);
+        assert_eq!(foci[0].field, foci[1].field);
+        assert_ne!(foci[0].lens, foci[1].lens);
+
+        for (f, l) in foci.iter().zip(&lenses) {
+            assert!((f.best.y - l.pos[1]).abs() < 1e-3);
+        }
+        // The deeper lens is the stronger one
+        assert!(foci[1].lens_distance < foci[0].lens_distance);
+    }
+
+    #[test]
+    fn collimated_output_has_no_focus() {
+        let plate = lens(1.0, LensSide::Flat, LensSide::Flat, Some(0.2), [0.0; 3]);
+        let world = traced(std::slice::from_ref(&plate), bundle(&plate, 0.3));
+        assert!(find_focus(&world).is_empty());
+    }
+}
@@ -2,9 +2,18 @@ use cgmath::Vector3 as Vec3;
 use cgmath::dot;
 use cgmath::InnerSpace;
 use cgmath::Vector3;
+use cgmath::Vector4;
+use cgmath::{Matrix3, Matrix4, SquareMatrix, ElementWise};
 
-use crate::Vertex;
-use crate::Normal;
+use std::collections::HashMap;
+
+#[cfg(feature = "viewer")]
+use crate::{Vertex, Normal};
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use crate::units;
 
 #[derive(Debug, Clone)]
 pub struct Triangle {
@@ -41,18 +50,23 @@ impl Triangle {
         }
     }
 
-    pub fn normals(&self) -> [Normal; 3] {
+    pub fn normal(&self) -> Vec3<f32> {
         let v1 = self.v1-self.v0;
         let v2 = self.v2-self.v0;
 
-        let n = -v1.cross(v2).normalize();
+        -v1.cross(v2).normalize()
+    }
+
+    #[cfg(feature = "viewer")]
+    pub fn normals(&self) -> [Normal; 3] {
         let n = Normal {
-            normal: n.into()
+            normal: self.normal().into()
         };
 
         [n, n, n]
     }
 
+    #[cfg(feature = "viewer")]
     pub fn vertices(&self) -> [Vertex; 3] {
         [Vertex {
             position: self.v0.into()
@@ -103,6 +117,136 @@ impl Triangle {
     }
 }
 
+/// A mesh of triangles sharing vertices, as read from a mesh file. Faces are
+/// wound counter-clockwise seen from outside.
+#[derive(Debug, Clone, Default)]
+pub struct IndexedMesh {
+    pub vertices: Vec<Vec3<f32>>,
+    /// Normal of every vertex, empty when the file has none
+    pub normals: Vec<Vec3<f32>>,
+    /// Colour of every vertex, empty when the file has none
+    pub colors: Vec<Vector4<f32>>,
+    pub faces: Vec<[usize; 3]>,
+}
+
+impl IndexedMesh {
+    /// A mesh of separate triangles, sharing the vertices they have exactly
+    /// in common
+    pub fn from_triangles(tris: &[Triangle]) -> Self {
+        let mut mesh = Self::default();
+        let mut seen = HashMap::new();
+        let mut index = |v: Vec3<f32>| *seen.entry([v.x, v.y, v.z].map(f32::to_bits)).or_insert_with(|| {
+            mesh.vertices.push(v);
+            mesh.vertices.len() - 1
+        });
+
+        let faces = tris.iter()
+            .map(|t| [index(t.v0), index(t.v2), index(t.v1)])
+            .collect();
+        mesh.faces = faces;
+        mesh
+    }
+
+    /// The mesh with every vertex coloured `color`
+    pub fn colored(mut self, color: Vector4<f32>) -> Self {
+        self.colors = vec![color; self.vertices.len()];
+        self
+    }
+
+    /// Add the vertices and faces of another mesh. Normals and colours are
+    /// only kept when both meshes have them.
+    pub fn append(&mut self, mut other: Self) {
+        let offset = self.vertices.len();
+        let empty = offset == 0;
+
+        if empty || (self.normals.len() == offset && !other.normals.is_empty()) {
+            self.normals.append(&mut other.normals);
+        } else {
+            self.normals.clear();
+        }
+        if empty || (self.colors.len() == offset && !other.colors.is_empty()) {
+            self.colors.append(&mut other.colors);
+        } else {
+            self.colors.clear();
+        }
+
+        self.vertices.append(&mut other.vertices);
+        self.faces.extend(other.faces.iter().map(|f| f.map(|i| i + offset)));
+    }
+
+    /// Unit normal of a face by its winding, zero for a degenerate face
+    pub fn face_normal(&self, face: usize) -> Vec3<f32> {
+        let [a, b, c] = self.faces[face].map(|i| self.vertices[i]);
+        let n = (b - a).cross(c - a);
+        if n.magnitude2() > 0.0 { n.normalize() } else { Vec3::new(0.0, 0.0, 0.0) }
+    }
+
+    /// The faces as separate triangles for the tracer
+    pub fn triangles(&self) -> Vec<Triangle> {
+        // `Triangle::normal` faces away from a counter-clockwise winding
+        self.faces.iter()
+            .map(|&[a, b, c]| Triangle::new(self.vertices[a], self.vertices[c], self.vertices[b]))
+            .collect()
+    }
+}
+
+/// Where an entity is placed in the world: scaled along its own axes, then
+/// rotated about its origin and then moved to `position`. Tracing and drawing
+/// both place entities by it, and the tracer takes normals from the placed
+/// triangles so they follow the scale too.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub position: Vec3<f32>,
+    pub rotation: Matrix3<f32>,
+    pub scale: Vec3<f32>,
+}
+
+impl Transform {
+    /// Moved to `position` without being turned or resized
+    pub fn at(position: Vec3<f32>) -> Self {
+        Self {
+            position,
+            rotation: Matrix3::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn rotated(self, rotation: Matrix3<f32>) -> Self {
+        Self { rotation, ..self }
+    }
+
+    pub fn scaled(self, scale: Vec3<f32>) -> Self {
+        Self { scale, ..self }
+    }
+
+    /// The whole transform as one matrix, for drawing
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Place a point given relative to the entity's origin
+    pub fn point(&self, v: Vec3<f32>) -> Vec3<f32> {
+        self.position + self.rotation * v.mul_element_wise(self.scale)
+    }
+
+    /// Whether the transform mirrors the entity, turning it inside out
+    pub fn mirrors(&self) -> bool {
+        self.rotation.determinant() * self.scale.x * self.scale.y * self.scale.z < 0.0
+    }
+
+    /// Place a triangle, turning it over when the transform mirrors it so
+    /// that it keeps facing the same way
+    pub fn triangle(&self, t: &Triangle) -> Triangle {
+        let [v0, v1, v2] = [t.v0, t.v1, t.v2].map(|v| self.point(v));
+        if self.mirrors() {
+            Triangle::new(v0, v2, v1)
+        } else {
+            Triangle::new(v0, v1, v2)
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
@@ -129,12 +273,11 @@ impl Ray {
         }
     }
 
-    pub fn tesselate(&self, d: f32) -> Vec<Triangle> {
+    /// A square tube `2 * w` wide along the first `d` of the ray
+    pub fn tesselate(&self, d: f32, w: f32) -> Vec<Triangle> {
         let f = self.origin;
         let t = self.origin + self.dir * d;
 
-        let w = 0.005;
-
         let a = Vector3::new(f.x, f.y+w, f.z+w);
         let b = Vector3::new(f.x, f.y+w, f.z-w);
         let c = Vector3::new(f.x, f.y-w, f.z+w);
@@ -216,6 +359,65 @@ impl Ray {
     }
 }
 
+/// An infinite plane, used as a detector or reference surface when analysing
+/// a trace. It does not take part in the trace itself.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct Plane {
+    #[serde(deserialize_with = "units::point")]
+    #[schemars(with = "[units::Length; 3]")]
+    pub pos: [f32; 3],
+    pub normal: [f32; 3],
+}
+
+impl Plane {
+    pub fn new(pos: Vec3<f32>, normal: Vec3<f32>) -> Self {
+        Self {
+            pos: pos.into(),
+            normal: normal.normalize().into()
+        }
+    }
+
+    /// Distance along the ray to the plane, if the ray crosses it
+    pub fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.intersect_line(ray).filter(|&t| t >= 0.0)
+    }
+
+    /// Signed distance along the line of the ray to the plane, negative when
+    /// the plane is behind the ray. Useful for virtual images.
+    pub fn intersect_line(&self, ray: &Ray) -> Option<f32> {
+        let n: Vec3<f32> = Vec3::from(self.normal).normalize();
+        let ang = dot(ray.dir, n);
+        if ang.abs() == 0.0 {
+            return None;
+        }
+
+        Some(dot(Vec3::from(self.pos) - ray.origin, n) / ang)
+    }
+
+    /// Two orthonormal vectors spanning the plane
+    pub fn basis(&self) -> (Vec3<f32>, Vec3<f32>) {
+        let n: Vec3<f32> = Vec3::from(self.normal).normalize();
+
+        // Pick whichever axis is least aligned with the normal to build from
+        let a = if n.x.abs() < 0.9 {
+            Vec3::new(1.0, 0.0, 0.0)
+        } else {
+            Vec3::new(0.0, 1.0, 0.0)
+        };
+
+        let u = a.cross(n).normalize();
+        let v = n.cross(u);
+        (u, v)
+    }
+
+    /// Coordinates of a point in the plane, relative to `pos`
+    pub fn project(&self, p: Vec3<f32>) -> [f32; 2] {
+        let (u, v) = self.basis();
+        let d = p - Vec3::from(self.pos);
+        [dot(d, u), dot(d, v)]
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Axis { X, Y, Z }
 
@@ -4,6 +4,11 @@ use cgmath::Vector3 as Vec3;
 const MAX_DEPTH: usize = 20;
 const NUM_POLYGONS: usize = 1;
 
+/// Hits closer than this, relative to the size of the ray's coordinates, are
+/// the surface the ray starts on. f32 loses absolute precision as scenes in
+/// finer units grow, so a fixed distance does not do.
+const SELF_HIT: f32 = 1e-6;
+
 #[derive(Debug)]
 pub enum KDNode {
     /// Decision Branch on Axis = f32, id for lef
@@ -62,16 +67,14 @@ impl KDNode {
             KDNode::Leaf(_, objs) => {
                 objs.iter()
                     .filter_map(|&i| ts[i].intersect(r).map(|d| (i, d)))
-                    .filter(|(_, d)| { *d > 0.000001 })
-                    .min_by(|a, b| {
-                        a.1.partial_cmp(&b.1).unwrap()
-                    })
+                    .filter(|(_, d)| { *d > SELF_HIT * (1.0 + r.origin.x.abs().max(r.origin.y.abs()).max(r.origin.z.abs())) })
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
             }
         }
     }
 }
 
-pub fn build_kdtree(g: &Vec<Triangle>) -> KDNode {
+pub fn build_kdtree(g: &[Triangle]) -> KDNode {
     let aabb = g.iter()
         .fold(
             AABB { min: Vec3::new(0.0, 0.0, 0.0), max: Vec3::new(0.0, 0.0, 0.0) },
@@ -81,7 +84,7 @@ pub fn build_kdtree(g: &Vec<Triangle>) -> KDNode {
     build_kdtree_h(g.iter().enumerate().collect(), aabb, Axis::X, 0)
 }
 
-fn build_kdtree_h<'a>(g: Vec<(usize, &'a Triangle)>, aabb: AABB, axis: Axis, depth: usize) -> KDNode {
+fn build_kdtree_h(g: Vec<(usize, &Triangle)>, aabb: AABB, axis: Axis, depth: usize) -> KDNode {
     // If we have reached our max depth return a leaf node containing the rest of the geometry
     if depth >= MAX_DEPTH {
         return KDNode::Leaf(aabb, g.iter().map(|a| a.0).collect());
@@ -95,8 +98,8 @@ fn build_kdtree_h<'a>(g: Vec<(usize, &'a Triangle)>, aabb: AABB, axis: Axis, dep
     // Now just subdivide by the axis and recur
     let (l, r, d) = aabb.split(axis);
 
-    let left: Vec<_> = g.iter().filter(|(_, t)| t.left_of(axis, d)).map(|a| *a).collect();
-    let right: Vec<_> = g.iter().filter(|(_, t)| t.right_of(axis, d)).map(|a| *a).collect();
+    let left: Vec<_> = g.iter().filter(|(_, t)| t.left_of(axis, d)).copied().collect();
+    let right: Vec<_> = g.iter().filter(|(_, t)| t.right_of(axis, d)).copied().collect();
 
     // If right and left have the same number as the parent just return a leaf node, don't recur
     if left.len() == right.len() && left.len() == g.len() {
@@ -1,233 +1,353 @@
-use crate::geometry::Triangle;
+use crate::geometry::{Triangle, IndexedMesh, Transform};
+use crate::units;
 use std::f32::consts::TAU;
-use cgmath::Vector3;
+use cgmath::{Vector3, Matrix3, Deg, Matrix};
 
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
 const MIN_LENS_WIDTH: f32 = 0.1;
-const TESSEL: usize = 20;
 
-#[derive(Serialize, Deserialize)]
+/// Segments around the axis and rings out from it each side of a lens is
+/// tessellated with
+const SEGMENTS: usize = 64;
+const RINGS: usize = 32;
+
+/// A lens with its axis along x. The left side faces +x and the right side
+/// faces -x, so light travelling along -x meets the left side first.
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Lens {
+    #[serde(deserialize_with = "units::length")]
+    #[schemars(with = "units::Length")]
     pub radius: f32,
     pub left: LensSide,
     pub right: LensSide,
+    #[serde(default = "default_pos", deserialize_with = "units::point")]
+    #[schemars(with = "[units::Length; 3]")]
+    pub pos: [f32; 3],
+    #[serde(default = "default_index")]
+    pub index: f32,
+    /// Thickness along the axis, by default derived from the sags of the sides
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_length")]
+    #[schemars(with = "Option<units::Length>")]
+    pub thickness: Option<f32>,
+    /// Tilt of the axis in degrees, about z (towards y) and then about y
+    /// (towards z). Ignored by the paraxial calculations.
+    #[serde(default, skip_serializing_if = "is_untilted")]
+    pub tilt: [f32; 2],
+    /// Thinnest the glass between the sides gets when the thickness is
+    /// derived from the sags, in the unit the lens was read in
+    #[serde(skip, default = "default_min_width")]
+    pub min_width: f32,
 }
 
-impl Lens {
-    pub fn tesselate(&self) -> Vec<Triangle> {
-        let mut triangles = vec![];
-
-        let offset = match (&self.left, &self.right) {
-            (LensSide::Flat, LensSide::Flat) => MIN_LENS_WIDTH/ 2.0,
-            (LensSide::Convex(r), LensSide::Flat) | (LensSide::Flat, LensSide::Convex(r)) => (MIN_LENS_WIDTH - r).max(0.0) / 2.0,
-            (LensSide::Convex(r1), LensSide::Convex(r2)) => (MIN_LENS_WIDTH - (r1+r2)).max(0.0) / 2.0,
-            (LensSide::Concave(r1), LensSide::Concave(r2)) => (r1+r2+MIN_LENS_WIDTH) / 2.0,
-            (LensSide::Concave(r), LensSide::Flat) | (LensSide::Flat, LensSide::Concave(r)) => (r+MIN_LENS_WIDTH) / 2.0,
-            (LensSide::Convex(r1), LensSide::Concave(r2)) | (LensSide::Concave(r2), LensSide::Convex(r1)) => (MIN_LENS_WIDTH - (r2-r1)).max(0.0) / 2.0,
-        };
-
-        triangles.append(&mut self.left.tesselate(self.radius, offset, false));
-        triangles.append(&mut self.right.tesselate(self.radius, offset,  true));
+fn is_untilted(tilt: &[f32; 2]) -> bool {
+    *tilt == [0.0, 0.0]
+}
 
-        if offset > 0.0 {
-            triangles.append(&mut LensSide::tesselate_cylinder(TESSEL, self.radius, offset, false));
-            triangles.append(&mut LensSide::tesselate_cylinder(TESSEL, self.radius, offset, true));
-        }
+fn default_pos() -> [f32; 3] {
+    [0.0, units::default_length(-0.1), 0.0]
+}
 
-        triangles
-    }
+fn default_min_width() -> f32 {
+    units::default_length(MIN_LENS_WIDTH)
 }
 
-#[derive(Serialize, Deserialize)]
-pub enum LensSide {
-    Flat,
-    Concave(f32),
-    Convex(f32)
+fn default_index() -> f32 {
+    1.3
 }
 
-impl LensSide {
-    fn tesselate(&self, lens_radius: f32, offset: f32, flipped: bool) -> Vec<Triangle> {
-        match self {
-            Self::Flat => Self::tesselate_flat(TESSEL, lens_radius, offset, flipped),
-            Self::Convex(h) => Self::tesselate_convex(TESSEL, lens_radius, *h, offset, flipped, false),
-            Self::Concave(h) => Self::tesselate_convex(TESSEL, lens_radius, *h, -offset, !flipped, true),
+impl Lens {
+    /// Distance from the centre of the lens to the rim of either side
+    fn offset(&self) -> f32 {
+        if let Some(t) = self.thickness {
+            return (t - self.left.sag() - self.right.sag()).max(0.0) / 2.0;
         }
-    }
-
-    fn tesselate_convex(num: usize, r: f32, h: f32,  offset: f32, flipped: bool, concave: bool) -> Vec<Triangle> {
-        let mut tris = vec![];
 
-        let x = (r.powi(2) - h.powi(2)) / (2.0 * h);
-        let convex_r = (x.powi(2) + r.powi(2)).sqrt();
-        let offset = offset-x;
-
-        let theta = (x/convex_r).acos();
+        let w = self.min_width;
+        match (&self.left, &self.right) {
+            (LensSide::Flat, LensSide::Flat) => w / 2.0,
+            (LensSide::Convex(r), LensSide::Flat) | (LensSide::Flat, LensSide::Convex(r)) => (w - r).max(0.0) / 2.0,
+            (LensSide::Convex(r1), LensSide::Convex(r2)) => (w - (r1+r2)).max(0.0) / 2.0,
+            (LensSide::Concave(r1), LensSide::Concave(r2)) => (r1+r2+w) / 2.0,
+            (LensSide::Concave(r), LensSide::Flat) | (LensSide::Flat, LensSide::Concave(r)) => (r+w) / 2.0,
+            (LensSide::Convex(r1), LensSide::Concave(r2)) | (LensSide::Concave(r2), LensSide::Convex(r1)) => (w - (r2-r1)).max(0.0) / 2.0,
+        }
+    }
 
-        let start = -theta;
-        let end = theta;
-        let angle = end-start;
-        let delta = angle / (num as f32);
+    /// Distance between the two vertices along the axis
+    pub fn center_thickness(&self) -> f32 {
+        2.0 * self.offset() + self.left.sag() + self.right.sag()
+    }
 
-        for zi in 0..num{
-            let lng = start + zi as f32 * delta;
-            let next_lng = lng + delta;
+    /// x coordinates of the left and right vertices relative to `pos`
+    pub fn vertices(&self) -> (f32, f32) {
+        let offset = self.offset();
+        (offset + self.left.sag(), -offset - self.right.sag())
+    }
 
-            for yi in 0..num {
-                let lat = start + yi as f32 * delta;
-                let next_lat = lat + delta;
+    /// Rotation of the axis by `tilt`
+    fn rotation(&self) -> Matrix3<f32> {
+        Matrix3::from_angle_y(Deg(self.tilt[1])) * Matrix3::from_angle_z(Deg(self.tilt[0]))
+    }
 
-                let mut v0 = Self::lng_lat_to_point(lng, lat, convex_r);
-                let mut v1 = Self::lng_lat_to_point(lng, next_lat, convex_r);
-                let mut v2 = Self::lng_lat_to_point(next_lng, lat, convex_r);
-                let mut v3 = Self::lng_lat_to_point(next_lng, next_lat, convex_r);
+    /// Where the lens entity is placed: tilted and moved to `pos`
+    pub fn transform(&self) -> Transform {
+        Transform::at(self.pos.into()).rotated(self.rotation())
+    }
 
+    /// Whether a point lies inside the glass of the lens
+    pub fn contains(&self, p: [f32; 3]) -> bool {
+        let p = self.rotation().transpose() * (Vector3::from(p) - Vector3::from(self.pos));
 
-                v0.x += offset;
-                v1.x += offset;
-                v2.x += offset;
-                v3.x += offset;
+        let rho = (p.y.powi(2) + p.z.powi(2)).sqrt();
+        if rho > self.radius {
+            return false;
+        }
 
-                if flipped {
-                    v0.x = -v0.x;
-                    v1.x = -v1.x;
-                    v2.x = -v2.x;
-                    v3.x = -v3.x;
-                }
+        let offset = self.offset();
+        p.x < offset + self.left.height(self.radius, rho) && p.x > -offset - self.right.height(self.radius, rho)
+    }
 
-                if (flipped && !concave) || (!flipped && concave) {
-                    tris.push(Triangle::new(v2, v1, v0));
-                    tris.push(Triangle::new(v1, v2, v3));
+    /// The surface of the lens as one closed mesh, untilted and centred on
+    /// the origin, `transform` places it. Both sides are rings of vertices around a centre vertex,
+    /// sharing their rim with each other or with the edge between them.
+    pub fn mesh(&self) -> IndexedMesh {
+        let offset = self.offset();
+        let mut mesh = IndexedMesh::default();
+
+        // Segments start half a step off the y and z axes so that the planes
+        // through the axis that rays are usually traced in cross faces rather
+        // than run along their edges
+        let step = TAU / SEGMENTS as f32;
+        let ring = |mesh: &mut IndexedMesh, x: f32, rho: f32| -> usize {
+            let first = mesh.vertices.len();
+            mesh.vertices.extend((0..SEGMENTS).map(|j| {
+                let theta = (j as f32 + 0.5) * step;
+                Vector3::new(x, rho * theta.sin(), rho * theta.cos())
+            }));
+            first
+        };
+        let next = |j: usize| (j + 1) % SEGMENTS;
+
+        // Faces of each side, wound counter-clockwise seen from outside. The
+        // left side faces +x and the right side -x.
+        let mut rims = [0; 2];
+        for (k, (side, sign)) in [(&self.left, 1.0), (&self.right, -1.0)].into_iter().enumerate() {
+            let x = |rho: f32| sign * (offset + side.height(self.radius, rho));
+            let outward = |a: usize, b: usize, c: usize| if sign > 0.0 { [a, c, b] } else { [a, b, c] };
+
+            let centre = mesh.vertices.len();
+            mesh.vertices.push(Vector3::new(x(0.0), 0.0, 0.0));
+
+            let radii = side.rings(self.radius);
+            let mut inner = None;
+            for (i, &rho) in radii.iter().enumerate() {
+                // Without an edge between them the sides share their rim
+                let outer = if k == 1 && i == radii.len() - 1 && offset == 0.0 {
+                    rims[0]
                 } else {
-                    tris.push(Triangle::new(v0, v1, v2));
-                    tris.push(Triangle::new(v3, v2, v1));
+                    ring(&mut mesh, x(rho), rho)
+                };
+
+                for j in 0..SEGMENTS {
+                    match inner {
+                        None => mesh.faces.push(outward(centre, outer + j, outer + next(j))),
+                        Some(inner) => {
+                            mesh.faces.push(outward(inner + j, outer + j, outer + next(j)));
+                            mesh.faces.push(outward(inner + j, outer + next(j), inner + next(j)));
+                        }
+                    }
                 }
+                inner = Some(outer);
             }
+            rims[k] = inner.unwrap();
         }
 
-        let v_in_circ = |v: Vector3<f32>| -> bool {
-            v.z.powi(2) + v.y.powi(2) <= r.powi(2)
-        };
-
-        let v_to_edge = |v: &mut Vector3<f32>| {
-            let theta = v.y.atan2(v.z);
-            v.z = r * theta.cos();
-            v.y = r * theta.sin();
-            v.x = convex_r * start.cos() * (start + angle /2.0).cos() + offset;
-            if flipped {
-                v.x = -v.x;
+        // The edge between the rims, facing away from the axis
+        let [left, right] = rims;
+        if left != right {
+            for j in 0..SEGMENTS {
+                mesh.faces.push([left + j, right + next(j), right + j]);
+                mesh.faces.push([left + j, left + next(j), right + next(j)]);
             }
-        };
-
-        // Filter out tris completely out of the cirlce
-        //
-        // Then take tris that are partially in the circle
-        // and move their edges to the nearest points on
-        // the edge of the circle (for z, y)
-        let tris = tris.into_iter()
-            .filter(|t| {
-                v_in_circ(t.v0)
-                    || v_in_circ(t.v1)
-                    || v_in_circ(t.v2)
-            })
-            .map(|mut t| {
-                if !v_in_circ(t.v0) {
-                    v_to_edge(&mut t.v0);
-                }
-
-                if !v_in_circ(t.v1) {
-                    v_to_edge(&mut t.v1);
-                }
+        }
 
-                if !v_in_circ(t.v2) {
-                    v_to_edge(&mut t.v2);
-                }
+        mesh
+    }
 
-                t
-            }).collect::<Vec<_>>();
+    /// The triangles of `mesh`
+    pub fn tesselate(&self) -> Vec<Triangle> {
+        self.mesh().triangles()
+    }
+}
 
+/// One side of a lens. Curved sides are given by their sag, the height of the
+/// cap above the rim.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum LensSide {
+    Flat,
+    Concave(
+        #[serde(deserialize_with = "units::length")]
+        #[schemars(with = "units::Length")]
+        f32
+    ),
+    Convex(
+        #[serde(deserialize_with = "units::length")]
+        #[schemars(with = "units::Length")]
+        f32
+    ),
+}
 
-        tris
+impl LensSide {
+    /// Signed height of the vertex above the rim, positive when convex
+    pub fn sag(&self) -> f32 {
+        match self {
+            Self::Flat => 0.0,
+            Self::Convex(h) => *h,
+            Self::Concave(h) => -*h,
+        }
     }
 
-    fn tesselate_flat(num: usize, radius: f32, offset: f32, flipped: bool) -> Vec<Triangle> {
-        let mut tris = vec![];
+    /// The side with the given signed sag
+    pub fn from_sag(h: f32) -> Self {
+        if h > 0.0 {
+            Self::Convex(h)
+        } else if h < 0.0 {
+            Self::Concave(-h)
+        } else {
+            Self::Flat
+        }
+    }
 
-        let offset = if flipped {-offset} else {offset};
+    /// Radius of the sphere the side is cut from, infinite when flat
+    pub fn radius_of_curvature(&self, lens_radius: f32) -> f32 {
+        match self {
+            Self::Flat => f32::INFINITY,
+            Self::Convex(h) | Self::Concave(h) => (lens_radius.powi(2) + h.powi(2)) / (2.0 * h),
+        }
+    }
 
-        // Create circle by creating triangle for every pizza slize
-        let angle = TAU / num as f32;
-        for i in 0..num {
-            let theta = i as f32 * angle;
+    /// The side cut from a sphere of signed radius `r`, positive when convex.
+    /// Radii smaller than the lens give a near hemisphere.
+    pub fn from_radius_of_curvature(r: f32, lens_radius: f32) -> Self {
+        if !r.is_finite() {
+            return Self::Flat;
+        }
 
-            let v0 = Vector3::new(offset, 0.0, 0.0);
-            let v1 = Vector3::new(offset, radius * theta.sin(), radius * theta.cos());
-            let v2 = Vector3::new(offset, radius * (theta+angle).sin(), radius * (theta+angle).cos());
+        let h = r.abs() - (r.powi(2) - lens_radius.powi(2)).max(0.0).sqrt();
+        Self::from_sag(r.signum() * h.min(0.99 * lens_radius))
+    }
 
-            if flipped {
-                tris.push(Triangle::new(v2, v1, v0))
-            } else {
-                tris.push(Triangle::new(v0, v1, v2))
+    /// Signed height of the surface above the rim at a distance `rho` from
+    /// the axis, positive when convex
+    pub fn height(&self, lens_radius: f32, rho: f32) -> f32 {
+        match self {
+            Self::Flat => 0.0,
+            Self::Convex(_) | Self::Concave(_) => {
+                let r = self.radius_of_curvature(lens_radius);
+                self.sag().signum() * ((r.powi(2) - rho.powi(2)).max(0.0).sqrt() - (r.powi(2) - lens_radius.powi(2)).max(0.0).sqrt())
             }
         }
+    }
 
+    /// Distances from the axis of the rings of vertices the side is
+    /// tessellated with, the last one on the rim. Curved sides are split
+    /// into rings evenly spaced along the arc so the steep outer part of
+    /// deep sides is not left coarse.
+    fn rings(&self, lens_radius: f32) -> Vec<f32> {
+        let r = self.radius_of_curvature(lens_radius).abs();
+        let arc = if r.is_finite() { (lens_radius / r).min(1.0).asin() } else { 0.0 };
+
+        (1..=RINGS).map(|i| {
+            let t = i as f32 / RINGS as f32;
+            if i == RINGS {
+                lens_radius
+            } else if arc > 0.0 {
+                r * (t * arc).sin()
+            } else {
+                t * lens_radius
+            }
+        }).collect()
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repair::repair;
 
-        tris
+    fn lens(left: LensSide, right: LensSide, thickness: Option<f32>) -> Lens {
+        Lens { radius: 0.5, left, right, pos: [0.0; 3], index: 1.5, thickness, tilt: [0.0, 0.0], min_width: MIN_LENS_WIDTH }
     }
 
-    fn tesselate_cylinder(num: usize, radius: f32, offset: f32, flipped: bool) -> Vec<Triangle> {
-        let mut tris = vec![];
+    #[test]
+    fn meshes_are_closed_and_outward() {
+        use LensSide::*;
+        let sides = [Flat, Convex(0.1), Convex(0.49), Concave(0.1), Concave(0.49)];
+
+        let mut checked = 0;
+        for left in sides {
+            for right in sides {
+                for thickness in [None, Some(0.02), Some(1.0)] {
+                    let lens = lens(left, right, thickness);
+                    // Sides that cross on the axis are rejected by validation
+                    if lens.center_thickness() <= 0.0 {
+                        continue;
+                    }
+
+                    let report = repair(&mut lens.mesh());
+                    let case = format!("{left:?} {right:?} {thickness:?}");
+                    assert!(report.watertight(), "{case}: {}", report.describe());
+                    assert_eq!(report.flipped, 0, "{case}");
+                    assert_eq!(report.welded, 0, "{case}");
+                    assert_eq!(report.components, 1, "{case}");
+                    checked += 1;
+                }
+            }
+        }
 
-        // Build cylinder for the offset
-        let angle = TAU / num as f32;
-        for i in 0..num {
-            let theta = i as f32 * angle;
+        assert!(checked > 50);
+    }
 
-            let x0 = if flipped {-offset} else {offset};
-            let x1 = 0.0;
+    #[test]
+    fn sides_share_their_rim_without_an_edge() {
+        let lens = lens(LensSide::Convex(0.2), LensSide::Convex(0.2), None);
+        assert_eq!(lens.offset(), 0.0);
 
-            let y0 = radius * theta.sin();
-            let y1 = radius * (theta+angle).sin();
+        // A centre and `RINGS` rings for each side, the outer ring shared
+        let mesh = lens.mesh();
+        assert_eq!(mesh.vertices.len(), 2 + (2 * RINGS - 1) * SEGMENTS);
 
-            let z0 = radius * theta.cos();
-            let z1 = radius * (theta+angle).cos();
+        let report = repair(&mut lens.mesh());
+        assert!(report.watertight(), "{}", report.describe());
+        assert_eq!((report.flipped, report.welded), (0, 0));
+    }
 
-            if flipped {
-                tris.push(Triangle::new(
-                    Vector3::new(x0, y0, z0),
-                    Vector3::new(x0, y1, z1),
-                    Vector3::new(x1, y1, z1),
-                ));
-                tris.push(Triangle::new(
-                    Vector3::new(x0, y0, z0),
-                    Vector3::new(x1, y1, z1),
-                    Vector3::new(x1, y0, z0),
-                ));
+    #[test]
+    fn defaults_keep_their_size_in_any_unit() {
+        use crate::scene::{Scene, Format};
 
-            } else {
-                tris.push(Triangle::new(
-                    Vector3::new(x1, y1, z1),
-                    Vector3::new(x0, y1, z1),
-                    Vector3::new(x0, y0, z0),
-                ));
-                tris.push(Triangle::new(
-                    Vector3::new(x1, y0, z0),
-                    Vector3::new(x1, y1, z1),
-                    Vector3::new(x0, y0, z0),
-                ));
-            }
+        let read = |unit: &str, radius: &str| {
+            let source = format!("unit: {unit}\nlenses:\n  - radius: {radius}\n    left: !Convex 0.5mm\n    right: Flat\n");
+            Scene::parse("lens.yaml", &source, Format::Yaml).unwrap().lenses.remove(0)
+        };
+        let mm = read("mm", "5");
+        let m = read("m", "0.005");
+
+        let close = |a: f32, b: f32| assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        close(m.center_thickness() * 1000.0, mm.center_thickness());
+        close(m.vertices().0 * 1000.0, mm.vertices().0);
+        close(m.vertices().1 * 1000.0, mm.vertices().1);
+        for i in 0..3 {
+            close(m.pos[i] * 1000.0, mm.pos[i]);
         }
 
-
-        tris
-    }
-
-    fn lng_lat_to_point(lng: f32, lat: f32, radius: f32) -> Vector3<f32> {
-        let x = radius * lat.cos() * lng.cos();
-        let y = radius * lat.cos() * lng.sin();
-        let z = radius * lat.sin();
-
-        Vector3::new(x, y, z)
+        // A scene without a unit is in centimetres
+        let cm = Scene::parse("lens.yaml", "lenses:\n  - radius: 0.5\n    left: !Convex 0.05\n    right: Flat\n", Format::Yaml)
+            .unwrap().lenses.remove(0);
+        close(cm.center_thickness() * 10.0, mm.center_thickness());
+        assert!(m.contains([0.0, m.pos[1], 0.0]));
+        assert!(!m.contains([0.02, m.pos[1], 0.0]));
     }
 }
@@ -7,33 +7,60 @@
 // notice may not be copied, modified, or distributed except
 // according to those terms.
 
+#[cfg(feature = "viewer")]
 pub mod vulkan;
+#[cfg(feature = "viewer")]
+pub mod viewer;
+pub mod error;
 pub mod world;
 pub mod geometry;
 pub mod ply;
+pub mod obj;
+pub mod stl;
 pub mod kdtree;
 pub mod lenses;
 pub mod light;
+pub mod segments;
+pub mod spot;
+pub mod focus;
+pub mod paraxial;
+pub mod fan;
+pub mod plot;
+pub mod wavefront;
+pub mod psf;
+pub mod linalg;
+pub mod optimize;
+pub mod sweep;
+pub mod tolerance;
+pub mod validate;
+pub mod scene;
+pub mod expand;
+pub mod enclosure;
+pub mod mesh;
+pub mod repair;
+pub mod units;
 
+pub use error::{Error, Result};
+
+#[cfg(feature = "viewer")]
 use bytemuck::{Pod, Zeroable};
-use vulkano::impl_vertex;
 
 use geometry::Triangle;
 use cgmath::Vector3;
 
+#[cfg(feature = "viewer")]
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 pub struct Vertex {
     position: [f32; 3],
 }
-impl_vertex!(Vertex, position);
 
+#[cfg(feature = "viewer")]
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 pub struct Normal {
     normal: [f32; 3],
 }
-impl_vertex!(Normal, normal);
 
 pub const THE_BOX: [Triangle; 12] = [
     // left wall
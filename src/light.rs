@@ -1,12 +1,26 @@
 use cgmath::Vector3;
+use cgmath::InnerSpace;
 use crate::geometry::Ray;
+use crate::units;
+
+use std::fmt;
 
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub enum Light {
-    Laser([f32; 3], [f32; 3]),
-    Point([f32; 3]),
+    Laser(
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        [f32; 3],
+        [f32; 3]
+    ),
+    Point(
+        #[serde(deserialize_with = "units::point")]
+        #[schemars(with = "[units::Length; 3]")]
+        [f32; 3]
+    ),
 }
 
 impl Light {
@@ -41,4 +55,46 @@ impl Light {
 
         self.spawn_from((*p).into())
     }
+
+    pub fn pos_mut(&mut self) -> &mut [f32; 3] {
+        match self {
+            Self::Point(p) => p,
+            Self::Laser(p, _) => p,
+        }
+    }
+
+    /// The field this light belongs to. Parallel lasers make up a single
+    /// collimated field, while every point light is a field of its own.
+    pub fn field(&self) -> Field {
+        match self {
+            Self::Laser(_, dir) => Field::Direction(Vector3::from(*dir).normalize()),
+            Self::Point(p) => Field::Point((*p).into()),
+        }
+    }
+}
+
+/// A group of lights that image to the same spot
+#[derive(Debug, Clone, Copy)]
+pub enum Field {
+    Direction(Vector3<f32>),
+    Point(Vector3<f32>),
+}
+
+impl PartialEq for Field {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Direction(a), Self::Direction(b)) => (a - b).magnitude() < 1e-4,
+            (Self::Point(a), Self::Point(b)) => (a - b).magnitude() < 1e-4,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Direction(d) => write!(f, "dir({:.3}, {:.3}, {:.3})", d.x, d.y, d.z),
+            Self::Point(p) => write!(f, "point({:.3}, {:.3}, {:.3})", p.x, p.y, p.z),
+        }
+    }
 }
@@ -0,0 +1,57 @@
+/// Solve the square system `a x = b` by gaussian elimination with partial
+/// pivoting, or None if it is singular or not finite
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        let p = a[pivot][col].abs();
+        if !p.is_finite() || p < 1e-12 {
+            return None;
+        }
+
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let pivot_row = a[col].clone();
+        for row in col+1..n {
+            let f = a[row][col] / pivot_row[col];
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= f * p;
+            }
+            b[row] -= f * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s = (row+1..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - s) / a[row][row];
+    }
+
+    Some(x)
+}
+
+/// Coefficients minimising the squared error of `rows * x = values`, through
+/// the normal equations. `damping` is added to the diagonal.
+pub fn least_squares(rows: &[Vec<f64>], values: &[f64], damping: f64) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+
+    let mut ata = vec![vec![0.0; n]; n];
+    let mut atb = vec![0.0; n];
+    for (r, v) in rows.iter().zip(values) {
+        for i in 0..n {
+            for j in 0..n {
+                ata[i][j] += r[i] * r[j];
+            }
+            atb[i] += r[i] * v;
+        }
+    }
+
+    for (i, row) in ata.iter_mut().enumerate() {
+        row[i] += damping;
+    }
+
+    solve(ata, atb)
+}
@@ -1,65 +1,208 @@
 use std::env::args;
 
+#[cfg(feature = "viewer")]
 use winit::event_loop::EventLoop;
+#[cfg(feature = "viewer")]
 use winit::event_loop::ControlFlow;
+#[cfg(feature = "viewer")]
 use winit::event::Event;
+#[cfg(feature = "viewer")]
 use winit::event::WindowEvent;
+#[cfg(feature = "viewer")]
 use winit::event::ElementState;
 
+#[cfg(feature = "viewer")]
 use lenses::vulkan::VulkanState;
+#[cfg(feature = "viewer")]
+use lenses::viewer::Viewer;
 use lenses::world::World;
-use lenses::THE_BOX;
-use lenses::light::Light;
 
 use lenses::lenses::Lens;
 use lenses::world::Material;
+use lenses::geometry::{Plane, IndexedMesh};
+use lenses::mesh;
+use lenses::segments;
+use lenses::spot;
+use lenses::focus;
+use lenses::paraxial::{self, RayTransfer, FirstOrder, System};
+use lenses::fan;
+use lenses::plot;
+use lenses::wavefront;
+use lenses::psf;
+use lenses::optimize;
+use lenses::sweep::{self, Sweep};
+use lenses::tolerance;
+use lenses::scene::{Scene, Format};
+use lenses::units::{self, Unit};
+use lenses::{Error, Result};
 
-use cgmath::Vector4;
 use cgmath::Vector3;
+use cgmath::Vector4;
 
-use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::BufWriter;
 
+const USAGE: &str = "usage:
+    lenses <scene.yaml>                     view the traced scene
+    lenses validate <scene.yaml>            check the scene for invalid or degenerate values
+    lenses schema [out.json]                JSON Schema of the scene format
+    lenses trace <scene.yaml> [out.json|out.csv]  trace without a window and write every segment
+    lenses spot <scene.yaml> [out.csv]      spot diagram on the scene's detector
+    lenses focus <scene.yaml>               find where each field comes to focus
+    lenses paraxial <scene.yaml>            first order properties of the lenses
+    lenses fan <scene.yaml> [prefix]        ray aberration fan across the aperture
+    lenses wavefront <scene.yaml> [prefix]  wavefront error across the aperture
+    lenses psf <scene.yaml> [prefix]        point spread and modulation transfer
+    lenses optimize <scene.yaml> [out.yaml] optimize the lenses against the scene's targets
+    lenses tolerance <scene.yaml> [out.csv]  monte carlo analysis of the scene's tolerances
+    lenses sweep <scene.yaml> <sweep.yaml> [out.csv]  trace the scene across a parameter sweep
+    lenses export <scene.yaml> <out.stl|out.obj|out.ply> [world|rays|lens <n>]
+                                            write the scene, the scene with its traced rays or
+                                            one lens as a mesh
 
-#[derive(Serialize, Deserialize)]
-struct FileFormat {
-    lenses: Vec<Lens>,
-    lights: Vec<Light>
-}
+options:
+    --field <degrees>       field angle in the xy plane for wavefront and psf
+    --wavelength <length>   wavelength in scene units or with a unit like 550nm,
+                            enables diffraction psf";
 
 fn main() {
-    let event_loop = EventLoop::new();
-    let mut vulkan = VulkanState::new(&event_loop);
+    let args = args().skip(1).collect::<Vec<_>>();
+
+    let number = |name: &str, what: &str| flag(&args, name).map(|v| {
+        v.parse::<f32>().unwrap_or_else(|_| {
+            eprintln!("--{name} takes {what}, not `{v}`");
+            std::process::exit(2);
+        })
+    });
+    let field = number("field", "an angle in degrees").unwrap_or(0.0).to_radians();
+    let wavelength = flag(&args, "wavelength");
+
+    let result = match positional(&args).as_slice() {
+        ["validate", scene] => validate_scene(scene),
+        ["schema", rest @ ..] if rest.len() < 2 => schema(rest.first().copied()),
+        ["trace", scene, rest @ ..] => trace_scene(scene, rest.first().copied()),
+        ["spot", scene, rest @ ..] => spot(scene, rest.first().copied()),
+        ["focus", scene] => find_focus(scene),
+        ["paraxial", scene] => first_order(scene),
+        ["fan", scene, rest @ ..] => ray_fan(scene, rest.first().copied()),
+        ["wavefront", scene, rest @ ..] => wavefront_map(scene, rest.first().copied(), field),
+        ["psf", scene, rest @ ..] => point_spread(scene, rest.first().copied(), field, wavelength),
+        ["optimize", scene, rest @ ..] => optimize_scene(scene, rest.first().copied()),
+        ["tolerance", scene, rest @ ..] => tolerance_scene(scene, rest.first().copied()),
+        ["sweep", scene, spec, rest @ ..] => sweep_scene(scene, spec, rest.first().copied()),
+        ["export", scene, out] | ["export", scene, out, "world"] => export(scene, out, false),
+        ["export", scene, out, "rays"] => export(scene, out, true),
+        ["export", scene, out, "lens", n] => export_lens(scene, out, n),
+        [scene] => view(scene),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
 
-    // Load the scene
-    let fname = args().nth(1).unwrap();
-    let s = std::fs::read_to_string(fname).unwrap();
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
 
-    let scene_file: FileFormat = serde_yaml::from_str(&s).unwrap();
+/// The value following `--name` on the command line
+fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a.strip_prefix("--") == Some(name))
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// Arguments that are not options or their values
+fn positional(args: &[String]) -> Vec<&str> {
+    let mut out = vec![];
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        if a.starts_with("--") {
+            it.next();
+        } else {
+            out.push(a.as_str());
+        }
+    }
+
+    out
+}
+
+/// Load and validate a scene, printing any warnings
+fn load_scene(fname: &str) -> Result<Scene> {
+    let (scene_file, warnings) = Scene::load(fname)?;
+    for w in warnings {
+        eprintln!("warning: {}", w.describe(fname));
+    }
+
+    Ok(scene_file)
+}
+
+fn validate_scene(fname: &str) -> Result<()> {
+    load_scene(fname)?;
+    println!("{fname}: ok");
+    Ok(())
+}
+
+fn schema(out: Option<&str>) -> Result<()> {
+    let schema = Scene::json_schema();
+    match out {
+        Some(out) => write_file(out, schema),
+        None => {
+            println!("{schema}");
+            Ok(())
+        }
+    }
+}
+
+/// Open a file for writing
+fn create(path: &str) -> Result<BufWriter<File>> {
+    File::create(path)
+        .map(BufWriter::new)
+        .map_err(|e| Error::io(path, e))
+}
+
+fn write_file(path: &str, contents: String) -> Result<()> {
+    std::fs::write(path, contents).map_err(|e| Error::io(path, e))
+}
+
+/// Build the world for a scene and trace it
+fn build_world(scene_file: Scene) -> Result<World> {
     let mut world = World::new();
 
-    // Add box
-    let box_model = world.add_model(THE_BOX.to_vec());
-    world.add_entity(
-        box_model,
-        Vector3::new(-2.5, -1.0, -2.5),
-        Material::Solid,
-        Vector3::new(1.0,  1.0, 1.0),
-        Vector4::new(0.2, 0.2, 0.2, 1.0)
-    );
+    if let Some((tris, transform, material)) = scene_file.enclosure.model(&scene_file.dir, scene_file.unit)? {
+        let model = world.add_model(tris);
+        world.add_entity(
+            model,
+            transform,
+            material,
+            material.color(),
+        );
+    }
+    world.far = scene_file.far.unwrap_or(10.0 * units::scale(scene_file.unit));
 
     for light in scene_file.lights {
         world.add_light(light);
     }
 
     for lens in scene_file.lenses {
-        let tris = lens.tesselate();
-        let model = world.add_model(tris);
+        let model = world.add_model(lens.tesselate());
         world.add_entity(
             model,
-            Vector3::new(0.0, -0.1, 0.0),
-            Material::Glass(1.3),
-            Vector3::new(1.0,  1.0, 1.0),
-            Vector4::new(0.209, 0.282, 0.686, 0.4),
+            lens.transform(),
+            Material::Glass(lens.index),
+            Material::Glass(lens.index).color(),
+        );
+    }
+
+    for mesh in scene_file.meshes {
+        let model = world.add_model(mesh.triangles(&scene_file.dir)?);
+        world.add_entity(
+            model,
+            mesh.transform(),
+            mesh.material,
+            mesh.material.color(),
         );
     }
 
@@ -67,10 +210,513 @@ fn main() {
     world.build_kdtree();
 
     // Run ray tracer
-    world.trace();
+    world.trace()?;
+
+    Ok(world)
+}
+
+fn trace_scene(fname: &str, out: Option<&str>) -> Result<()> {
+    let world = build_world(load_scene(fname)?)?;
+    let records = segments::records(&world);
+
+    match out {
+        Some(out) => {
+            let f = create(out)?;
+            if out.ends_with(".csv") {
+                segments::write_csv(&records, f)
+            } else {
+                segments::write_json(&records, f)
+            }.map_err(|e| Error::io(out, e))?;
+            eprintln!("wrote {} segments of {} paths to {out}", records.len(), world.paths.len());
+        }
+        None => segments::write_json(&records, std::io::stdout())
+            .map_err(|e| Error::io("stdout", e))?,
+    }
+
+    Ok(())
+}
+
+fn spot(fname: &str, out: Option<&str>) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let plane = scene_file.detector
+        .ok_or_else(|| Error::Scene(format!("{fname}: scene has no detector")))?;
+    let u = units::suffix(scene_file.unit);
+    let world = build_world(scene_file)?;
+
+    let spots = spot::spot_diagram(&world, &plane);
+
+    println!("{:<32} {:>6} {:>22} {:>10} {:>10}", "field", "rays", format!("centroid{u}"), format!("rms{u}"), format!("radius{u}"));
+    for s in spots.iter() {
+        let c = s.centroid();
+        println!(
+            "{:<32} {:>6} {:>10.5},{:>11.5} {:>10.5} {:>10.5}",
+            s.field.to_string(),
+            s.points.len(),
+            c[0], c[1],
+            s.rms_radius(),
+            s.geometric_radius()
+        );
+    }
+
+    let out = out.unwrap_or("spot.csv");
+    spot::write_csv(&spots, create(out)?).map_err(|e| Error::io(out, e))
+}
+
+fn find_focus(fname: &str) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let u = units::suffix(scene_file.unit);
+    let world = build_world(scene_file)?;
+
+    let foci = focus::find_focus(&world);
+    if foci.is_empty() {
+        println!("no field comes to a focus");
+        return Ok(());
+    }
+
+    for f in foci.iter() {
+        println!("{} ({} rays leaving entity {})", f.field, f.rays, f.lens);
+        println!("    closest approach  ({:.5}, {:.5}, {:.5}){u}", f.point.x, f.point.y, f.point.z);
+        println!("    best focus        ({:.5}, {:.5}, {:.5}){u}", f.best.x, f.best.y, f.best.z);
+        println!("    rms spot radius   {:.5}{u}", f.rms);
+        println!("    lens distance     {:.5}{u}", f.lens_distance);
+        println!("    back focal dist.  {:.5}{u}", f.bfl);
+    }
+
+    Ok(())
+}
+
+fn print_first_order(name: &str, p: &FirstOrder, unit: Option<Unit>) {
+    let u = units::suffix(unit);
+    let per = unit.map(|u| format!(" per {u}")).unwrap_or_default();
+    println!("{name}");
+    println!("    power             {:.5}{per}", p.power);
+    println!("    effective focal   {:.5}{u}", p.efl);
+    println!("    back focal        {:.5}{u}", p.bfl);
+    println!("    front focal       {:.5}{u}", p.ffl);
+    println!("    front principal   {:.5}{u}", p.front_principal);
+    println!("    rear principal    {:.5}{u}", p.rear_principal);
+}
+
+fn first_order(fname: &str) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+
+    for (i, lens) in scene_file.lenses.iter().enumerate() {
+        print_first_order(&format!("lens {i}"), &RayTransfer::lens(lens).first_order(), scene_file.unit);
+    }
+
+    let system = match System::new(&scene_file.lenses) {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    print_first_order("system", &system.first_order(), scene_file.unit);
+    let u = units::suffix(scene_file.unit);
+
+    // Cross check the paraxial focus against a trace of rays across the
+    // inner half of the aperture
+    let predicted = system.focal_point();
+    let setting = scene_file.setting();
+    let world = build_world(Scene {
+        lights: paraxial::axial_fan(&scene_file.lenses, &system, 0.5, 16),
+        lenses: scene_file.lenses,
+        ..setting
+    })?;
+
+    println!("rear focal point");
+    println!("    paraxial          x = {predicted:.5}{u}");
+    match focus::find_focus(&world).first() {
+        Some(f) => {
+            println!("    traced            x = {:.5}{u}", f.best.x);
+            println!("    difference        {:.5}{u}", f.best.x - predicted);
+        }
+        None => println!("    traced            no focus"),
+    }
+
+    Ok(())
+}
+
+fn ray_fan(fname: &str, prefix: Option<&str>) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let system = lens_system(fname, &scene_file)?;
+
+    // Measure on the detector if there is one, otherwise on the paraxial
+    // image plane
+    let image = scene_file.detector.unwrap_or_else(|| {
+        let first = &scene_file.lenses[system.order[0]];
+        Plane::new(
+            Vector3::new(system.focal_point(), first.pos[1], first.pos[2]),
+            Vector3::new(1.0, 0.0, 0.0)
+        )
+    });
+
+    let u = units::suffix(scene_file.unit);
+    let lenses = scene_file.lenses.clone();
+    let setting = scene_file.setting();
+    let world = build_world(Scene {
+        lights: paraxial::axial_fan(&scene_file.lenses, &system, 0.98, 40),
+        lenses: scene_file.lenses,
+        ..setting
+    })?;
+
+    let fan = fan::ray_fan(&world, &lenses, &system, &image);
+
+    // The marginal ray is the outermost one that could be measured
+    if let Some(marginal) = fan.iter().filter(|r| r.pupil.is_finite()).max_by(|a, b| a.pupil.total_cmp(&b.pupil)) {
+        println!("{} rays traced", fan.len());
+        println!("    marginal height         {:.5}{u}", marginal.height);
+        println!("    marginal transverse     {:.5}{u}", marginal.transverse);
+        if let Some(l) = marginal.longitudinal {
+            println!("    marginal longitudinal   {:.5}{u}", l);
+        }
+    }
+
+    let prefix = prefix.unwrap_or("fan");
+    let out = format!("{prefix}.csv");
+    fan::write_csv(&fan, create(&out)?).map_err(|e| Error::io(&out, e))?;
+
+    let transverse = fan.iter().map(|r| (r.pupil, r.transverse)).collect();
+    let svg = plot::line_plot(
+        "Transverse ray aberration", "pupil height", "image height",
+        &[("meridional", transverse)]
+    );
+    write_file(&format!("{prefix}_transverse.svg"), svg)?;
+
+    let longitudinal = fan.iter()
+        .filter_map(|r| Some((r.height, r.longitudinal?)))
+        .collect();
+    let svg = plot::line_plot(
+        "Longitudinal spherical aberration", "ray height", "focus shift",
+        &[("meridional", longitudinal)]
+    );
+    write_file(&format!("{prefix}_longitudinal.svg"), svg)
+}
+
+/// The lenses of a scene as a paraxial system
+fn lens_system(fname: &str, scene_file: &Scene) -> Result<System> {
+    System::new(&scene_file.lenses)
+        .ok_or_else(|| Error::Scene(format!("{fname}: scene has no lenses")))
+}
+
+/// A collimated grid of rays traced through the lenses of a scene
+struct Pupil {
+    world: World,
+    lenses: Vec<Lens>,
+    system: System,
+    /// Image point of the grid
+    center: Vector3<f32>,
+    unit: Option<Unit>,
+}
+
+/// Trace a collimated grid of rays at the field angle through the lenses of
+/// a scene
+fn trace_pupil(fname: &str, grid: usize, field: f32) -> Result<Pupil> {
+    let scene_file = load_scene(fname)?;
+    let system = lens_system(fname, &scene_file)?;
+
+    let (lenses, unit) = (scene_file.lenses.clone(), scene_file.unit);
+    let setting = scene_file.setting();
+    let world = build_world(Scene {
+        lights: paraxial::pupil_grid(&scene_file.lenses, &system, grid, field),
+        lenses: scene_file.lenses,
+        ..setting
+    })?;
+
+    // Centre on the best focus, falling back to the paraxial focus
+    let center = focus::find_focus(&world).first()
+        .map(|f| f.best)
+        .unwrap_or_else(|| {
+            let first = &lenses[system.order[0]];
+            Vector3::new(system.focal_point(), first.pos[1], first.pos[2])
+        });
+
+    Ok(Pupil { world, lenses, system, center, unit })
+}
+
+fn wavefront_map(fname: &str, prefix: Option<&str>, field: f32) -> Result<()> {
+    const GRID: usize = 32;
+
+    let Pupil { world, lenses, system, center, unit } = trace_pupil(fname, GRID, field)?;
+    let wf = wavefront::wavefront(&world, &lenses, &system, center)
+        .ok_or_else(|| Error::Scene(format!("{fname}: too few rays left the system")))?;
+
+    let u = units::suffix(unit);
+    println!("{} rays, reference sphere radius {:.5}{u}", wf.samples.len(), wf.radius);
+    println!("    rms wavefront error   {:.6}{u}", wf.rms());
+    println!("    peak to valley        {:.6}{u}", wf.peak_to_valley());
+    println!("    zernike coefficients (noll){}", unit.map(|u| format!(", in {u}")).unwrap_or_default());
+    for (j, c) in wf.zernike(15).iter().enumerate() {
+        let (n, m) = wavefront::noll(j + 1);
+        println!("    {:>4} ({n:>2},{m:>3})  {c:>12.6}", j + 1);
+    }
+
+    let prefix = prefix.unwrap_or("wavefront");
+    let out = format!("{prefix}.csv");
+    wavefront::write_csv(&wf, create(&out)?).map_err(|e| Error::io(&out, e))?;
+
+    let points = wf.samples.iter().map(|s| (s.pupil, s.opd)).collect::<Vec<_>>();
+    let svg = plot::heat_map("Optical path difference", &points, 2.0 / GRID as f32);
+    write_file(&format!("{prefix}.svg"), svg)
+}
+
+fn write_psf(prefix: &str, title: &str, p: &psf::Psf) -> Result<()> {
+    let out = format!("{prefix}.csv");
+    p.write_csv(create(&out)?).map_err(|e| Error::io(&out, e))?;
+
+    // Plot the psf in [-1, 1] with the brightest pixels on top
+    let half = p.size as f32 / 2.0;
+    let mut points = (0..p.size * p.size)
+        .map(|k| {
+            let (i, j) = (k % p.size, k / p.size);
+            ([(i as f32 + 0.5 - half) / half, (j as f32 + 0.5 - half) / half], p.data[k])
+        })
+        .collect::<Vec<_>>();
+    points.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let svg = plot::heat_map(title, &points, 2.0 / p.size as f32);
+    write_file(&format!("{prefix}.svg"), svg)
+}
+
+fn write_mtf(prefix: &str, title: &str, m: &psf::Mtf, unit: Option<Unit>) -> Result<()> {
+    let out = format!("{prefix}.csv");
+    m.write_csv(create(&out)?).map_err(|e| Error::io(&out, e))?;
+
+    let series = |v: &[f32]| m.frequencies.iter().cloned().zip(v.iter().cloned()).collect();
+    let label = format!("spatial frequency (cycles per {})", unit.map_or("unit", Unit::name));
+    let svg = plot::line_plot(
+        title, &label, "modulation",
+        &[("tangential", series(&m.tangential)), ("sagittal", series(&m.sagittal))]
+    );
+    write_file(&format!("{prefix}.svg"), svg)
+}
+
+fn point_spread(fname: &str, prefix: Option<&str>, field: f32, wavelength: Option<&str>) -> Result<()> {
+    const GRID: usize = 32;
+    const PIXELS: usize = 64;
+
+    let prefix = prefix.unwrap_or("psf");
+    let Pupil { world, lenses, system, center, unit } = trace_pupil(fname, GRID, field)?;
+    let wavelength = wavelength
+        .map(|w| units::parse_length(w, unit).map_err(|e| Error::Scene(format!("--wavelength: {e}"))))
+        .transpose()?;
+    let u = units::suffix(unit);
+    let frequency = format!("cycles/{}", unit.map_or("unit", Unit::name));
+
+    // Geometric psf and mtf on the plane of best focus
+    let normal = focus::find_focus(&world).first()
+        .map(|f| f.normal)
+        .unwrap_or(Vector3::new(-1.0, 0.0, 0.0));
+    let plane = Plane::new(center, normal);
+
+    let spot = spot::spot_diagram(&world, &plane).into_iter().next()
+        .ok_or_else(|| Error::Scene(format!("{fname}: no rays reached the image plane")))?;
+    let rms = spot.rms_radius().max(f32::EPSILON);
+
+    let geometric = psf::geometric_psf(&spot, PIXELS, 6.0 * rms / PIXELS as f32);
+    let mtf = psf::geometric_mtf(&spot, 2.0 / rms, 50);
+
+    println!("geometric, {} rays, rms spot radius {:.6}{u}", spot.points.len(), rms);
+    println!("    {:>12} {:>12} {:>12}", frequency, "tangential", "sagittal");
+    for i in (0..mtf.frequencies.len()).step_by(5) {
+        println!("    {:>12.4} {:>12.4} {:>12.4}", mtf.frequencies[i], mtf.tangential[i], mtf.sagittal[i]);
+    }
+
+    write_psf(&format!("{prefix}_geometric"), "Geometric point spread", &geometric)?;
+    write_mtf(&format!("{prefix}_mtf_geometric"), "Geometric MTF", &mtf, unit)?;
+
+    // Diffraction psf and mtf from the wavefront
+    if let Some(wavelength) = wavelength {
+        let wf = wavefront::wavefront(&world, &lenses, &system, center)
+            .ok_or_else(|| Error::Scene(format!("{fname}: too few rays left the system")))?;
+        let radius = lenses[system.order[0]].radius;
+
+        let (diffraction, strehl) = psf::diffraction_psf(&wf, radius, wavelength, 4 * GRID, 4);
+        let mtf = psf::psf_mtf(&diffraction);
+
+        println!("diffraction, wavelength {wavelength}{u}, strehl ratio {strehl:.4}");
+        println!("    {:>12} {:>12} {:>12}", frequency, "tangential", "sagittal");
+        for i in (0..mtf.frequencies.len()).step_by(4) {
+            println!("    {:>12.4} {:>12.4} {:>12.4}", mtf.frequencies[i], mtf.tangential[i], mtf.sagittal[i]);
+        }
+
+        write_psf(&format!("{prefix}_diffraction"), "Diffraction point spread", &diffraction)?;
+        write_mtf(&format!("{prefix}_mtf_diffraction"), "Diffraction MTF", &mtf, unit)?;
+    }
+
+    Ok(())
+}
+
+fn optimize_scene(fname: &str, out: Option<&str>) -> Result<()> {
+    let mut scene_file = load_scene(fname)?;
+    let opt = scene_file.optimize.clone()
+        .ok_or_else(|| Error::Scene(format!("{fname}: scene has nothing to optimize")))?;
+
+    // The optimized lenses are saved into the scene as written, which only
+    // has its own lenses to put them in
+    for (i, v) in opt.variables.iter().enumerate() {
+        if scene_file.origins.written_lens(v.lens).is_none() {
+            return Err(Error::Scene(format!(
+                "{fname}: optimize.variables[{i}]: lens {} comes from {}, only lenses listed in `lenses` can be optimized",
+                v.lens, scene_file.origins.lenses[v.lens],
+            )));
+        }
+    }
+
+    let lights = scene_file.lights.clone();
+    let (detector, setting) = (scene_file.detector, scene_file.setting());
+    let report = optimize::optimize(&opt, &mut scene_file.lenses, detector.as_ref(), |lenses| {
+        build_world(Scene {
+            lenses: lenses.to_vec(),
+            lights: lights.clone(),
+            ..setting.clone()
+        })
+    })?;
+
+    let u = units::suffix(scene_file.unit);
+    println!("{} evaluations", report.evaluations);
+    println!("    initial merit   {:.6}", report.initial);
+    println!("    final merit     {:.6}", report.merit);
+    for v in opt.variables.iter() {
+        println!("    lens {} {:?} = {:.6}{u}", v.lens, v.param, v.get(&scene_file.lenses)?);
+    }
+
+    let out = match (out, fname.rsplit_once('.')) {
+        (Some(out), _) => out.to_string(),
+        (None, Some((stem, ext))) => format!("{stem}.optimized.{ext}"),
+        (None, None) => format!("{fname}.optimized.yaml"),
+    };
+    // Keep the includes, definitions, instances and generators as written
+    let source = std::fs::read_to_string(fname).map_err(|e| Error::io(fname, e))?;
+    let mut written = Scene::parse(fname, &source, Format::from_path(fname))?;
+    for v in opt.variables.iter() {
+        if let Some(k) = scene_file.origins.written_lens(v.lens) {
+            written.lenses[k] = scene_file.lenses[v.lens].clone();
+        }
+    }
+    written.save(&out)?;
+    println!("wrote {out}");
+
+    Ok(())
+}
+
+fn tolerance_scene(fname: &str, out: Option<&str>) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let tol = scene_file.tolerance.clone()
+        .ok_or_else(|| Error::Scene(format!("{fname}: scene has no tolerances")))?;
+
+    let lights = scene_file.lights.clone();
+    let (detector, setting) = (scene_file.detector, scene_file.setting());
+    let report = tolerance::monte_carlo(&tol, &scene_file.lenses, detector.as_ref(), |lenses| {
+        build_world(Scene {
+            lenses: lenses.to_vec(),
+            lights: lights.clone(),
+            ..setting.clone()
+        })
+    })?;
+
+    let u = if tol.metric.is_length() { units::suffix(scene_file.unit) } else { String::new() };
+    let failed = report.trials.len() - report.values().len();
+    println!("{} trials of {:?}, {failed} failed to evaluate", report.trials.len(), tol.metric);
+    println!("    nominal         {:.6}{u}", report.nominal);
+    println!("    mean            {:.6}{u}", report.mean());
+    println!("    std dev         {:.6}{u}", report.std_dev());
+    println!("    mean change     {:.6}{u}", report.mean() - report.nominal);
+    for p in [0.0, 0.5, 0.9, 0.98, 1.0] {
+        println!("    {:>3}%            {:.6}{u}", (p * 100.0) as u32, report.percentile(p));
+    }
+    if tol.max.is_some() || tol.max_change.is_some() {
+        println!("    yield           {:.1}%", 100.0 * report.yield_fraction(&tol));
+    }
+
+    if let Some(out) = out {
+        tolerance::write_csv(&tol, &report, create(out)?).map_err(|e| Error::io(out, e))?;
+        println!("wrote {out}");
+    }
+
+    Ok(())
+}
+
+fn sweep_scene(fname: &str, spec_file: &str, out: Option<&str>) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let spec = std::fs::read_to_string(spec_file).map_err(|e| Error::io(spec_file, e))?;
+    // Lengths in the spec are in the unit of the scene
+    let spec: Sweep = units::reading(scene_file.unit, None, || Format::from_path(spec_file).parse(spec_file, &spec))?;
+    spec.check(spec_file, scene_file.lenses.len(), scene_file.lights.len())?;
+
+    let (detector, setting) = (scene_file.detector, scene_file.setting());
+    let rows = sweep::run(&spec, &scene_file.lenses, &scene_file.lights, detector.as_ref(), |lenses, lights| {
+        build_world(Scene {
+            lenses: lenses.to_vec(),
+            lights: lights.to_vec(),
+            ..setting.clone()
+        })
+    })?;
+
+    sweep::write_csv(&spec, &rows, std::io::stdout()).map_err(|e| Error::io("stdout", e))?;
+    if let Some(out) = out {
+        sweep::write_csv(&spec, &rows, create(out)?).map_err(|e| Error::io(out, e))?;
+    }
+
+    Ok(())
+}
+
+/// Write the traced world as one mesh coloured like the viewer draws it,
+/// with the ray tubes when `rays` is set
+fn export(fname: &str, out: &str, rays: bool) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let scale = units::scale(scene_file.unit);
+    let world = build_world(scene_file)?;
+
+    let tris = world.world_tris();
+    let mut mesh = IndexedMesh::default();
+    let mut start = 0;
+    for (i, m) in world.models.iter().enumerate() {
+        let end = start + m.count as usize;
+        mesh.append(IndexedMesh::from_triangles(&tris[start..end]).colored(world.colors[i]));
+        start = end;
+    }
+
+    if rays {
+        for tube in world.tubes(0.005 * scale) {
+            mesh.append(IndexedMesh::from_triangles(&tube).colored(Vector4::new(1.0, 1.0, 1.0, 1.0)));
+        }
+    }
+
+    mesh::write_mesh(out, &mesh)?;
+    println!("wrote {} triangles to {out}", mesh.faces.len());
+    Ok(())
+}
+
+/// Write lens `n` on its own, untilted and centred on the origin
+fn export_lens(fname: &str, out: &str, n: &str) -> Result<()> {
+    let scene_file = load_scene(fname)?;
+    let lens = n.parse::<usize>().ok()
+        .and_then(|i| scene_file.lenses.get(i))
+        .ok_or_else(|| Error::Scene(format!("{fname}: no lens {n}, the scene has {}", scene_file.lenses.len())))?;
+
+    let mesh = lens.mesh();
+    mesh::write_mesh(out, &mesh)?;
+    println!("wrote {} triangles to {out}", mesh.faces.len());
+    Ok(())
+}
+
+#[cfg(not(feature = "viewer"))]
+fn view(_fname: &str) -> Result<()> {
+    eprintln!("built without the viewer feature, use one of the headless commands\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+#[cfg(feature = "viewer")]
+fn view(fname: &str) -> Result<()> {
+    // Load the scene before opening a window so mistakes are reported first
+    let scene_file = load_scene(fname)?;
+    let scale = units::scale(scene_file.unit);
+    let world = build_world(scene_file)?;
+
+    let event_loop = EventLoop::new();
+    let mut vulkan = VulkanState::new(&event_loop)?;
 
     // upload geometry
-    world.upload_models(&mut vulkan);
+    let mut viewer = Viewer::new(&world, &mut vulkan, scale)?;
 
     // Render scene
     let mut mouse_pressed = false;
@@ -87,7 +733,7 @@ fn main() {
                     winit::event::MouseScrollDelta::LineDelta(_, y) => y,
                     winit::event::MouseScrollDelta::PixelDelta(p) => p.y as f32,
                 };
-                world.zoom(-zoom*0.3);
+                viewer.zoom(-zoom*0.3);
             }
             Event::WindowEvent {
                 event: WindowEvent::MouseInput { state, .. },
@@ -103,7 +749,7 @@ fn main() {
                     let lng_d = (mouse_pos.0 - position.x) / 500.0;
                     let lat_d = (mouse_pos.1 - position.y) / 500.0;
 
-                    world.rotate(lat_d as f32, lng_d as f32, 0.0);
+                    viewer.rotate(lat_d as f32, lng_d as f32, 0.0);
                 }
                 *&mut mouse_pos = (position.x, position.y);
             }
@@ -116,7 +762,10 @@ fn main() {
                 vulkan.recreate_swapchain = true;
             }
             Event::MainEventsCleared => {
-                world.draw(&mut vulkan);
+                if let Err(e) = viewer.draw(&world, &mut vulkan) {
+                    eprintln!("error: {e}");
+                    std::process::exit(1);
+                }
             }
             _ => {}
         }
@@ -0,0 +1,195 @@
+use crate::geometry::{Triangle, IndexedMesh, Transform};
+use crate::world::Material;
+use crate::units;
+use crate::{ply, obj, stl};
+use crate::repair::{self, Repair};
+use crate::error::{Error, Result};
+
+use std::path::Path;
+use std::fs::File;
+use std::io::BufWriter;
+
+use cgmath::{Vector3, Matrix3, Deg};
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+/// A mesh read from a ply, obj or stl file and placed in the scene
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Mesh {
+    /// Path of the mesh file, relative to the scene file
+    pub file: String,
+    #[serde(default, deserialize_with = "units::point")]
+    #[schemars(with = "[units::Length; 3]")]
+    pub pos: [f32; 3],
+    /// Rotation in degrees about x, then y and then z
+    #[serde(default, skip_serializing_if = "is_unrotated")]
+    pub rotation: [f32; 3],
+    /// Factor the mesh is scaled by before it is rotated
+    #[serde(default = "unscaled", skip_serializing_if = "is_unscaled")]
+    pub scale: f32,
+    pub material: Material,
+}
+
+fn is_unrotated(rotation: &[f32; 3]) -> bool {
+    *rotation == [0.0, 0.0, 0.0]
+}
+
+fn unscaled() -> f32 {
+    1.0
+}
+
+fn is_unscaled(scale: &f32) -> bool {
+    *scale == 1.0
+}
+
+impl Mesh {
+    /// Read the mesh from `dir` and repair it
+    pub fn load(&self, dir: &Path) -> Result<(IndexedMesh, Repair)> {
+        let file = dir.join(&self.file).to_string_lossy().into_owned();
+        let mut mesh = read_mesh(&file)?;
+        let report = repair::repair(&mut mesh);
+        Ok((mesh, report))
+    }
+
+    /// Read and repair the mesh from `dir`, untransformed
+    pub fn triangles(&self, dir: &Path) -> Result<Vec<Triangle>> {
+        Ok(self.load(dir)?.0.triangles())
+    }
+
+    /// Where the mesh entity is placed: scaled and rotated about its origin
+    /// and moved to `pos`
+    pub fn transform(&self) -> Transform {
+        let [x, y, z] = self.rotation;
+        let rotation = Matrix3::from_angle_z(Deg(z)) * Matrix3::from_angle_y(Deg(y)) * Matrix3::from_angle_x(Deg(x));
+
+        Transform::at(self.pos.into())
+            .rotated(rotation)
+            .scaled(Vector3::new(self.scale, self.scale, self.scale))
+    }
+}
+
+/// Read a ply, obj or stl mesh, telling them apart by extension or, for other
+/// extensions, by their contents
+pub fn read_mesh(path: &str) -> Result<IndexedMesh> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("ply") => return ply::read_ply(path),
+        Some("obj") => return obj::read_obj(path),
+        Some("stl") => return stl::read_stl(path),
+        _ => {}
+    }
+
+    let data = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    if data.starts_with(b"ply") {
+        ply::parse_ply(path, &data)
+    } else if data.starts_with(b"solid") || stl::is_binary(&data) {
+        stl::parse_stl(path, &data)
+    } else if let Ok(text) = std::str::from_utf8(&data) {
+        obj::parse_obj(path, text)
+    } else {
+        Err(Error::mesh(path, None, "not a ply, obj or stl file"))
+    }
+}
+
+/// Write a mesh as a ply, obj or stl file by the extension of `path`
+pub fn write_mesh(path: &str, mesh: &IndexedMesh) -> Result<()> {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    let write = match ext.as_deref() {
+        Some("ply") => ply::write_ply,
+        Some("obj") => obj::write_obj,
+        Some("stl") => stl::write_stl,
+        _ => return Err(Error::mesh(path, None, "meshes are written as .ply, .obj or .stl")),
+    };
+
+    let f = File::create(path).map_err(|e| Error::io(path, e))?;
+    write(mesh, BufWriter::new(f)).map_err(|e| Error::io(path, e))
+}
+
+/// Fixtures and checks for the tests of the mesh formats
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Line and message of a mesh error, panicking on any other result
+    pub fn message<T>(result: Result<T>) -> (Option<usize>, String) {
+        match result {
+            Err(Error::Mesh { line, message, .. }) => (line, message),
+            Err(e) => panic!("expected a mesh error, got {e}"),
+            Ok(_) => panic!("expected a mesh error"),
+        }
+    }
+
+    /// A unit cube with its faces wound counter-clockwise seen from outside
+    pub fn cube() -> IndexedMesh {
+        let vertices = (0..8)
+            .map(|i| Vector3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2 & 1) as f32))
+            .collect();
+        let faces = vec![
+            [0, 2, 3], [0, 3, 1], [4, 5, 7], [4, 7, 6],
+            [0, 1, 5], [0, 5, 4], [2, 6, 7], [2, 7, 3],
+            [0, 4, 6], [0, 6, 2], [1, 3, 7], [1, 7, 5],
+        ];
+        IndexedMesh { vertices, faces, ..Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `data` to a file in the temporary directory and read it back
+    fn read_back(name: &str, data: &[u8]) -> Result<IndexedMesh> {
+        let path = std::env::temp_dir().join(format!("lenses-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let mesh = read_mesh(&path.to_string_lossy());
+        std::fs::remove_file(&path).unwrap();
+        mesh
+    }
+
+    fn triangle() -> IndexedMesh {
+        IndexedMesh {
+            vertices: vec![Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0)],
+            faces: vec![[0, 1, 2]],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detects_formats_by_contents() {
+        let mut ply = vec![];
+        ply::write_ply(&triangle(), &mut ply).unwrap();
+        let mut obj = vec![];
+        obj::write_obj(&triangle(), &mut obj).unwrap();
+        let mut stl = vec![];
+        stl::write_stl(&triangle(), &mut stl).unwrap();
+
+        // Each format is read back as the triangle it was written from
+        for (name, data) in [("ply.mesh", ply), ("obj.mesh", obj), ("stl.mesh", stl)] {
+            let mesh = read_back(name, &data).unwrap();
+            assert_eq!(mesh.faces, triangle().faces, "{name}");
+            assert_eq!(mesh.vertices, triangle().vertices, "{name}");
+        }
+    }
+
+    #[test]
+    fn detects_binary_stl_starting_with_solid() {
+        let mut data = vec![];
+        stl::write_stl(&triangle(), &mut data).unwrap();
+        data[..10].copy_from_slice(b"solid mesh");
+        let mesh = read_back("solid.mesh", &data).unwrap();
+        assert_eq!(mesh.faces.len(), 1);
+    }
+
+    #[test]
+    fn rejects_binary_that_is_no_mesh() {
+        assert!(matches!(read_back("noise.mesh", &[0xff, 0xfe, 0x00, 0x80]), Err(Error::Mesh { .. })));
+    }
+}
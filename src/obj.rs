@@ -0,0 +1,185 @@
+use cgmath::Vector3 as Vec3;
+use cgmath::Vector4;
+use crate::geometry::IndexedMesh;
+use crate::error::{Error, Result};
+
+use std::io::Write;
+
+/// Read the vertices and faces of a wavefront obj file. Texture
+/// coordinates, groups and materials are ignored. Vertex colours written
+/// after the position are read, and normals when every vertex is given one.
+pub fn read_obj(path: &str) -> Result<IndexedMesh> {
+    let text = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    parse_obj(path, &text)
+}
+
+/// Parse the contents of an obj file, `path` is only used in errors
+pub fn parse_obj(path: &str, text: &str) -> Result<IndexedMesh> {
+    let err = |line: usize, message: String| Error::mesh(path, Some(line), message);
+
+    let mut mesh = IndexedMesh::default();
+    let mut colors = vec![];
+    let mut normals = vec![];
+    // The normal given to each vertex by the faces using it
+    let mut vertex_normals: Vec<Option<usize>> = vec![];
+
+    for (n, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let line = line.split('#').next().unwrap_or("");
+        let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        let numbers = |words: &[&str]| words.iter()
+            .map(|w| w.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| err(n, format!("bad numbers `{}`", words.join(" "))));
+
+        match words.as_slice() {
+            ["v", v @ ..] => {
+                let ps = numbers(v)?;
+                match ps.len() {
+                    // A position with an optional weight
+                    3 | 4 => {}
+                    // A position followed by a colour
+                    6 => colors.push(Vector4::new(ps[3], ps[4], ps[5], 1.0)),
+                    _ => return Err(err(n, format!("vertex has {} numbers", ps.len()))),
+                }
+                mesh.vertices.push(Vec3::new(ps[0], ps[1], ps[2]));
+                vertex_normals.push(None);
+            }
+            ["vn", v @ ..] => {
+                let ps = numbers(v)?;
+                if ps.len() != 3 {
+                    return Err(err(n, format!("normal has {} numbers", ps.len())));
+                }
+                normals.push(Vec3::new(ps[0], ps[1], ps[2]));
+            }
+            ["f", corners @ ..] => {
+                if corners.len() < 3 {
+                    return Err(err(n, format!("face has {} vertices", corners.len())));
+                }
+
+                // Corners are `v`, `v/vt`, `v//vn` or `v/vt/vn`, counting
+                // from 1 or back from the last one read when negative
+                let resolve = |index: &str, count: usize, what: &str| {
+                    let i = index.parse::<isize>().map_err(|_| err(n, format!("bad {what} index `{index}`")))?;
+                    let resolved = if i < 0 { count as isize + i } else { i - 1 };
+                    (0..count as isize).contains(&resolved).then_some(resolved as usize)
+                        .ok_or_else(|| err(n, format!("{what} {i} is not one of the {count} read so far")))
+                };
+
+                let mut polygon = vec![];
+                for corner in corners {
+                    let mut parts = corner.split('/');
+                    let v = resolve(parts.next().unwrap_or(""), mesh.vertices.len(), "vertex")?;
+                    if let Some(vn) = parts.nth(1).filter(|p| !p.is_empty()) {
+                        vertex_normals[v] = Some(resolve(vn, normals.len(), "normal")?);
+                    }
+                    polygon.push(v);
+                }
+
+                // Split the polygon into a fan of triangles around its first
+                // vertex
+                for w in polygon[1..].windows(2) {
+                    mesh.faces.push([polygon[0], w[0], w[1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if colors.len() == mesh.vertices.len() {
+        mesh.colors = colors;
+    }
+    if let Some(ns) = vertex_normals.iter().map(|vn| vn.map(|i| normals[i])).collect::<Option<Vec<_>>>() {
+        if !ns.is_empty() {
+            mesh.normals = ns;
+        }
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as an obj file, with colours after the vertex positions and
+/// normals when the mesh has them
+pub fn write_obj<W: Write>(mesh: &IndexedMesh, mut w: W) -> std::io::Result<()> {
+    let normals = mesh.normals.len() == mesh.vertices.len() && !mesh.vertices.is_empty();
+    let colors = mesh.colors.len() == mesh.vertices.len() && !mesh.vertices.is_empty();
+
+    for (i, v) in mesh.vertices.iter().enumerate() {
+        if colors {
+            let c = mesh.colors[i];
+            writeln!(w, "v {} {} {} {} {} {}", v.x, v.y, v.z, c.x, c.y, c.z)?;
+        } else {
+            writeln!(w, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+    }
+    if normals {
+        for n in &mesh.normals {
+            writeln!(w, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+    }
+
+    for f in &mesh.faces {
+        let [a, b, c] = f.map(|i| i + 1);
+        if normals {
+            writeln!(w, "f {a}//{a} {b}//{b} {c}//{c}")?;
+        } else {
+            writeln!(w, "f {a} {b} {c}")?;
+        }
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::testing::message;
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let text = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf -4 -3 -2 -1\n";
+        let mesh = parse_obj("square.obj", text).unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn negative_indices_count_from_where_the_face_is() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 1 1 0\nf -1 -2 -3\nf -5 1 2\n";
+        let (line, message) = message(parse_obj("triangles.obj", text));
+        assert_eq!(line, Some(7));
+        assert!(message.contains("vertex -5 is not one of the 4 read so far"), "{message}");
+
+        let mesh = parse_obj("triangles.obj", text.trim_end_matches("f -5 1 2\n")).unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [3, 2, 1]]);
+    }
+
+    #[test]
+    fn normals_of_corners_without_texture_coordinates() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n";
+        let mesh = parse_obj("triangle.obj", text).unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2]]);
+        assert_eq!(mesh.normals, vec![Vec3::new(0.0, 0.0, 1.0); 3]);
+    }
+
+    #[test]
+    fn normals_are_dropped_unless_every_vertex_has_one() {
+        let text = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2 3/5\n";
+        let mesh = parse_obj("triangle.obj", text).unwrap();
+        assert!(mesh.normals.is_empty());
+    }
+
+    #[test]
+    fn colours_after_positions() {
+        let text = "v 0 0 0 1 0 0\nv 1 0 0 0 1 0\nv 0 1 0 0 0 1\nf 1 2 3\n";
+        let mesh = parse_obj("triangle.obj", text).unwrap();
+        assert_eq!(mesh.colors[2], Vector4::new(0.0, 0.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn index_past_the_vertices_read() {
+        let text = "v 0 0 0\nv 1 0 0\nf 1 2 3\nv 0 1 0\n";
+        let (line, message) = message(parse_obj("triangle.obj", text));
+        assert_eq!(line, Some(3));
+        assert!(message.contains("vertex 3 is not one of the 2 read so far"), "{message}");
+    }
+}
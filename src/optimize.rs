@@ -0,0 +1,386 @@
+use crate::geometry::Plane;
+use crate::lenses::{Lens, LensSide};
+use crate::paraxial::System;
+use crate::world::World;
+use crate::focus::find_focus;
+use crate::spot::spot_diagram;
+use crate::units;
+use crate::error::{Error, Result};
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+/// Merit returned when a design can not be evaluated at all
+const FAILED: f32 = 1e6;
+
+/// What to optimize and against which targets
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Optimize {
+    pub variables: Vec<Variable>,
+    pub targets: Vec<Target>,
+    #[serde(default = "default_iterations")]
+    pub iterations: usize,
+}
+
+fn default_iterations() -> usize {
+    200
+}
+
+/// A parameter of one lens that the optimizer may change, kept within the
+/// optional bounds
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Variable {
+    pub lens: usize,
+    pub param: Param,
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_length")]
+    #[schemars(with = "Option<units::Length>")]
+    pub min: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_length")]
+    #[schemars(with = "Option<units::Length>")]
+    pub max: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum Param {
+    /// Signed sag of the left side, positive when convex and negative when
+    /// concave
+    Left,
+    /// Signed sag of the right side
+    Right,
+    Thickness,
+    /// Radius of the lens aperture
+    Radius,
+    X,
+    Y,
+    Z,
+}
+
+impl Variable {
+    /// Error for a variable of a lens past the end of `count` lenses
+    fn missing(&self, count: usize) -> Error {
+        Error::Scene(format!("variable of lens {}, the scene has {count} lenses", self.lens))
+    }
+
+    pub fn get(&self, lenses: &[Lens]) -> Result<f32> {
+        let lens = lenses.get(self.lens).ok_or_else(|| self.missing(lenses.len()))?;
+        Ok(match self.param {
+            Param::Left => lens.left.sag(),
+            Param::Right => lens.right.sag(),
+            Param::Thickness => lens.center_thickness(),
+            Param::Radius => lens.radius,
+            Param::X => lens.pos[0],
+            Param::Y => lens.pos[1],
+            Param::Z => lens.pos[2],
+        })
+    }
+
+    pub fn set(&self, lenses: &mut [Lens], v: f32) -> Result<()> {
+        let v = v.max(self.min.unwrap_or(f32::NEG_INFINITY))
+            .min(self.max.unwrap_or(f32::INFINITY));
+
+        let count = lenses.len();
+        let lens = lenses.get_mut(self.lens).ok_or_else(|| self.missing(count))?;
+
+        // A cap can never be taller than the lens is wide
+        let max_sag = 0.99 * lens.radius;
+        match self.param {
+            Param::Left => lens.left = LensSide::from_sag(v.clamp(-max_sag, max_sag)),
+            Param::Right => lens.right = LensSide::from_sag(v.clamp(-max_sag, max_sag)),
+            Param::Thickness => lens.thickness = Some(v.max(0.0)),
+            Param::Radius => lens.radius = v.max(0.0),
+            Param::X => lens.pos[0] = v,
+            Param::Y => lens.pos[1] = v,
+            Param::Z => lens.pos[2] = v,
+        }
+
+        Ok(())
+    }
+}
+
+/// A weighted term of the merit function
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Target {
+    pub operand: Operand,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum Operand {
+    /// RMS spot radius of the traced fields, on the detector if there is one
+    /// and at best focus otherwise. Targets zero.
+    RmsSpot,
+    /// Paraxial effective focal length of the system
+    FocalLength(
+        #[serde(deserialize_with = "units::length")]
+        #[schemars(with = "units::Length")]
+        f32
+    ),
+    /// Paraxial back focal length of the system
+    BackFocalLength(
+        #[serde(deserialize_with = "units::length")]
+        #[schemars(with = "units::Length")]
+        f32
+    ),
+}
+
+impl Target {
+    /// Weighted error of the target for lenses traced in `world`
+    pub fn error(&self, world: &World, lenses: &[Lens], detector: Option<&Plane>) -> f32 {
+        let e = match self.operand {
+            Operand::RmsSpot => rms_spot(world, detector),
+            Operand::FocalLength(f) => System::new(lenses)
+                .map(|s| s.first_order().efl - f)
+                .unwrap_or(FAILED),
+            Operand::BackFocalLength(f) => System::new(lenses)
+                .map(|s| s.first_order().bfl - f)
+                .unwrap_or(FAILED),
+        };
+
+        if e.is_finite() { self.weight * e } else { FAILED }
+    }
+}
+
+/// Mean RMS spot radius over all fields and the lenses they leave, weighted
+/// by their ray counts
+pub fn rms_spot(world: &World, detector: Option<&Plane>) -> f32 {
+    let spots = match detector {
+        Some(plane) => spot_diagram(world, plane).into_iter()
+            .filter(|s| !s.points.is_empty())
+            .map(|s| (s.points.len(), s.rms_radius()))
+            .collect::<Vec<_>>(),
+        None => find_focus(world).into_iter()
+            .map(|f| (f.rays, f.rms))
+            .collect(),
+    };
+
+    let rays = spots.iter().map(|s| s.0).sum::<usize>();
+    if rays == 0 {
+        return FAILED;
+    }
+
+    spots.iter().map(|&(n, r)| n as f32 * r).sum::<f32>() / rays as f32
+}
+
+/// Sum of the squared weighted errors of every target
+pub fn merit(opt: &Optimize, world: &World, lenses: &[Lens], detector: Option<&Plane>) -> f32 {
+    opt.targets.iter()
+        .map(|t| t.error(world, lenses, detector).powi(2))
+        .sum()
+}
+
+/// Outcome of an optimization run
+pub struct Report {
+    pub initial: f32,
+    pub merit: f32,
+    pub evaluations: usize,
+}
+
+/// Optimize the variables of `lenses` in place with Nelder-Mead, building and
+/// tracing a world for every evaluation with `build`. Stops at the first world
+/// that fails to build or variable that can not be set.
+pub fn optimize<B>(opt: &Optimize, lenses: &mut [Lens], detector: Option<&Plane>, mut build: B) -> Result<Report>
+where
+    B: FnMut(&[Lens]) -> Result<World>
+{
+    let mut evaluations = 0;
+    let mut error = None;
+    let mut f = |x: &[f32]| {
+        if error.is_some() {
+            return FAILED;
+        }
+
+        let mut trial = lenses.to_vec();
+        let set = opt.variables.iter()
+            .zip(x)
+            .try_for_each(|(v, &x)| v.set(&mut trial, x));
+
+        evaluations += 1;
+        match set.and_then(|_| build(&trial)) {
+            Ok(world) => merit(opt, &world, &trial, detector),
+            Err(e) => {
+                error = Some(e);
+                FAILED
+            }
+        }
+    };
+
+    let x0 = opt.variables.iter().map(|v| v.get(lenses)).collect::<Result<Vec<_>>>()?;
+    let initial = f(&x0);
+    let (x, merit) = nelder_mead(&mut f, &x0, opt.iterations);
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    for (v, &x) in opt.variables.iter().zip(x.iter()) {
+        v.set(lenses, x)?;
+    }
+
+    Ok(Report { initial, merit, evaluations })
+}
+
+/// Minimise `f` starting from `x0` with the Nelder-Mead simplex method,
+/// returning the best point and its value. Points where `f` is NaN are
+/// treated as worse than any other.
+pub fn nelder_mead<F>(f: &mut F, x0: &[f32], iterations: usize) -> (Vec<f32>, f32)
+where
+    F: FnMut(&[f32]) -> f32
+{
+    let mut f = |x: &[f32]| {
+        let v = f(x);
+        if v.is_nan() { f32::INFINITY } else { v }
+    };
+
+    let n = x0.len();
+    if n == 0 {
+        return (vec![], f(x0));
+    }
+
+    // Start from a simplex stepping a tenth of each value along each axis
+    let mut simplex = vec![x0.to_vec()];
+    for i in 0..n {
+        let mut x = x0.to_vec();
+        x[i] += if x[i].abs() > 1e-3 { 0.1 * x[i] } else { 0.01 };
+        simplex.push(x);
+    }
+    let mut values = simplex.iter().map(|x| f(x)).collect::<Vec<_>>();
+
+    let point = |a: &[f32], b: &[f32], t: f32| {
+        a.iter().zip(b).map(|(a, b)| a + t * (b - a)).collect::<Vec<_>>()
+    };
+
+    for _ in 0..iterations {
+        // Order best to worst
+        let mut order = (0..=n).collect::<Vec<_>>();
+        order.sort_by(|&i, &j| values[i].total_cmp(&values[j]));
+        simplex = order.iter().map(|&i| simplex[i].clone()).collect();
+        values = order.iter().map(|&i| values[i]).collect();
+
+        if (values[n] - values[0]).abs() <= 1e-9 * values[0].abs().max(1e-9) {
+            break;
+        }
+
+        let centroid = (0..n)
+            .map(|k| simplex[..n].iter().map(|x| x[k]).sum::<f32>() / n as f32)
+            .collect::<Vec<_>>();
+
+        let reflected = point(&centroid, &simplex[n], -1.0);
+        let fr = f(&reflected);
+
+        if fr < values[0] {
+            let expanded = point(&centroid, &simplex[n], -2.0);
+            let fe = f(&expanded);
+            if fe < fr {
+                simplex[n] = expanded;
+                values[n] = fe;
+            } else {
+                simplex[n] = reflected;
+                values[n] = fr;
+            }
+        } else if fr < values[n-1] {
+            simplex[n] = reflected;
+            values[n] = fr;
+        } else {
+            let contracted = point(&centroid, &simplex[n], 0.5);
+            let fc = f(&contracted);
+            if fc < values[n] {
+                simplex[n] = contracted;
+                values[n] = fc;
+            } else {
+                // Shrink everything towards the best point
+                for i in 1..=n {
+                    simplex[i] = point(&simplex[0], &simplex[i], 0.5);
+                    values[i] = f(&simplex[i]);
+                }
+            }
+        }
+    }
+
+    let best = (0..=n)
+        .min_by(|&i, &j| values[i].total_cmp(&values[j]))
+        .unwrap();
+
+    (simplex[best].clone(), values[best])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::testing::lens;
+
+    #[test]
+    fn nelder_mead_finds_the_minimum_of_a_quadratic() {
+        let mut calls = 0;
+        let mut f = |x: &[f32]| {
+            calls += 1;
+            (x[0] - 1.0).powi(2) + 10.0 * (x[1] + 2.0).powi(2) + 3.0
+        };
+
+        let (x, v) = nelder_mead(&mut f, &[0.0, 0.0], 500);
+        assert!((x[0] - 1.0).abs() < 1e-2 && (x[1] + 2.0).abs() < 1e-2, "{x:?}");
+        assert!((v - 3.0).abs() < 1e-3);
+        assert!(calls < 1000);
+    }
+
+    #[test]
+    fn nelder_mead_steps_around_nan() {
+        let mut f = |x: &[f32]| if x[0] < 0.5 { f32::NAN } else { (x[0] - 2.0).powi(2) };
+        let (x, v) = nelder_mead(&mut f, &[1.0], 200);
+        assert!((x[0] - 2.0).abs() < 1e-2 && v < 1e-3, "{x:?} {v}");
+    }
+
+    #[test]
+    fn variables_are_kept_within_bounds() {
+        let mut lenses = vec![lens(1.0, LensSide::Flat, LensSide::Flat, Some(0.2), [0.0; 3])];
+        let variable = |param, min, max| Variable { lens: 0, param, min, max };
+
+        variable(Param::Left, None, None).set(&mut lenses, 5.0).unwrap();
+        assert!(matches!(lenses[0].left, LensSide::Convex(h) if h == 0.99));
+        variable(Param::Right, Some(-0.3), None).set(&mut lenses, -0.5).unwrap();
+        assert!(matches!(lenses[0].right, LensSide::Concave(h) if h == 0.3));
+        variable(Param::X, None, Some(1.0)).set(&mut lenses, 3.0).unwrap();
+        assert_eq!(lenses[0].pos[0], 1.0);
+
+        assert_eq!(variable(Param::Right, None, None).get(&lenses).unwrap(), -0.3);
+        assert!(Variable { lens: 1, ..variable(Param::X, None, None) }.get(&lenses).is_err());
+        assert!(Variable { lens: 1, ..variable(Param::X, None, None) }.set(&mut lenses, 0.0).is_err());
+    }
+
+    #[test]
+    fn reaches_a_paraxial_focal_length() {
+        let mut lenses = vec![lens(1.0, LensSide::Convex(0.1), LensSide::Flat, Some(0.3), [0.0; 3])];
+        let opt = Optimize {
+            variables: vec![Variable { lens: 0, param: Param::Left, min: Some(0.0), max: None }],
+            targets: vec![Target { operand: Operand::FocalLength(3.0), weight: 1.0 }],
+            iterations: 200,
+        };
+
+        // Only the paraxial target is used, so nothing needs tracing
+        let report = optimize(&opt, &mut lenses, None, |_| Ok(World::new())).unwrap();
+        assert!(report.merit < report.initial);
+        assert!(report.evaluations > 1);
+
+        // A plano-convex lens has f = R / (n - 1)
+        let r = lenses[0].left.radius_of_curvature(lenses[0].radius);
+        assert!((r - 3.0 * (lenses[0].index - 1.0)).abs() < 1e-2, "{r}");
+    }
+
+    #[test]
+    fn stops_at_the_first_world_that_fails_to_build() {
+        let mut lenses = vec![lens(1.0, LensSide::Convex(0.1), LensSide::Flat, Some(0.3), [0.0; 3])];
+        let opt = Optimize {
+            variables: vec![Variable { lens: 0, param: Param::Left, min: None, max: None }],
+            targets: vec![Target { operand: Operand::RmsSpot, weight: 1.0 }],
+            iterations: 10,
+        };
+
+        let result = optimize(&opt, &mut lenses, None, |_| Err(Error::NoKdTree));
+        assert!(matches!(result, Err(Error::NoKdTree)));
+        assert!(matches!(lenses[0].left, LensSide::Convex(h) if h == 0.1));
+    }
+}
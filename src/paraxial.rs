@@ -0,0 +1,266 @@
+use crate::lenses::Lens;
+use crate::light::Light;
+
+use std::ops::Mul;
+
+/// A ray transfer matrix acting on a paraxial ray given as its height and
+/// its angle multiplied by the index of the medium it travels through.
+///
+/// Distances are measured along the direction light travels, which for the
+/// lenses in a scene is along -x.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayTransfer {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+}
+
+impl RayTransfer {
+    pub fn identity() -> Self {
+        Self { a: 1.0, b: 0.0, c: 0.0, d: 1.0 }
+    }
+
+    /// Travel a distance `t` through a medium of index `n`
+    pub fn translation(t: f32, n: f32) -> Self {
+        Self { a: 1.0, b: t / n, c: 0.0, d: 1.0 }
+    }
+
+    /// Cross a surface of curvature `c` from index `n1` into index `n2`
+    pub fn refraction(c: f32, n1: f32, n2: f32) -> Self {
+        Self { a: 1.0, b: 0.0, c: -(n2 - n1) * c, d: 1.0 }
+    }
+
+    /// The matrix of a single lens in air, from its left to its right vertex
+    pub fn lens(lens: &Lens) -> Self {
+        let (c1, c2) = curvatures(lens);
+        let n = lens.index;
+
+        Self::refraction(c2, n, 1.0)
+            * Self::translation(lens.center_thickness(), n)
+            * Self::refraction(c1, 1.0, n)
+    }
+
+    pub fn first_order(&self) -> FirstOrder {
+        let Self { a, c, d, .. } = *self;
+
+        FirstOrder {
+            power: -c,
+            efl: -1.0 / c,
+            bfl: -a / c,
+            ffl: -d / c,
+            front_principal: (d - 1.0) / c,
+            rear_principal: (1.0 - a) / c,
+        }
+    }
+}
+
+impl Mul for RayTransfer {
+    type Output = Self;
+
+    fn mul(self, o: Self) -> Self {
+        Self {
+            a: self.a * o.a + self.b * o.c,
+            b: self.a * o.b + self.b * o.d,
+            c: self.c * o.a + self.d * o.c,
+            d: self.c * o.b + self.d * o.d,
+        }
+    }
+}
+
+/// Curvatures of the left and right side of a lens, positive when the centre
+/// of curvature lies further along the direction of travel
+pub fn curvatures(lens: &Lens) -> (f32, f32) {
+    let c1 = lens.left.sag().signum() / lens.left.radius_of_curvature(lens.radius);
+    let c2 = -lens.right.sag().signum() / lens.right.radius_of_curvature(lens.radius);
+
+    (c1, c2)
+}
+
+/// First order properties of a lens or system in air. Focal lengths are
+/// infinite when the power is zero.
+#[derive(Debug, Clone, Copy)]
+pub struct FirstOrder {
+    pub power: f32,
+    /// Effective focal length
+    pub efl: f32,
+    /// Back focal length, from the last vertex to the rear focal point
+    pub bfl: f32,
+    /// Front focal length, from the front focal point to the first vertex
+    pub ffl: f32,
+    /// Front principal plane measured from the first vertex
+    pub front_principal: f32,
+    /// Rear principal plane measured from the last vertex
+    pub rear_principal: f32,
+}
+
+/// A set of lenses sharing an axis, ordered along the direction of travel
+pub struct System {
+    /// Indices into the lenses the system was made from, in order
+    pub order: Vec<usize>,
+    pub matrix: RayTransfer,
+    /// x coordinate of the first and last vertex
+    pub front: f32,
+    pub rear: f32,
+}
+
+impl System {
+    /// Order the lenses along -x and chain their matrices together
+    pub fn new(lenses: &[Lens]) -> Option<Self> {
+        let mut order = (0..lenses.len()).collect::<Vec<_>>();
+        order.sort_by(|&i, &j| lenses[j].pos[0].total_cmp(&lenses[i].pos[0]));
+
+        let first = &lenses[*order.first()?];
+        let front = first.pos[0] + first.vertices().0;
+
+        let mut matrix = RayTransfer::identity();
+        let mut rear = front;
+        for &i in order.iter() {
+            let lens = &lenses[i];
+            let (l, r) = lens.vertices();
+
+            matrix = RayTransfer::lens(lens)
+                * RayTransfer::translation(rear - (lens.pos[0] + l), 1.0)
+                * matrix;
+            rear = lens.pos[0] + r;
+        }
+
+        Some(Self { order, matrix, front, rear })
+    }
+
+    pub fn first_order(&self) -> FirstOrder {
+        self.matrix.first_order()
+    }
+
+    /// x coordinate of the rear focal point
+    pub fn focal_point(&self) -> f32 {
+        self.rear - self.first_order().bfl
+    }
+}
+
+/// Distance in front of the first vertex that lights start at, clear of the
+/// rim of a concave first side by the radius of the lens so that it keeps
+/// its size relative to the lens in any unit
+fn standoff(first: &Lens) -> f32 {
+    (-first.left.sag()).max(0.0) + first.radius
+}
+
+/// Lasers parallel to the axis of the system in the meridional (xy) plane,
+/// spread over the given fraction of the aperture of the first lens
+pub fn axial_fan(lenses: &[Lens], system: &System, aperture: f32, count: usize) -> Vec<Light> {
+    let first = &lenses[system.order[0]];
+    let spread = aperture * first.radius;
+    let start = system.front + standoff(first);
+
+    (0..count)
+        .map(|i| {
+            let h = -spread + 2.0 * spread * (i as f32 + 0.5) / count as f32;
+            Light::Laser(
+                [start, first.pos[1] + h, first.pos[2]],
+                [-1.0, 0.0, 0.0]
+            )
+        })
+        .collect()
+}
+
+/// Lasers on a square grid clipped to the aperture of the first lens, `count`
+/// rays across. The rays are tilted by `angle` radians from the axis in the
+/// xy plane and aimed so that the grid is centred on the first vertex.
+pub fn pupil_grid(lenses: &[Lens], system: &System, count: usize, angle: f32) -> Vec<Light> {
+    let first = &lenses[system.order[0]];
+    let r = 0.98 * first.radius;
+    let step = 2.0 * r / count as f32;
+
+    let dir = [-angle.cos(), -angle.sin(), 0.0];
+    let back = standoff(first) / angle.cos();
+
+    (0..count)
+        .flat_map(|i| (0..count).map(move |j| (i, j)))
+        .map(|(i, j)| (-r + step * (i as f32 + 0.5), -r + step * (j as f32 + 0.5)))
+        .filter(|(y, z)| y * y + z * z <= r * r)
+        .map(|(y, z)| Light::Laser(
+            [
+                system.front - dir[0] * back,
+                first.pos[1] + y - dir[1] * back,
+                first.pos[2] + z,
+            ],
+            dir
+        ))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::LensSide;
+    use crate::world::testing::lens;
+
+    fn close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4 * b.abs().max(1.0), "{a} != {b}");
+    }
+
+    #[test]
+    fn thick_lens_follows_the_lensmakers_equation() {
+        let l = lens(1.0, LensSide::Convex(0.1), LensSide::Concave(0.05), Some(0.3), [0.0; 3]);
+        let (n, d) = (l.index, l.center_thickness());
+        // A meniscus, both centres of curvature lie further along -x so
+        // both radii are positive
+        let r1 = l.left.radius_of_curvature(l.radius);
+        let r2 = l.right.radius_of_curvature(l.radius);
+
+        let power = (n - 1.0) * (1.0 / r1 - 1.0 / r2 + (n - 1.0) * d / (n * r1 * r2));
+        let f = RayTransfer::lens(&l).first_order();
+        close(f.power, power);
+        close(f.efl, 1.0 / power);
+        close(f.bfl, (1.0 - (n - 1.0) * d / (n * r1)) / power);
+        close(f.ffl, (1.0 + (n - 1.0) * d / (n * r2)) / power);
+    }
+
+    #[test]
+    fn plano_convex_focal_length_ignores_thickness() {
+        for thickness in [0.2, 0.5] {
+            let l = lens(1.0, LensSide::Convex(0.2), LensSide::Flat, Some(thickness), [0.0; 3]);
+            let r = l.left.radius_of_curvature(l.radius);
+            let f = RayTransfer::lens(&l).first_order();
+
+            close(f.efl, r / (l.index - 1.0));
+            close(f.bfl, f.efl - thickness / l.index);
+            // The front principal plane touches the curved vertex
+            close(f.front_principal, 0.0);
+        }
+    }
+
+    #[test]
+    fn flat_glass_has_no_power() {
+        let l = lens(1.0, LensSide::Flat, LensSide::Flat, Some(0.2), [0.0; 3]);
+        let f = RayTransfer::lens(&l).first_order();
+        assert_eq!(f.power, 0.0);
+        assert!(f.efl.is_infinite());
+    }
+
+    #[test]
+    fn systems_combine_lenses_in_order_along_the_axis() {
+        let a = lens(1.0, LensSide::Convex(0.1), LensSide::Flat, Some(0.2), [2.0, 0.0, 0.0]);
+        let b = lens(1.0, LensSide::Flat, LensSide::Convex(0.1), Some(0.2), [0.0, 0.0, 0.0]);
+        let (fa, fb) = (RayTransfer::lens(&a).first_order(), RayTransfer::lens(&b).first_order());
+
+        // Distance between the rear principal plane of the first lens and
+        // the front principal plane of the second, along the direction of
+        // travel
+        let gap = (a.pos[0] + a.vertices().1) - (b.pos[0] + b.vertices().0);
+        let e = gap - fa.rear_principal + fb.front_principal;
+        let power = fa.power + fb.power - e * fa.power * fb.power;
+
+        let forward = System::new(&[a.clone(), b.clone()]).unwrap();
+        let backward = System::new(&[b, a]).unwrap();
+        assert_eq!(forward.order, [0, 1]);
+        assert_eq!(backward.order, [1, 0]);
+        assert_eq!(forward.matrix, backward.matrix);
+        close(forward.front, 2.15);
+        close(forward.rear, -0.15);
+
+        let f = forward.first_order();
+        close(f.power, power);
+        close(forward.focal_point(), forward.rear - f.bfl);
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Write;
+
+const WIDTH: f32 = 640.0;
+const HEIGHT: f32 = 480.0;
+const MARGIN: f32 = 60.0;
+const TICKS: usize = 5;
+const COLORS: [&str; 4] = ["#1f4e9c", "#c0392b", "#27ae60", "#8e44ad"];
+
+/// Render named series of points as an svg line plot
+pub fn line_plot(title: &str, xlabel: &str, ylabel: &str, series: &[(&str, Vec<(f32, f32)>)]) -> String {
+    let pts = series.iter().flat_map(|s| s.1.iter());
+    let (mut x0, mut x1, mut y0, mut y1) = pts.fold(
+        (0.0f32, 0.0f32, 0.0f32, 0.0f32),
+        |(x0, x1, y0, y1), &(x, y)| (x0.min(x), x1.max(x), y0.min(y), y1.max(y))
+    );
+
+    // Avoid dividing by zero for flat data
+    if x1 - x0 <= f32::EPSILON { x0 -= 1.0; x1 += 1.0; }
+    if y1 - y0 <= f32::EPSILON { y0 -= 1.0; y1 += 1.0; }
+
+    let sx = |x: f32| MARGIN + (x - x0) / (x1 - x0) * (WIDTH - 2.0 * MARGIN);
+    let sy = |y: f32| HEIGHT - MARGIN - (y - y0) / (y1 - y0) * (HEIGHT - 2.0 * MARGIN);
+
+    let mut s = String::new();
+    writeln!(s, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" font-family="sans-serif" font-size="12">"#).unwrap();
+    writeln!(s, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    writeln!(s, r#"<text x="{}" y="24" text-anchor="middle" font-size="16">{title}</text>"#, WIDTH / 2.0).unwrap();
+    writeln!(s, r#"<text x="{}" y="{}" text-anchor="middle">{xlabel}</text>"#, WIDTH / 2.0, HEIGHT - 15.0).unwrap();
+    writeln!(s, r#"<text x="15" y="{}" text-anchor="middle" transform="rotate(-90 15 {})">{ylabel}</text>"#, HEIGHT / 2.0, HEIGHT / 2.0).unwrap();
+
+    // Frame, zero lines and ticks
+    writeln!(s, r#"<rect x="{MARGIN}" y="{MARGIN}" width="{}" height="{}" fill="none" stroke="black"/>"#, WIDTH - 2.0 * MARGIN, HEIGHT - 2.0 * MARGIN).unwrap();
+    if x0 < 0.0 && x1 > 0.0 {
+        writeln!(s, r#"<line x1="{0}" y1="{MARGIN}" x2="{0}" y2="{1}" stroke="gray"/>"#, sx(0.0), HEIGHT - MARGIN).unwrap();
+    }
+    if y0 < 0.0 && y1 > 0.0 {
+        writeln!(s, r#"<line x1="{MARGIN}" y1="{0}" x2="{1}" y2="{0}" stroke="gray"/>"#, sy(0.0), WIDTH - MARGIN).unwrap();
+    }
+
+    for i in 0..=TICKS {
+        let x = x0 + (x1 - x0) * i as f32 / TICKS as f32;
+        let y = y0 + (y1 - y0) * i as f32 / TICKS as f32;
+        writeln!(s, r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#, sx(x), HEIGHT - MARGIN + 16.0, tick(x)).unwrap();
+        writeln!(s, r#"<text x="{}" y="{}" text-anchor="end">{}</text>"#, MARGIN - 6.0, sy(y) + 4.0, tick(y)).unwrap();
+    }
+
+    for (i, (name, points)) in series.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let line = points.iter()
+            .map(|&(x, y)| format!("{:.2},{:.2}", sx(x), sy(y)))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        writeln!(s, r#"<polyline points="{line}" fill="none" stroke="{color}" stroke-width="1.5"/>"#).unwrap();
+        writeln!(s, r#"<text x="{}" y="{}" fill="{color}">{name}</text>"#, MARGIN + 8.0, MARGIN + 16.0 * (i + 1) as f32).unwrap();
+    }
+
+    writeln!(s, "</svg>").unwrap();
+    s
+}
+
+fn tick(v: f32) -> String {
+    if v != 0.0 && (v.abs() < 1e-2 || v.abs() >= 1e4) {
+        format!("{v:.2e}")
+    } else {
+        format!("{v:.3}")
+    }
+}
+
+/// Render values at points in [-1, 1]^2 as an svg heat map of square cells
+/// `cell` wide, shading from blue at the lowest value to red at the highest
+pub fn heat_map(title: &str, points: &[([f32; 2], f32)], cell: f32) -> String {
+    let (lo, hi) = points.iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), p| (lo.min(p.1), hi.max(p.1)));
+    let range = if hi - lo > f32::EPSILON { hi - lo } else { 1.0 };
+
+    let size = HEIGHT - 2.0 * MARGIN;
+    let sx = |x: f32| MARGIN + (x + 1.0) / 2.0 * size;
+    let sy = |y: f32| MARGIN + (1.0 - y) / 2.0 * size;
+    let w = cell / 2.0 * size;
+
+    let mut s = String::new();
+    writeln!(s, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{HEIGHT}" font-family="sans-serif" font-size="12">"#, size + 2.0 * MARGIN + 120.0).unwrap();
+    writeln!(s, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    writeln!(s, r#"<text x="{}" y="24" text-anchor="middle" font-size="16">{title}</text>"#, MARGIN + size / 2.0).unwrap();
+
+    for &([x, y], v) in points {
+        let (r, g, b) = shade((v - lo) / range);
+        writeln!(
+            s, r#"<rect x="{:.2}" y="{:.2}" width="{w:.2}" height="{w:.2}" fill="rgb({r},{g},{b})"/>"#,
+            sx(x) - w / 2.0, sy(y) - w / 2.0
+        ).unwrap();
+    }
+
+    writeln!(s, r#"<rect x="{MARGIN}" y="{MARGIN}" width="{size}" height="{size}" fill="none" stroke="black"/>"#).unwrap();
+
+    // Colour scale
+    let lx = size + 2.0 * MARGIN;
+    for i in 0..=10 {
+        let f = i as f32 / 10.0;
+        let (r, g, b) = shade(f);
+        writeln!(s, r#"<rect x="{lx}" y="{:.2}" width="20" height="{:.2}" fill="rgb({r},{g},{b})"/>"#, MARGIN + (1.0 - f) * (size - size / 11.0), size / 11.0).unwrap();
+    }
+    writeln!(s, r#"<text x="{}" y="{}">{}</text>"#, lx + 26.0, MARGIN + 12.0, tick(hi)).unwrap();
+    writeln!(s, r#"<text x="{}" y="{}">{}</text>"#, lx + 26.0, MARGIN + size, tick(lo)).unwrap();
+
+    writeln!(s, "</svg>").unwrap();
+    s
+}
+
+/// Blue through white to red for f in [0, 1]
+fn shade(f: f32) -> (u8, u8, u8) {
+    let f = f.clamp(0.0, 1.0);
+    if f < 0.5 {
+        let t = f * 2.0;
+        ((255.0 * t) as u8, (255.0 * t) as u8, 255)
+    } else {
+        let t = (1.0 - f) * 2.0;
+        (255, (255.0 * t) as u8, (255.0 * t) as u8)
+    }
+}
@@ -1,65 +1,484 @@
 use cgmath::Vector3 as Vec3;
-use crate::geometry::Triangle;
+use cgmath::Vector4;
+use crate::geometry::{Triangle, IndexedMesh};
+use crate::error::{Error, Result};
 
-use std::io::{BufReader, BufRead};
-use std::fs::File;
+use std::io::Write;
 
-pub fn load_ply(path: &str) -> Vec<Triangle> {
-    let r = BufReader::new(File::open(path).unwrap());
+/// Type of a ply property or of the count or items of a list property
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// Value of full intensity for a colour of this type
+    fn full(self) -> f64 {
+        match self {
+            Self::F32 | Self::F64 => 1.0,
+            Self::U16 | Self::I16 => 65535.0,
+            _ => 255.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Property {
+    Scalar(Scalar, String),
+    /// Type of the count, type of the items
+    List(Scalar, Scalar, String),
+}
 
-    let mut lines = r.lines()
-        .filter_map(|l| l.ok());
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Self::Scalar(_, name) | Self::List(_, _, name) => name,
+        }
+    }
+}
 
-    // Find the number of vertices
-    let vcount = lines.find(|s| {
-        s.starts_with("element vertex")
-    }).map(|s| {
-        s.split(" ").nth(2).unwrap().parse::<usize>().unwrap()
-    }).unwrap();
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
 
-    // Find the number of faces
-    let fcount = lines.find(|s| {
-        s.starts_with("element face")
-    }).map(|s| {
-        s.split(" ").nth(2).unwrap().parse::<usize>().unwrap()
-    }).unwrap();
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
 
-    // Consume the rest of the header
-    lines.find(|s| s.starts_with("end_header")).unwrap();
+/// The data after the header, read one value at a time
+enum Body<'a> {
+    /// Every word of the data with the line it is on
+    Ascii(std::vec::IntoIter<(usize, &'a str)>),
+    Binary { bytes: &'a [u8], at: usize, big: bool },
+}
 
-    // Read in the list of all the vertices
-    let mut vs = Vec::with_capacity(vcount);
+impl Body<'_> {
+    fn scalar(&mut self, t: Scalar) -> std::result::Result<f64, (Option<usize>, String)> {
+        match self {
+            Self::Ascii(words) => {
+                let (n, word) = words.next().ok_or((None, "file ends in the middle of the data".to_string()))?;
+                let v = word.parse::<f64>().map_err(|_| (Some(n), format!("`{word}` is not a number")))?;
+                if !matches!(t, Scalar::F32 | Scalar::F64) && v.fract() != 0.0 {
+                    return Err((Some(n), format!("`{word}` is not an integer")));
+                }
+                Ok(v)
+            }
+            Self::Binary { bytes, at, big } => {
+                let b = bytes.get(*at..*at + t.size())
+                    .ok_or((None, "file ends in the middle of the data".to_string()))?;
+                *at += t.size();
 
-    for _ in 0..vcount {
-        let s = lines.next().unwrap();
+                macro_rules! read {
+                    ($t:ty) => {{
+                        let b = b.try_into().unwrap();
+                        (if *big { <$t>::from_be_bytes(b) } else { <$t>::from_le_bytes(b) }) as f64
+                    }};
+                }
 
-        let mut ps = s.split_ascii_whitespace();
-        let x = ps.next().unwrap().parse::<f32>().unwrap();
-        let y = ps.next().unwrap().parse::<f32>().unwrap();
-        let z = ps.next().unwrap().parse::<f32>().unwrap();
+                Ok(match t {
+                    Scalar::I8 => b[0] as i8 as f64,
+                    Scalar::U8 => b[0] as f64,
+                    Scalar::I16 => read!(i16),
+                    Scalar::U16 => read!(u16),
+                    Scalar::I32 => read!(i32),
+                    Scalar::U32 => read!(u32),
+                    Scalar::F32 => read!(f32),
+                    Scalar::F64 => read!(f64),
+                })
+            }
+        }
+    }
 
-        vs.push(Vec3{ x, y, z });
+    /// Values of the properties of one element, a single value for each
+    /// scalar and the items of each list
+    fn element(&mut self, e: &Element) -> std::result::Result<Vec<Vec<f64>>, (Option<usize>, String)> {
+        e.properties.iter().map(|p| match p {
+            Property::Scalar(t, _) => Ok(vec![self.scalar(*t)?]),
+            Property::List(count, item, name) => {
+                let n = self.scalar(*count)?;
+                if n < 0.0 {
+                    return Err((None, format!("list `{name}` has {n} items")));
+                }
+                (0..n as usize).map(|_| self.scalar(*item)).collect()
+            }
+        }).collect()
     }
+}
 
-    // Now go through the faces and push all the triangles
-    let mut ts = Vec::with_capacity(fcount);
+/// Read the triangles of a ply file
+pub fn load_ply(path: &str) -> Result<Vec<Triangle>> {
+    Ok(read_ply(path)?.triangles())
+}
+
+/// Read a ply file, ascii or binary, with the normals and colours of its
+/// vertices when it has them. Polygons are split into fans of triangles.
+pub fn read_ply(path: &str) -> Result<IndexedMesh> {
+    let data = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_ply(path, &data)
+}
 
-    for _ in 0..fcount {
-        let s = lines.next().unwrap();
-        
-        let mut ps = s.split_ascii_whitespace();
-        ps.next().unwrap();
+/// Parse the contents of a ply file, `path` is only used in errors
+pub fn parse_ply(path: &str, data: &[u8]) -> Result<IndexedMesh> {
+    let err = |line: Option<usize>, message: String| Error::mesh(path, line, message);
 
-        let x = ps.next().unwrap().parse::<usize>().unwrap();
-        let y = ps.next().unwrap().parse::<usize>().unwrap();
-        let z = ps.next().unwrap().parse::<usize>().unwrap();
+    // The header is text up to the end_header line, whatever the encoding
+    let mut lines = vec![];
+    let mut start = 0;
+    let mut body = None;
+    while start < data.len() {
+        let end = data[start..].iter().position(|&b| b == b'\n').map_or(data.len(), |e| start + e);
+        let line = std::str::from_utf8(&data[start..end])
+            .map_err(|_| err(Some(lines.len() + 1), "header is not text".into()))?
+            .trim_end_matches('\r');
+        lines.push(line);
+        start = end + 1;
 
-        ts.push(Triangle {
-            v0: vs[x],
-            v1: vs[z],
-            v2: vs[y]
-        });
+        if line.trim() == "end_header" {
+            body = Some(start.min(data.len()));
+            break;
+        }
     }
+    let body = body.ok_or_else(|| err(None, "header never ends".into()))?;
 
-    ts
+    if lines.first().map(|l| l.trim()) != Some("ply") {
+        return Err(err(Some(1), "not a ply file".into()));
+    }
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = vec![];
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        let n = Some(i + 1);
+        let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        match words.as_slice() {
+            ["format", format, _] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    _ => return Err(err(n, format!("unknown format `{format}`"))),
+                });
+            }
+            ["element", name, count] => {
+                let count = count.parse().map_err(|_| err(n, format!("bad element count in `{line}`")))?;
+                elements.push(Element { name: name.to_string(), count, properties: vec![] });
+            }
+            ["property", "list", count, item, name] => {
+                let element = elements.last_mut().ok_or_else(|| err(n, "property before any element".into()))?;
+                let scalar = |t: &str| Scalar::parse(t).ok_or_else(|| err(n, format!("unknown property type `{t}`")));
+                element.properties.push(Property::List(scalar(count)?, scalar(item)?, name.to_string()));
+            }
+            ["property", t, name] => {
+                let element = elements.last_mut().ok_or_else(|| err(n, "property before any element".into()))?;
+                let t = Scalar::parse(t).ok_or_else(|| err(n, format!("unknown property type `{t}`")))?;
+                element.properties.push(Property::Scalar(t, name.to_string()));
+            }
+            ["comment", ..] | ["obj_info", ..] | ["end_header"] | [] => {}
+            _ => return Err(err(n, format!("can not read header line `{line}`"))),
+        }
+    }
+    let encoding = encoding.ok_or_else(|| err(None, "header has no format".into()))?;
+
+    let mut body = match encoding {
+        Encoding::Ascii => {
+            let text = std::str::from_utf8(&data[body..]).map_err(|_| err(None, "ascii data is not text".into()))?;
+            let first = lines.len() + 1;
+            let words = text.lines()
+                .enumerate()
+                .flat_map(|(i, l)| l.split_ascii_whitespace().map(move |w| (first + i, w)))
+                .collect::<Vec<_>>();
+            Body::Ascii(words.into_iter())
+        }
+        Encoding::LittleEndian | Encoding::BigEndian => Body::Binary {
+            bytes: &data[body..],
+            at: 0,
+            big: encoding == Encoding::BigEndian,
+        },
+    };
+
+    let vertex = elements.iter().position(|e| e.name == "vertex")
+        .ok_or_else(|| err(None, "no vertex element in header".into()))?;
+    let face = elements.iter().position(|e| e.name == "face")
+        .ok_or_else(|| err(None, "no face element in header".into()))?;
+
+    let find = |e: &Element, name: &str| e.properties.iter().position(|p| p.name() == name);
+    let position = ["x", "y", "z"].map(|a| find(&elements[vertex], a));
+    let [Some(x), Some(y), Some(z)] = position else {
+        return Err(err(None, "vertices have no x, y and z".into()));
+    };
+    let normal = match ["nx", "ny", "nz"].map(|a| find(&elements[vertex], a)) {
+        [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+        _ => None,
+    };
+    let color = match ["red", "green", "blue"].map(|a| find(&elements[vertex], a)) {
+        [Some(r), Some(g), Some(b)] => Some([r, g, b]),
+        _ => None,
+    };
+    let alpha = find(&elements[vertex], "alpha");
+    let indices = find(&elements[face], "vertex_indices")
+        .or_else(|| find(&elements[face], "vertex_index"))
+        .filter(|&i| matches!(elements[face].properties[i], Property::List(..)))
+        .ok_or_else(|| err(None, "faces have no list of vertex indices".into()))?;
+
+    let full = |i: usize| match elements[vertex].properties[i] {
+        Property::Scalar(t, _) => t.full(),
+        Property::List(..) => 1.0,
+    };
+
+    let mut mesh = IndexedMesh::default();
+    for (k, e) in elements.iter().enumerate() {
+        for j in 0..e.count {
+            let values = body.element(e)
+                .map_err(|(n, m)| err(n, format!("{} {j}: {m}", e.name)))?;
+            let value = |i: usize| values[i].first().copied()
+                .ok_or_else(|| err(None, format!("{} {j}: `{}` is an empty list", e.name, e.properties[i].name())));
+
+            if k == vertex {
+                mesh.vertices.push(Vec3::new(value(x)? as f32, value(y)? as f32, value(z)? as f32));
+                if let Some([x, y, z]) = normal {
+                    mesh.normals.push(Vec3::new(value(x)? as f32, value(y)? as f32, value(z)? as f32));
+                }
+                if let Some([r, g, b]) = color {
+                    let a = match alpha {
+                        Some(a) => value(a)? / full(a),
+                        None => 1.0,
+                    };
+                    mesh.colors.push(Vector4::new(
+                        (value(r)? / full(r)) as f32,
+                        (value(g)? / full(g)) as f32,
+                        (value(b)? / full(b)) as f32,
+                        a as f32,
+                    ));
+                }
+            } else if k == face {
+                let polygon = &values[indices];
+                if polygon.len() < 3 {
+                    return Err(err(None, format!("face {j} has {} vertices", polygon.len())));
+                }
+
+                let vcount = elements[vertex].count;
+                let polygon = polygon.iter()
+                    .map(|&v| (v >= 0.0 && (v as usize) < vcount).then_some(v as usize)
+                        .ok_or_else(|| err(None, format!("face {j}: vertex {v} is not one of the {vcount} vertices"))))
+                    .collect::<Result<Vec<_>>>()?;
+
+                // Split the polygon into a fan of triangles around its first
+                // vertex
+                for w in polygon[1..].windows(2) {
+                    mesh.faces.push([polygon[0], w[0], w[1]]);
+                }
+            }
+        }
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as a binary little endian ply file, with normals and colours
+/// when the mesh has them
+pub fn write_ply<W: Write>(mesh: &IndexedMesh, mut w: W) -> std::io::Result<()> {
+    let normals = mesh.normals.len() == mesh.vertices.len() && !mesh.vertices.is_empty();
+    let colors = mesh.colors.len() == mesh.vertices.len() && !mesh.vertices.is_empty();
+
+    writeln!(w, "ply")?;
+    writeln!(w, "format binary_little_endian 1.0")?;
+    writeln!(w, "element vertex {}", mesh.vertices.len())?;
+    for p in ["x", "y", "z"] {
+        writeln!(w, "property float {p}")?;
+    }
+    if normals {
+        for p in ["nx", "ny", "nz"] {
+            writeln!(w, "property float {p}")?;
+        }
+    }
+    if colors {
+        for p in ["red", "green", "blue", "alpha"] {
+            writeln!(w, "property uchar {p}")?;
+        }
+    }
+    writeln!(w, "element face {}", mesh.faces.len())?;
+    writeln!(w, "property list uchar int vertex_indices")?;
+    writeln!(w, "end_header")?;
+
+    for (i, v) in mesh.vertices.iter().enumerate() {
+        for x in [v.x, v.y, v.z] {
+            w.write_all(&x.to_le_bytes())?;
+        }
+        if normals {
+            let n = mesh.normals[i];
+            for x in [n.x, n.y, n.z] {
+                w.write_all(&x.to_le_bytes())?;
+            }
+        }
+        if colors {
+            let c = mesh.colors[i];
+            w.write_all(&[c.x, c.y, c.z, c.w].map(|x| (x.clamp(0.0, 1.0) * 255.0).round() as u8))?;
+        }
+    }
+
+    for f in &mesh.faces {
+        w.write_all(&[3])?;
+        for i in f {
+            w.write_all(&(*i as i32).to_le_bytes())?;
+        }
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::testing::message;
+
+    /// A unit square in the z = 0 plane, one quad face around it
+    const SQUARE: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+
+    fn header(format: &str) -> String {
+        format!(
+            "ply\nformat {format} 1.0\ncomment a square\nelement vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+             element face 1\nproperty list uchar int vertex_indices\nend_header\n"
+        )
+    }
+
+    fn ascii(indices: &str) -> Vec<u8> {
+        let vertices = SQUARE.iter().map(|v| format!("{} {} {}\n", v[0], v[1], v[2])).collect::<String>();
+        format!("{}{vertices}{indices}\n", header("ascii")).into_bytes()
+    }
+
+    fn binary(big: bool) -> Vec<u8> {
+        let mut data = header(if big { "binary_big_endian" } else { "binary_little_endian" }).into_bytes();
+        for x in SQUARE.iter().flatten() {
+            data.extend(if big { x.to_be_bytes() } else { x.to_le_bytes() });
+        }
+        data.push(4);
+        for i in 0..4i32 {
+            data.extend(if big { i.to_be_bytes() } else { i.to_le_bytes() });
+        }
+        data
+    }
+
+    fn assert_square(mesh: &IndexedMesh) {
+        let vertices = SQUARE.map(Vec3::from);
+        assert_eq!(mesh.vertices, vertices);
+        // The quad is split into a fan around its first vertex
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!(mesh.normals.is_empty() && mesh.colors.is_empty());
+    }
+
+    #[test]
+    fn reads_ascii() {
+        assert_square(&parse_ply("square.ply", &ascii("4 0 1 2 3")).unwrap());
+    }
+
+    #[test]
+    fn reads_little_endian() {
+        assert_square(&parse_ply("square.ply", &binary(false)).unwrap());
+    }
+
+    #[test]
+    fn reads_big_endian() {
+        assert_square(&parse_ply("square.ply", &binary(true)).unwrap());
+    }
+
+    #[test]
+    fn reads_normals_and_colours() {
+        let data = "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+                    property float nx\nproperty float ny\nproperty float nz\nproperty uchar red\nproperty uchar green\nproperty uchar blue\n\
+                    element face 1\nproperty list uchar uint vertex_index\nend_header\n\
+                    0 0 0 0 0 1 255 0 0\n1 0 0 0 0 1 0 255 0\n0 1 0 0 0 1 0 0 255\n3 0 1 2\n";
+        let mesh = parse_ply("triangle.ply", data.as_bytes()).unwrap();
+        assert_eq!(mesh.normals, vec![Vec3::new(0.0, 0.0, 1.0); 3]);
+        assert_eq!(mesh.colors[1], Vector4::new(0.0, 1.0, 0.0, 1.0));
+        assert_eq!(mesh.faces, vec![[0, 1, 2]]);
+    }
+
+    #[test]
+    fn truncated_binary_body() {
+        let data = binary(false);
+        let (line, message) = message(parse_ply("square.ply", &data[..data.len() - 2]));
+        assert_eq!(line, None);
+        assert!(message.contains("ends in the middle of the data"), "{message}");
+    }
+
+    #[test]
+    fn truncated_ascii_body() {
+        let (_, message) = message(parse_ply("square.ply", &ascii("4 0 1")));
+        assert!(message.contains("ends in the middle of the data"), "{message}");
+    }
+
+    #[test]
+    fn out_of_range_index() {
+        let (_, message) = message(parse_ply("square.ply", &ascii("4 0 1 2 7")));
+        assert!(message.contains("vertex 7 is not one of the 4 vertices"), "{message}");
+    }
+
+    #[test]
+    fn bad_ascii_number_has_its_line() {
+        let data = String::from_utf8(ascii("4 0 1 2 3")).unwrap().replace("1 1 0", "1 one 0");
+        let (line, message) = message(parse_ply("square.ply", data.as_bytes()));
+        assert_eq!(line, Some(13));
+        assert!(message.contains("`one` is not a number"), "{message}");
+    }
+
+    #[test]
+    fn unknown_format() {
+        let data = header("binary_middle_endian");
+        let (line, message) = message(parse_ply("square.ply", data.as_bytes()));
+        assert_eq!(line, Some(2));
+        assert!(message.contains("unknown format"), "{message}");
+    }
+
+    #[test]
+    fn reads_big_endian_doubles_and_short_indices() {
+        let mut data = "ply\nformat binary_big_endian 1.0\nelement vertex 3\nproperty double x\nproperty double y\nproperty double z\n\
+                        element face 1\nproperty list uchar ushort vertex_indices\nend_header\n".as_bytes().to_vec();
+        for x in [0.0f64, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            data.extend(x.to_be_bytes());
+        }
+        data.push(3);
+        for i in [0u16, 1, 2] {
+            data.extend(i.to_be_bytes());
+        }
+
+        let mesh = parse_ply("triangle.ply", &data).unwrap();
+        assert_eq!(mesh.vertices, vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+        assert_eq!(mesh.faces, vec![[0, 1, 2]]);
+    }
 }
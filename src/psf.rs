@@ -0,0 +1,337 @@
+use crate::spot::Spot;
+use crate::wavefront::Wavefront;
+
+use std::f64::consts::TAU;
+use std::io::{self, Write};
+
+/// Intensity sampled on a square grid in an image plane
+pub struct Psf {
+    /// Number of samples across
+    pub size: usize,
+    /// Distance between samples
+    pub pixel: f32,
+    /// Row major intensities, summing to one
+    pub data: Vec<f32>,
+}
+
+impl Psf {
+    /// Plane coordinates of the centre of sample (i, j)
+    pub fn position(&self, i: usize, j: usize) -> [f32; 2] {
+        let c = self.size as f32 / 2.0;
+        [(i as f32 + 0.5 - c) * self.pixel, (j as f32 + 0.5 - c) * self.pixel]
+    }
+
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "x,y,intensity")?;
+        for j in 0..self.size {
+            for i in 0..self.size {
+                let p = self.position(i, j);
+                writeln!(w, "{},{},{}", p[0], p[1], self.data[j * self.size + i])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Modulation transfer against spatial frequency in tangential (y) and
+/// sagittal (z) directions, for fields in the xy plane
+pub struct Mtf {
+    pub frequencies: Vec<f32>,
+    pub tangential: Vec<f32>,
+    pub sagittal: Vec<f32>,
+}
+
+impl Mtf {
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "frequency,tangential,sagittal")?;
+        for i in 0..self.frequencies.len() {
+            writeln!(w, "{},{},{}", self.frequencies[i], self.tangential[i], self.sagittal[i])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Histogram the spot around its centroid into `size` by `size` pixels
+pub fn geometric_psf(spot: &Spot, size: usize, pixel: f32) -> Psf {
+    let c = spot.centroid();
+    let mut data = vec![0.0; size * size];
+
+    let half = size as f32 / 2.0;
+    for p in spot.points.iter() {
+        let i = ((p[0] - c[0]) / pixel + half).floor();
+        let j = ((p[1] - c[1]) / pixel + half).floor();
+        if i >= 0.0 && j >= 0.0 && (i as usize) < size && (j as usize) < size {
+            data[j as usize * size + i as usize] += 1.0;
+        }
+    }
+
+    let n = spot.points.len().max(1) as f32;
+    data.iter_mut().for_each(|v| *v /= n);
+
+    Psf { size, pixel, data }
+}
+
+/// MTF of the spot from the fourier transform of its points projected onto
+/// each direction, up to `max_frequency`
+pub fn geometric_mtf(spot: &Spot, max_frequency: f32, count: usize) -> Mtf {
+    let modulation = |f: f32, axis: usize| {
+        let (re, im) = spot.points.iter()
+            .map(|p| TAU * (f * p[axis]) as f64)
+            .fold((0.0, 0.0), |(re, im), a| (re + a.cos(), im - a.sin()));
+
+        ((re * re + im * im).sqrt() / spot.points.len().max(1) as f64) as f32
+    };
+
+    let frequencies = (0..count)
+        .map(|i| max_frequency * i as f32 / (count - 1).max(1) as f32)
+        .collect::<Vec<_>>();
+
+    Mtf {
+        tangential: frequencies.iter().map(|&f| modulation(f, 1)).collect(),
+        sagittal: frequencies.iter().map(|&f| modulation(f, 0)).collect(),
+        frequencies,
+    }
+}
+
+/// Diffraction PSF from the fourier transform of the pupil function, sampled
+/// on a `size` wide grid, with the pupil padded to fill 1/`pad` of the grid.
+/// Distances are in scene units, as is the wavelength. Also returns the
+/// Strehl ratio.
+pub fn diffraction_psf(wf: &Wavefront, lens_radius: f32, wavelength: f32, size: usize, pad: usize) -> (Psf, f32) {
+    let (step, grid) = pupil_grid(wf, size / pad);
+    let offset = (size - size / pad) / 2;
+
+    let mut pupil = vec![(0.0, 0.0); size * size];
+    let mut ideal = vec![(0.0, 0.0); size * size];
+    // Rows follow y and columns z, to match the plane coordinates of spots
+    for (&(y, z), s) in grid.iter().zip(wf.samples.iter()) {
+        let k = (y + offset) * size + z + offset;
+        let phase = TAU * (s.opd / wavelength) as f64;
+        pupil[k] = (phase.cos(), phase.sin());
+        ideal[k] = (1.0, 0.0);
+    }
+
+    fft2(&mut pupil, size);
+    fft2(&mut ideal, size);
+
+    let intensity = |f: &[(f64, f64)]| f.iter().map(|(re, im)| re * re + im * im).collect::<Vec<_>>();
+    let psf = shift(&intensity(&pupil), size);
+    let perfect = intensity(&ideal);
+
+    let peak = psf.iter().cloned().fold(0.0, f64::max);
+    let ideal_peak = perfect.iter().cloned().fold(0.0, f64::max);
+    let strehl = if ideal_peak > 0.0 { (peak / ideal_peak) as f32 } else { 0.0 };
+
+    let total = psf.iter().sum::<f64>().max(f64::MIN_POSITIVE);
+
+    // Samples in the image are lambda * R / D apart for a pupil D across
+    let width = step * lens_radius * size as f32;
+    let pixel = wavelength * wf.radius / width;
+
+    let data = psf.iter().map(|v| (v / total) as f32).collect();
+    (Psf { size, pixel, data }, strehl)
+}
+
+/// MTF slices through the transform of a PSF, up to the nyquist frequency
+pub fn psf_mtf(psf: &Psf) -> Mtf {
+    let size = psf.size;
+    let mut otf = psf.data.iter().map(|&v| (v as f64, 0.0)).collect::<Vec<_>>();
+    fft2(&mut otf, size);
+
+    let dc = otf[0].0.hypot(otf[0].1).max(f64::MIN_POSITIVE);
+    let m = |k: usize| (otf[k].0.hypot(otf[k].1) / dc) as f32;
+
+    let count = size / 2;
+    let df = 1.0 / (size as f32 * psf.pixel);
+    Mtf {
+        frequencies: (0..count).map(|i| i as f32 * df).collect(),
+        tangential: (0..count).map(|i| m(i * size)).collect(),
+        sagittal: (0..count).map(m).collect(),
+    }
+}
+
+/// Grid cell of every wavefront sample, on a grid `count` cells across,
+/// along with the spacing of the cells in pupil coordinates
+fn pupil_grid(wf: &Wavefront, count: usize) -> (f32, Vec<(usize, usize)>) {
+    let lo = wf.samples.iter()
+        .fold([f32::INFINITY; 2], |lo, s| [lo[0].min(s.pupil[0]), lo[1].min(s.pupil[1])]);
+    let hi = wf.samples.iter()
+        .fold([f32::NEG_INFINITY; 2], |hi, s| [hi[0].max(s.pupil[0]), hi[1].max(s.pupil[1])]);
+
+    let step = ((hi[0] - lo[0]).max(hi[1] - lo[1]) / (count - 1).max(1) as f32).max(f32::EPSILON);
+    let cell = |v: f32, lo: f32| (((v - lo) / step).round() as usize).min(count - 1);
+
+    let grid = wf.samples.iter()
+        .map(|s| (cell(s.pupil[0], lo[0]), cell(s.pupil[1], lo[1])))
+        .collect();
+
+    (step, grid)
+}
+
+/// Move the zero frequency to the centre of the grid
+fn shift(data: &[f64], size: usize) -> Vec<f64> {
+    let h = size / 2;
+    let mut out = vec![0.0; data.len()];
+    for j in 0..size {
+        for i in 0..size {
+            out[((j + h) % size) * size + (i + h) % size] = data[j * size + i];
+        }
+    }
+
+    out
+}
+
+/// In place two dimensional FFT of a row major square grid. `size` must be a
+/// power of two.
+fn fft2(data: &mut [(f64, f64)], size: usize) {
+    for row in data.chunks_mut(size) {
+        fft(row);
+    }
+
+    let mut col = vec![(0.0, 0.0); size];
+    for i in 0..size {
+        for j in 0..size {
+            col[j] = data[j * size + i];
+        }
+        fft(&mut col);
+        for j in 0..size {
+            data[j * size + i] = col[j];
+        }
+    }
+}
+
+/// In place iterative radix 2 forward FFT
+fn fft(data: &mut [(f64, f64)]) {
+    let n = data.len();
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let ang = -TAU / len as f64;
+        let (wr, wi) = (ang.cos(), ang.sin());
+
+        for start in (0..n).step_by(len) {
+            let (mut cr, mut ci) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let (ar, ai) = data[start + k];
+                let (br, bi) = data[start + k + len / 2];
+                let (tr, ti) = (br * cr - bi * ci, br * ci + bi * cr);
+
+                data[start + k] = (ar + tr, ai + ti);
+                data[start + k + len / 2] = (ar - tr, ai - ti);
+
+                (cr, ci) = (cr * wr - ci * wi, cr * wi + ci * wr);
+            }
+        }
+
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::Field;
+    use crate::wavefront::WavefrontSample;
+
+    use cgmath::Vector3;
+
+    fn spot(points: Vec<[f32; 2]>) -> Spot {
+        Spot { field: Field::Point(Vector3::new(0.0, 0.0, 0.0)), points }
+    }
+
+    #[test]
+    fn fft_matches_the_discrete_fourier_transform() {
+        let input = (0..16).map(|i| ((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos())).collect::<Vec<_>>();
+        let mut fast = input.clone();
+        fft(&mut fast);
+
+        for (k, &(re, im)) in fast.iter().enumerate() {
+            let (er, ei) = input.iter().enumerate()
+                .map(|(n, &(xr, xi))| {
+                    let a = -TAU * (k * n) as f64 / 16.0;
+                    (xr * a.cos() - xi * a.sin(), xr * a.sin() + xi * a.cos())
+                })
+                .fold((0.0, 0.0), |(sr, si), (r, i)| (sr + r, si + i));
+            assert!((re - er).abs() < 1e-9 && (im - ei).abs() < 1e-9, "{k}");
+        }
+    }
+
+    #[test]
+    fn a_point_transfers_every_frequency() {
+        let mut data = vec![0.0; 16 * 16];
+        data[16 * 5 + 9] = 1.0;
+        let mtf = psf_mtf(&Psf { size: 16, pixel: 0.1, data });
+
+        assert_eq!(mtf.frequencies.len(), 8);
+        assert!((mtf.frequencies[1] - 1.0 / 1.6).abs() < 1e-6);
+        assert!(mtf.tangential.iter().chain(&mtf.sagittal).all(|&m| (m - 1.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn two_points_transfer_a_cosine() {
+        let d = 0.25;
+        let mtf = geometric_mtf(&spot(vec![[0.0, 0.0], [0.0, d]]), 4.0, 9);
+
+        for (f, (t, s)) in mtf.frequencies.iter().zip(mtf.tangential.iter().zip(&mtf.sagittal)) {
+            assert!((t - (std::f32::consts::PI * f * d).cos().abs()).abs() < 1e-5, "{f}: {t}");
+            assert!((s - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn histograms_around_the_centroid() {
+        // Centred on the origin, the outer two points fall off the grid
+        let points = vec![[1.0, 2.0], [1.0, 2.0], [1.1, 2.0], [0.9, 2.0], [11.0, 2.0], [-9.0, 2.0]];
+        let psf = geometric_psf(&spot(points), 4, 0.2);
+
+        assert!((psf.data[2 * 4 + 2] - 3.0 / 6.0).abs() < 1e-6);
+        assert!((psf.data[2 * 4 + 1] - 1.0 / 6.0).abs() < 1e-6);
+        assert!((psf.data.iter().sum::<f32>() - 4.0 / 6.0).abs() < 1e-6);
+        assert_eq!(psf.position(0, 0), [-0.3, -0.3]);
+    }
+
+    #[test]
+    fn a_flat_wavefront_is_diffraction_limited() {
+        let n = 16;
+        let samples = (0..n).flat_map(|i| (0..n).map(move |j| (i, j)))
+            .map(|(i, j)| [2.0 * i as f32 / (n - 1) as f32 - 1.0, 2.0 * j as f32 / (n - 1) as f32 - 1.0])
+            .filter(|p| p[0].hypot(p[1]) <= 1.0)
+            .map(|pupil| WavefrontSample { pupil, opl: 1.0, opd: 0.0 })
+            .collect();
+        let wf = Wavefront { center: Vector3::new(0.0, 0.0, 0.0), radius: 10.0, samples };
+
+        let (psf, strehl) = diffraction_psf(&wf, 1.0, 0.0005, 64, 4);
+        assert!((strehl - 1.0).abs() < 1e-5);
+        assert!((psf.data.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+
+        // Brightest in the middle, and symmetric about it
+        let peak = psf.data.iter().cloned().fold(0.0, f32::max);
+        assert_eq!(psf.data[32 * 64 + 32], peak);
+        assert!((psf.data[32 * 64 + 30] - psf.data[32 * 64 + 34]).abs() < 1e-6);
+
+        // Half a wave of defocus loses most of the peak
+        let mut defocused = wf;
+        for s in defocused.samples.iter_mut() {
+            s.opd = 0.00025 * (2.0 * (s.pupil[0].powi(2) + s.pupil[1].powi(2)) - 1.0);
+        }
+        let (_, strehl) = diffraction_psf(&defocused, 1.0, 0.0005, 64, 4);
+        assert!(strehl < 0.5, "{strehl}");
+    }
+}
@@ -0,0 +1,288 @@
+use crate::geometry::IndexedMesh;
+
+use std::collections::{HashMap, VecDeque};
+
+use cgmath::{dot, InnerSpace};
+
+/// Vertices closer than this, relative to the size of the mesh, are welded
+const WELD: f32 = 1e-6;
+
+/// What repairing a mesh found and changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Repair {
+    /// Vertices merged into another at the same place
+    pub welded: usize,
+    /// Faces dropped because welding left them without area
+    pub degenerate: usize,
+    /// Faces turned over to agree with their neighbours and face outward
+    pub flipped: usize,
+    /// Edges of only one face, where the mesh has holes
+    pub open_edges: usize,
+    /// Edges shared by more than two faces
+    pub non_manifold_edges: usize,
+    /// Separate pieces of the mesh
+    pub components: usize,
+}
+
+impl Repair {
+    /// Whether the mesh is closed, so that it has a well defined inside
+    pub fn watertight(&self) -> bool {
+        self.open_edges == 0 && self.non_manifold_edges == 0
+    }
+
+    /// The problems left in the mesh, for warnings
+    pub fn describe(&self) -> String {
+        let mut problems = vec![];
+        if self.open_edges > 0 {
+            problems.push(format!("{} open edges", self.open_edges));
+        }
+        if self.non_manifold_edges > 0 {
+            problems.push(format!("{} edges shared by more than two faces", self.non_manifold_edges));
+        }
+
+        if problems.is_empty() { "watertight".into() } else { problems.join(", ") }
+    }
+}
+
+/// Weld duplicate vertices, drop the faces that leaves degenerate and turn
+/// the faces of every piece of the mesh to agree with each other and face
+/// out of it. Holes and non-manifold edges are counted, not fixed.
+pub fn repair(mesh: &mut IndexedMesh) -> Repair {
+    let mut report = Repair {
+        welded: weld(mesh),
+        ..Default::default()
+    };
+
+    let before = mesh.faces.len();
+    mesh.faces.retain(|&[a, b, c]| a != b && b != c && c != a);
+    report.degenerate = before - mesh.faces.len();
+
+    let edges = edges(mesh);
+    report.open_edges = edges.values().filter(|f| f.len() == 1).count();
+    report.non_manifold_edges = edges.values().filter(|f| f.len() > 2).count();
+
+    let (flips, components) = orient(mesh, &edges);
+    report.components = components;
+    for (face, flip) in mesh.faces.iter_mut().zip(flips) {
+        if flip {
+            face.swap(1, 2);
+            report.flipped += 1;
+        }
+    }
+
+    report
+}
+
+/// Merge vertices within `WELD` of each other, keeping the normal and
+/// colour of the first, and drop the rest. Returns how many were merged.
+fn weld(mesh: &mut IndexedMesh) -> usize {
+    let Some(first) = mesh.vertices.first().copied() else { return 0 };
+    let (min, max) = mesh.vertices.iter().fold((first, first), |(lo, hi), v| {
+        (lo.zip(*v, f32::min), hi.zip(*v, f32::max))
+    });
+    let tolerance = WELD * (max - min).magnitude().max(f32::MIN_POSITIVE);
+
+    // Kept vertices by the cell of a grid `tolerance` wide they are in, so
+    // only the neighbouring cells need searching
+    let cell = |v: cgmath::Vector3<f32>| [v.x, v.y, v.z].map(|x| (x / tolerance).floor() as i64);
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let mut kept: Vec<usize> = vec![];
+    let mut remap = Vec::with_capacity(mesh.vertices.len());
+
+    for (i, &v) in mesh.vertices.iter().enumerate() {
+        let [x, y, z] = cell(v);
+        let near = (-1..=1).flat_map(|dx| (-1..=1).flat_map(move |dy| (-1..=1).map(move |dz| [x + dx, y + dy, z + dz])))
+            .filter_map(|c| grid.get(&c))
+            .flatten()
+            .find(|&&k| (mesh.vertices[kept[k]] - v).magnitude() <= tolerance)
+            .copied();
+
+        remap.push(near.unwrap_or_else(|| {
+            kept.push(i);
+            grid.entry([x, y, z]).or_default().push(kept.len() - 1);
+            kept.len() - 1
+        }));
+    }
+
+    let welded = mesh.vertices.len() - kept.len();
+    if welded > 0 {
+        fn pick<T: Copy>(values: &[T], kept: &[usize]) -> Vec<T> {
+            kept.iter().map(|&i| values[i]).collect()
+        }
+
+        if mesh.normals.len() == mesh.vertices.len() {
+            mesh.normals = pick(&mesh.normals, &kept);
+        }
+        if mesh.colors.len() == mesh.vertices.len() {
+            mesh.colors = pick(&mesh.colors, &kept);
+        }
+        mesh.vertices = pick(&mesh.vertices, &kept);
+        for f in mesh.faces.iter_mut() {
+            *f = f.map(|i| remap[i]);
+        }
+    }
+
+    welded
+}
+
+/// The faces of every edge, keyed by its vertices in increasing order, with
+/// whether the face runs along the edge in that order
+fn edges(mesh: &IndexedMesh) -> HashMap<(usize, usize), Vec<(usize, bool)>> {
+    let mut edges: HashMap<_, Vec<_>> = HashMap::new();
+    for (f, &[a, b, c]) in mesh.faces.iter().enumerate() {
+        for (u, v) in [(a, b), (b, c), (c, a)] {
+            edges.entry((u.min(v), u.max(v))).or_default().push((f, u < v));
+        }
+    }
+
+    edges
+}
+
+/// Which faces to turn over so that faces sharing an edge run along it in
+/// opposite directions and each piece encloses a positive volume, and the
+/// number of pieces. Only edges of exactly two faces join faces together.
+fn orient(mesh: &IndexedMesh, edges: &HashMap<(usize, usize), Vec<(usize, bool)>>) -> (Vec<bool>, usize) {
+    let mut flip = vec![false; mesh.faces.len()];
+    let mut seen = vec![false; mesh.faces.len()];
+    let mut components = 0;
+
+    for start in 0..mesh.faces.len() {
+        if seen[start] {
+            continue;
+        }
+        components += 1;
+
+        let mut piece = vec![];
+        let mut queue = VecDeque::from([start]);
+        seen[start] = true;
+        while let Some(f) = queue.pop_front() {
+            piece.push(f);
+            let [a, b, c] = mesh.faces[f];
+            for (u, v) in [(a, b), (b, c), (c, a)] {
+                let shared = &edges[&(u.min(v), u.max(v))];
+                let [(f0, d0), (f1, d1)] = shared[..] else { continue };
+                let ((g, dg), df) = if f0 == f { ((f1, d1), d0) } else { ((f0, d0), d1) };
+                if !seen[g] {
+                    // Run along the shared edge against this face
+                    flip[g] = dg == (df != flip[f]);
+                    seen[g] = true;
+                    queue.push_back(g);
+                }
+            }
+        }
+
+        // Outward faces enclose a positive volume
+        let volume: f32 = piece.iter()
+            .map(|&f| {
+                let [a, b, c] = mesh.faces[f].map(|i| mesh.vertices[i]);
+                let v = dot(a, b.cross(c));
+                if flip[f] { -v } else { v }
+            })
+            .sum();
+        if volume < 0.0 {
+            for &f in &piece {
+                flip[f] = !flip[f];
+            }
+        }
+    }
+
+    (flip, components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::testing::cube;
+    use crate::stl;
+    use std::collections::HashSet;
+
+    fn volume(mesh: &IndexedMesh) -> f32 {
+        mesh.faces.iter()
+            .map(|f| {
+                let [a, b, c] = f.map(|i| mesh.vertices[i]);
+                dot(a, b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+
+    /// Whether every edge is run along once each way
+    fn consistent(mesh: &IndexedMesh) -> bool {
+        let mut directed = HashSet::new();
+        mesh.faces.iter()
+            .flat_map(|&[a, b, c]| [(a, b), (b, c), (c, a)])
+            .all(|e| directed.insert(e))
+            && directed.iter().all(|&(u, v)| directed.contains(&(v, u)))
+    }
+
+    #[test]
+    fn closed_mesh_is_left_alone() {
+        let mut mesh = cube();
+        let report = repair(&mut mesh);
+        assert_eq!(report, Repair { components: 1, ..Default::default() });
+        assert!(report.watertight());
+        assert_eq!(mesh.faces, cube().faces);
+    }
+
+    #[test]
+    fn welds_duplicate_vertices() {
+        // Stl files give every face its own three vertices
+        let mut data = vec![];
+        stl::write_stl(&cube(), &mut data).unwrap();
+        let mut mesh = stl::parse_stl("cube.stl", &data).unwrap();
+        assert_eq!(mesh.vertices.len(), 36);
+
+        let report = repair(&mut mesh);
+        assert_eq!(report.welded, 36 - 8);
+        assert_eq!(mesh.vertices.len(), 8);
+        assert!(report.watertight());
+        assert_eq!(report.components, 1);
+    }
+
+    #[test]
+    fn turns_a_flipped_face() {
+        let mut mesh = cube();
+        mesh.faces[5].swap(1, 2);
+
+        let report = repair(&mut mesh);
+        assert_eq!(report.flipped, 1);
+        assert!(consistent(&mesh));
+        assert!((volume(&mesh) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn turns_an_inside_out_mesh() {
+        let mut mesh = cube();
+        for f in mesh.faces.iter_mut() {
+            f.swap(1, 2);
+        }
+
+        let report = repair(&mut mesh);
+        assert_eq!(report.flipped, 12);
+        assert!(consistent(&mesh));
+        assert!((volume(&mesh) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn counts_the_edges_of_a_hole() {
+        let mut mesh = cube();
+        mesh.faces.remove(0);
+
+        let report = repair(&mut mesh);
+        assert_eq!(report.open_edges, 3);
+        assert_eq!(report.non_manifold_edges, 0);
+        assert!(!report.watertight());
+        assert_eq!(report.describe(), "3 open edges");
+    }
+
+    #[test]
+    fn drops_faces_welded_flat() {
+        let mut mesh = cube();
+        let n = mesh.vertices.len();
+        mesh.vertices.push(mesh.vertices[0]);
+        mesh.faces.push([0, n, 1]);
+
+        let report = repair(&mut mesh);
+        assert_eq!((report.welded, report.degenerate), (1, 1));
+        assert!(report.watertight());
+    }
+}
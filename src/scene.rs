@@ -0,0 +1,338 @@
+use crate::lenses::Lens;
+use crate::light::Light;
+use crate::geometry::Plane;
+use crate::optimize::Optimize;
+use crate::tolerance::Tolerancing;
+use crate::expand::{self, Instance, Generator, Origins};
+use crate::validate::{self, Diagnostic, Severity};
+use crate::units::{self, Unit};
+use crate::enclosure::Enclosure;
+use crate::mesh::Mesh;
+use crate::error::{Error, Result};
+
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Version of the scene format written by this build. Scenes of older
+/// versions are migrated to it when they are loaded.
+pub const VERSION: u32 = 5;
+
+/// Upgrade of a scene read at one version to the next
+type Migration = fn(&mut Scene);
+
+/// `MIGRATIONS[i]` takes version `i + 1` to version `i + 2`. So far every
+/// version only adds settings whose defaults keep older scenes as they were,
+/// so the steps have nothing to change.
+const MIGRATIONS: [Migration; VERSION as usize - 1] = [
+    // 2 adds includes, lens definitions and instances, and generators
+    |_| {},
+    // 3 adds scene units and unit suffixes on lengths
+    |_| {},
+    // 4 adds the enclosure and the far distance of escaped rays
+    |_| {},
+    // 5 adds meshes
+    |_| {},
+];
+
+/// A scene file, the lenses and lights to trace along with the settings of
+/// the analyses run on them
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Scene {
+    /// Version of the format the scene is written in, 1 when not given
+    #[serde(default = "first_version")]
+    pub version: u32,
+    /// Unit of every length in the scene and in the analyses of it. Lengths
+    /// may also be written with a unit suffix, like "25mm", and are converted
+    /// to this unit when the scene is loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<Unit>,
+    /// Other scene files, relative to this one, whose lenses, lights and
+    /// lens definitions are added to this scene
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Named lenses for instances and generators to copy
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub definitions: BTreeMap<String, Lens>,
+    #[serde(default)]
+    pub lenses: Vec<Lens>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<Instance>,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub generate: Vec<Generator>,
+    /// Meshes read from ply, obj or stl files, glass ones refract like
+    /// lenses
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub meshes: Vec<Mesh>,
+    /// Reference plane that rays are measured against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detector: Option<Plane>,
+    /// What surrounds the scene, a 5 cm solid box when not given
+    #[serde(default, skip_serializing_if = "Enclosure::is_default")]
+    pub enclosure: Enclosure,
+    /// How far rays that leave the scene are drawn, 10 cm when not given
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "units::optional_length")]
+    #[schemars(with = "Option<units::Length>")]
+    pub far: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub optimize: Option<Optimize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<Tolerancing>,
+
+    /// Where the lenses and lights of an expanded scene were written
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub origins: Origins,
+    /// Directory the scene was loaded from, the files it names are relative
+    /// to it
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub dir: PathBuf,
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            unit: None,
+            include: vec![],
+            definitions: BTreeMap::new(),
+            lenses: vec![],
+            instances: vec![],
+            lights: vec![],
+            generate: vec![],
+            meshes: vec![],
+            detector: None,
+            enclosure: Enclosure::default(),
+            far: None,
+            optimize: None,
+            tolerance: None,
+            origins: Origins::default(),
+            dir: PathBuf::new(),
+        }
+    }
+}
+
+impl Scene {
+    /// Read, migrate, expand and validate a scene file in the format given
+    /// by its extension. Errors found by validation fail the load, warnings
+    /// are returned with the scene.
+    pub fn load(path: &str) -> Result<(Self, Vec<Diagnostic>)> {
+        let source = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let format = Format::from_path(path);
+        let mut scene = Self::parse(path, &source, format)?;
+        scene.dir = Path::new(path).parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let mut diagnostics = expand::expand(&mut scene, path)?;
+        if diagnostics.is_empty() {
+            diagnostics = scene.validate();
+        }
+        if format == Format::Yaml {
+            validate::locate_all(&mut diagnostics, &source);
+        }
+
+        let (errors, warnings): (Vec<_>, Vec<_>) = diagnostics.into_iter()
+            .partition(|d| d.severity == Severity::Error);
+        if !errors.is_empty() {
+            return Err(Error::Invalid { path: path.to_string(), diagnostics: errors });
+        }
+
+        Ok((scene, warnings))
+    }
+
+    /// Parse a scene of any supported version, migrating it to the current one
+    pub fn parse(path: &str, source: &str, format: Format) -> Result<Self> {
+        let header = format.parse::<Header>(path, source)?;
+        Self::read(path, source, format, header.version, header.unit, None)
+    }
+
+    /// Parse a scene included by one in `unit`, with its lengths converted
+    /// to it. A scene without a unit counts as centimetres, as it does for
+    /// `units::scale`, when the other one has a unit.
+    pub fn parse_in(path: &str, source: &str, format: Format, unit: Option<Unit>) -> Result<Self> {
+        let header = format.parse::<Header>(path, source)?;
+        let (file, target) = match (header.unit, unit) {
+            (None, None) => (None, None),
+            (file, target) => (file.or(Some(Unit::Cm)), target.or(Some(Unit::Cm))),
+        };
+        Self::read(path, source, format, header.version, file, target)
+    }
+
+    /// Parse a scene with plain lengths in `file` converted to `target`
+    fn read(path: &str, source: &str, format: Format, version: u32, file: Option<Unit>, target: Option<Unit>) -> Result<Self> {
+        let mut scene = units::reading(file, target, || Self::read_version(path, source, format, version))?;
+        scene.unit = target.or(file);
+        Ok(scene)
+    }
+
+    /// Parse a scene written at `version` and migrate it to the current one
+    fn read_version(path: &str, source: &str, format: Format, version: u32) -> Result<Self> {
+        if version == 0 || version > VERSION {
+            return Err(Error::Scene(format!("{path}: scene version {version} is not supported, this build reads versions 1 to {VERSION}")));
+        }
+
+        let mut scene: Self = format.parse(path, source)?;
+        scene.migrate();
+        Ok(scene)
+    }
+
+    /// Bring a scene of an older version up to the current one, a version at
+    /// a time
+    fn migrate(&mut self) {
+        for step in &MIGRATIONS[self.version as usize - 1..] {
+            step(self);
+            self.version += 1;
+        }
+    }
+
+    /// Check the scene for values that can not be traced and meshes that
+    /// can not be read or leak, without lines. Problems with expanded
+    /// lenses, lights and meshes are placed on what they were expanded from.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = validate::check(&self.lenses, &self.lights, self.detector.as_ref());
+        validate::check_meshes(&self.meshes, &mut diagnostics);
+        validate::check_mesh_files(&self.meshes, &self.dir, &mut diagnostics);
+        validate::check_surroundings(&self.enclosure, self.far, &mut diagnostics);
+        if let Some(opt) = &self.optimize {
+            validate::check_optimize(opt, self.lenses.len(), &mut diagnostics);
+        }
+        if let Some(tol) = &self.tolerance {
+            validate::check_tolerance(tol, &self.lenses, &mut diagnostics);
+        }
+        for d in diagnostics.iter_mut() {
+            expand::relocate(d, &self.origins);
+        }
+
+        diagnostics
+    }
+
+    /// A scene without lenses or lights in the same unit, with the same
+    /// meshes and surroundings as this one, to trace other lenses and lights
+    /// in
+    pub fn setting(&self) -> Self {
+        Self {
+            unit: self.unit,
+            detector: self.detector,
+            meshes: self.meshes.clone(),
+            enclosure: self.enclosure.clone(),
+            far: self.far,
+            dir: self.dir.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Write the scene in the format given by the extension of `path`
+    pub fn save(&self, path: &str) -> Result<()> {
+        let contents = Format::from_path(path).write(path, self)?;
+        std::fs::write(path, contents).map_err(|e| Error::io(path, e))
+    }
+
+    /// JSON Schema of the current version of the scene format
+    pub fn json_schema() -> String {
+        let schema = schemars::schema_for!(Scene);
+        serde_json::to_string_pretty(&schema).expect("schemas always serialize")
+    }
+}
+
+/// What is read of a scene before the rest, to know how to read it
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default = "first_version")]
+    version: u32,
+    #[serde(default)]
+    unit: Option<Unit>,
+}
+
+/// The file formats scenes can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Json,
+    Toml,
+    Ron,
+}
+
+impl Format {
+    /// The format of a file from its extension, yaml when it is not one of
+    /// the others
+    pub fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("json") => Self::Json,
+            Some("toml") => Self::Toml,
+            Some("ron") => Self::Ron,
+            _ => Self::Yaml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, path: &str, source: &str) -> Result<T> {
+        match self {
+            Self::Yaml => serde_yaml::from_str(source).map_err(|e| Error::yaml(path, e)),
+            Self::Json => serde_json::from_str(source).map_err(|e| Error::json(path, e)),
+            // toml has no tuple variants, read it through json to get serde's
+            // usual representation of them as single entry tables
+            Self::Toml => {
+                let value: Value = toml::from_str(source).map_err(|e| Error::toml(path, e))?;
+                serde_json::from_value(value).map_err(|e| Error::json(path, e))
+            }
+            Self::Ron => ron::from_str(source).map_err(|e| Error::ron(path, e)),
+        }
+    }
+
+    pub fn write<T: Serialize>(self, path: &str, value: &T) -> Result<String> {
+        match self {
+            Self::Yaml => serde_yaml::to_string(value).map_err(|e| Error::yaml(path, e)),
+            Self::Json => serde_json::to_string_pretty(value).map_err(|e| Error::json(path, e)),
+            // Through json text rather than a json value, which would widen
+            // every f32 to the nearest f64
+            Self::Toml => serde_json::to_string(value)
+                .and_then(|json| serde_json::from_str::<toml::Value>(&json))
+                .map_err(|e| Error::json(path, e))
+                .and_then(|v| toml::to_string_pretty(&v).map_err(|e| Error::Scene(format!("{path}: {e}")))),
+            Self::Ron => ron::ser::to_string_pretty(value, Default::default()).map_err(|e| Error::Scene(format!("{path}: {e}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::LensSide;
+
+    #[test]
+    fn migrates_version_one() {
+        // The format before it had a version: lenses and lights only
+        let source = "lenses:\n  - radius: 0.5\n    left: !Convex 0.1\n    right: Flat\nlights:\n  - !Laser [[2, 0, 0], [-1, 0, 0]]\n";
+        let scene = Scene::parse("old.yaml", source, Format::Yaml).unwrap();
+
+        assert_eq!(scene.version, VERSION);
+        assert_eq!(scene.lenses.len(), 1);
+        assert_eq!(scene.lights.len(), 1);
+        assert!(matches!(scene.lenses[0].left, LensSide::Convex(h) if h == 0.1));
+        assert_eq!(scene.lenses[0].pos, [0.0, -0.1, 0.0]);
+        assert!(scene.unit.is_none());
+        assert!(scene.enclosure.is_default());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        for version in [0, VERSION + 1] {
+            let source = format!("version: {version}\n");
+            assert!(matches!(Scene::parse("new.yaml", &source, Format::Yaml), Err(Error::Scene(_))));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::world::{World, Material, Event};
+
+use serde::Serialize;
+
+use std::io::{self, Write};
+
+/// A single traced segment in a flat form for writing out
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentRecord {
+    /// Position of the segment among the segments of every path
+    pub id: usize,
+    pub path: usize,
+    pub light: usize,
+    /// Position of the segment within its path
+    pub segment: usize,
+    /// Id of the segment this one continues, none for the spawned ray
+    pub parent: Option<usize>,
+    pub start: [f32; 3],
+    pub dir: [f32; 3],
+    /// End of the segment, none when it escapes
+    pub end: Option<[f32; 3]>,
+    pub length: Option<f32>,
+    /// Entity the segment ends on
+    pub entity: Option<usize>,
+    pub material: Option<Material>,
+    pub triangle: Option<usize>,
+    pub normal: Option<[f32; 3]>,
+    pub event: Event,
+    /// Glass entity the segment travels through, none in air
+    pub medium: Option<usize>,
+    /// Refractive index of the medium the segment travels through
+    pub index: f32,
+}
+
+/// Flatten every traced path of the world into records
+pub fn records(world: &World) -> Vec<SegmentRecord> {
+    let mut out = vec![];
+    for (p, path) in world.paths.iter().enumerate() {
+        let first = out.len();
+        for (i, s) in path.segments.iter().enumerate() {
+            let escaped = s.event == Event::Escaped;
+            out.push(SegmentRecord {
+                id: first + i,
+                path: p,
+                light: path.light,
+                segment: i,
+                parent: s.parent.map(|j| first + j),
+                start: s.ray.origin.into(),
+                dir: s.ray.dir.into(),
+                end: if escaped { None } else { Some(s.end().into()) },
+                length: if escaped { None } else { Some(s.length) },
+                entity: s.entity,
+                material: s.entity.map(|e| world.materials[e]),
+                triangle: s.triangle,
+                normal: s.normal.map(|n| n.into()),
+                event: s.event,
+                medium: s.medium,
+                index: s.index,
+            });
+        }
+    }
+
+    out
+}
+
+pub fn write_json<W: Write>(records: &[SegmentRecord], w: W) -> io::Result<()> {
+    serde_json::to_writer_pretty(w, records)?;
+    Ok(())
+}
+
+pub fn write_csv<W: Write>(records: &[SegmentRecord], mut w: W) -> io::Result<()> {
+    writeln!(
+        w, "id,path,light,segment,parent,start_x,start_y,start_z,dir_x,dir_y,dir_z,\
+            end_x,end_y,end_z,length,entity,material,triangle,normal_x,normal_y,normal_z,event,medium,index"
+    )?;
+
+    let opt = |v: Option<String>| v.unwrap_or_default();
+    let xyz = |v: Option<[f32; 3]>| match v {
+        Some([x, y, z]) => format!("{x},{y},{z}"),
+        None => ",,".to_string(),
+    };
+
+    for r in records {
+        let material = r.material.map(|m| match m {
+            Material::Solid => "solid".to_string(),
+            Material::Mirror => "mirror".to_string(),
+            Material::Glass(eta) => format!("glass {eta}"),
+        });
+
+        writeln!(
+            w, "{},{},{},{},{},{},{},{},{},{},{},{},{},{:?},{},{}",
+            r.id, r.path, r.light, r.segment,
+            opt(r.parent.map(|p| p.to_string())),
+            xyz(Some(r.start)),
+            xyz(Some(r.dir)),
+            xyz(r.end),
+            opt(r.length.map(|l| l.to_string())),
+            opt(r.entity.map(|e| e.to_string())),
+            opt(material),
+            opt(r.triangle.map(|t| t.to_string())),
+            xyz(r.normal),
+            r.event,
+            opt(r.medium.map(|m| m.to_string())),
+            r.index
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use crate::geometry::Plane;
+use crate::light::Field;
+use crate::world::{World, RayPath};
+
+use cgmath::Vector3;
+
+use std::io::{self, Write};
+
+/// Where the rays of a single field cross a reference plane
+pub struct Spot {
+    pub field: Field,
+    /// Plane coordinates of every ray that reached the plane
+    pub points: Vec<[f32; 2]>,
+}
+
+impl Spot {
+    pub fn centroid(&self) -> [f32; 2] {
+        let n = self.points.len().max(1) as f32;
+        let (x, y) = self.points.iter()
+            .fold((0.0, 0.0), |(x, y), p| (x + p[0], y + p[1]));
+
+        [x / n, y / n]
+    }
+
+    /// Root mean square distance of the points from the centroid
+    pub fn rms_radius(&self) -> f32 {
+        let c = self.centroid();
+        let n = self.points.len().max(1) as f32;
+        let sum = self.points.iter()
+            .map(|p| (p[0]-c[0]).powi(2) + (p[1]-c[1]).powi(2))
+            .sum::<f32>();
+
+        (sum / n).sqrt()
+    }
+
+    /// Distance from the centroid to the furthest point
+    pub fn geometric_radius(&self) -> f32 {
+        let c = self.centroid();
+        self.points.iter()
+            .map(|p| ((p[0]-c[0]).powi(2) + (p[1]-c[1]).powi(2)).sqrt())
+            .fold(0.0, f32::max)
+    }
+}
+
+/// The first point at which a traced ray crosses the plane
+pub fn plane_hit(path: &RayPath, plane: &Plane) -> Option<Vector3<f32>> {
+    path.segments.iter().find_map(|s| {
+        let t = plane.intersect(&s.ray)?;
+        if t <= s.length {
+            Some(s.ray.origin + s.ray.dir * t)
+        } else {
+            None
+        }
+    })
+}
+
+/// Collect the spots of every field on the plane from the last trace
+pub fn spot_diagram(world: &World, plane: &Plane) -> Vec<Spot> {
+    let mut spots: Vec<Spot> = vec![];
+
+    for path in world.paths.iter() {
+        let field = world.lights[path.light].field();
+        let spot = match spots.iter().position(|s| s.field == field) {
+            Some(i) => &mut spots[i],
+            None => {
+                spots.push(Spot { field, points: vec![] });
+                spots.last_mut().unwrap()
+            }
+        };
+
+        if let Some(p) = plane_hit(path, plane) {
+            spot.points.push(plane.project(p));
+        }
+    }
+
+    spots
+}
+
+/// Write the spots as csv, one row per ray
+pub fn write_csv<W: Write>(spots: &[Spot], mut w: W) -> io::Result<()> {
+    writeln!(w, "field,x,y")?;
+    for (i, s) in spots.iter().enumerate() {
+        for p in s.points.iter() {
+            writeln!(w, "{},{},{}", i, p[0], p[1])?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::LensSide;
+    use crate::light::Light;
+    use crate::world::testing::{lens, traced};
+
+    #[test]
+    fn measures_spread_from_the_centroid() {
+        let spot = Spot {
+            field: Field::Point(Vector3::new(0.0, 0.0, 0.0)),
+            points: vec![[2.0, 1.0], [0.0, 1.0], [1.0, 2.0], [1.0, 0.0]],
+        };
+
+        assert_eq!(spot.centroid(), [1.0, 1.0]);
+        assert_eq!(spot.rms_radius(), 1.0);
+        assert_eq!(spot.geometric_radius(), 1.0);
+    }
+
+    #[test]
+    fn flat_glass_does_not_move_a_collimated_spot() {
+        let plate = lens(1.0, LensSide::Flat, LensSide::Flat, Some(0.2), [0.0; 3]);
+        let heights = [-0.5, 0.1, 0.6];
+        let mut lights = heights.iter()
+            .map(|&y| Light::Laser([2.0, y, 0.3], [-1.0, 0.0, 0.0]))
+            .collect::<Vec<_>>();
+        lights.push(Light::Laser([2.0, 0.0, 0.0], [-1.0, 0.1, 0.0]));
+
+        let plane = Plane::new(Vector3::new(-2.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        let spots = spot_diagram(&traced(&[plate], lights), &plane);
+        assert_eq!(spots.len(), 2);
+        assert_eq!(spots[0].points.len(), heights.len());
+        assert_eq!(spots[1].points.len(), 1);
+
+        // The plane's coordinates run along -z and y
+        for (p, y) in spots[0].points.iter().zip(heights) {
+            assert!((p[0] + 0.3).abs() < 1e-4 && (p[1] - y).abs() < 1e-4, "{p:?}");
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use cgmath::Vector3 as Vec3;
+use crate::geometry::IndexedMesh;
+use crate::error::{Error, Result};
+
+use std::io::Write;
+
+/// Size of the header of a binary stl file, before the triangle count
+const HEADER: usize = 80;
+/// Size of a triangle in a binary stl file: a normal, three vertices and an
+/// attribute byte count
+const TRIANGLE: usize = 50;
+
+/// Read an ascii or binary stl file. Every triangle gets its own three
+/// vertices, each with the triangle's normal.
+pub fn read_stl(path: &str) -> Result<IndexedMesh> {
+    let data = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+    parse_stl(path, &data)
+}
+
+/// Whether `data` is laid out as a binary stl file. Binary files may start
+/// with `solid` too, so their size is what tells them apart.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.get(HEADER..HEADER + 4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+        .is_some_and(|n| data.len() == HEADER + 4 + n * TRIANGLE)
+}
+
+/// Parse the contents of an stl file, `path` is only used in errors
+pub fn parse_stl(path: &str, data: &[u8]) -> Result<IndexedMesh> {
+    if is_binary(data) {
+        return Ok(parse_binary(data));
+    }
+
+    let text = std::str::from_utf8(data)
+        .map_err(|_| Error::mesh(path, None, "neither an ascii nor a binary stl file"))?;
+    parse_ascii(path, text)
+}
+
+fn parse_binary(data: &[u8]) -> IndexedMesh {
+    let vector = |b: &[u8]| {
+        let float = |at: usize| f32::from_le_bytes(b[at..at + 4].try_into().unwrap());
+        Vec3::new(float(0), float(4), float(8))
+    };
+
+    let mut mesh = IndexedMesh::default();
+    for t in data[HEADER + 4..].chunks_exact(TRIANGLE) {
+        let n = vector(t);
+        for v in 1..=3 {
+            mesh.vertices.push(vector(&t[v * 12..]));
+            mesh.normals.push(n);
+        }
+
+        let i = mesh.vertices.len();
+        mesh.faces.push([i - 3, i - 2, i - 1]);
+    }
+
+    mesh
+}
+
+fn parse_ascii(path: &str, text: &str) -> Result<IndexedMesh> {
+    let err = |line: usize, message: String| Error::mesh(path, Some(line), message);
+
+    let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l));
+    match lines.next() {
+        Some((_, l)) if l.trim_start().starts_with("solid") => {}
+        _ => return Err(Error::mesh(path, Some(1), "not an stl file")),
+    }
+
+    let numbers = |n: usize, words: &[&str]| -> Result<Vec3<f32>> {
+        let ps = words.iter()
+            .map(|w| w.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .ok()
+            .filter(|ps| ps.len() == 3)
+            .ok_or_else(|| err(n, format!("bad vector `{}`", words.join(" "))))?;
+        Ok(Vec3::new(ps[0], ps[1], ps[2]))
+    };
+
+    let mut mesh = IndexedMesh::default();
+    let mut normal = None;
+    let mut polygon = vec![];
+    let mut ended = false;
+    for (n, line) in lines {
+        let words = line.split_ascii_whitespace().collect::<Vec<_>>();
+
+        match words.as_slice() {
+            ["facet", "normal", v @ ..] => normal = Some(numbers(n, v)?),
+            ["outer", "loop"] => polygon.clear(),
+            ["vertex", v @ ..] => {
+                if normal.is_none() {
+                    return Err(err(n, "vertex outside of a facet".into()));
+                }
+                polygon.push(numbers(n, v)?);
+            }
+            ["endloop"] => {}
+            ["endfacet"] => {
+                let normal = normal.take().ok_or_else(|| err(n, "endfacet without a facet".into()))?;
+                if polygon.len() < 3 {
+                    return Err(err(n, format!("facet has {} vertices", polygon.len())));
+                }
+
+                // Split the facet into a fan of triangles around its first
+                // vertex
+                let first = mesh.vertices.len();
+                mesh.normals.extend(polygon.iter().map(|_| normal));
+                mesh.vertices.append(&mut polygon);
+                for i in first + 1..mesh.vertices.len() - 1 {
+                    mesh.faces.push([first, i, i + 1]);
+                }
+            }
+            ["endsolid", ..] => {
+                ended = true;
+                break;
+            }
+            [] => {}
+            _ => return Err(err(n, format!("can not read `{}`", line.trim()))),
+        }
+    }
+
+    if !ended {
+        return Err(Error::mesh(path, None, "file ends before endsolid"));
+    }
+
+    Ok(mesh)
+}
+
+/// Write a mesh as a binary stl file, with the normal of every face worked
+/// out from its winding
+pub fn write_stl<W: Write>(mesh: &IndexedMesh, mut w: W) -> std::io::Result<()> {
+    // Anything but `solid` at the start, which would read as ascii
+    let mut header = [b' '; HEADER];
+    header[..10].copy_from_slice(b"binary stl");
+    w.write_all(&header)?;
+    w.write_all(&(mesh.faces.len() as u32).to_le_bytes())?;
+
+    for (i, f) in mesh.faces.iter().enumerate() {
+        let n = mesh.face_normal(i);
+        for v in std::iter::once(n).chain(f.iter().map(|&v| mesh.vertices[v])) {
+            for x in [v.x, v.y, v.z] {
+                w.write_all(&x.to_le_bytes())?;
+            }
+        }
+        w.write_all(&[0, 0])?;
+    }
+
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::testing::message;
+
+    const ASCII: &str = "solid triangle
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid triangle
+";
+
+    /// A binary file of one triangle with `header` at the start
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(HEADER, b' ');
+        data.extend(1u32.to_le_bytes());
+        for x in [0.0f32, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0] {
+            data.extend(x.to_le_bytes());
+        }
+        data.extend([0, 0]);
+        data
+    }
+
+    fn assert_triangle(mesh: &IndexedMesh) {
+        assert_eq!(mesh.vertices, vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)]);
+        assert_eq!(mesh.normals, vec![Vec3::new(0.0, 0.0, 1.0); 3]);
+        assert_eq!(mesh.faces, vec![[0, 1, 2]]);
+    }
+
+    #[test]
+    fn reads_ascii() {
+        assert!(!is_binary(ASCII.as_bytes()));
+        assert_triangle(&parse_stl("triangle.stl", ASCII.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn reads_binary() {
+        let data = binary(b"made by hand");
+        assert!(is_binary(&data));
+        assert_triangle(&parse_stl("triangle.stl", &data).unwrap());
+    }
+
+    #[test]
+    fn binary_with_a_header_starting_with_solid() {
+        let data = binary(b"solid exported by a careless program");
+        assert!(is_binary(&data));
+        assert_triangle(&parse_stl("triangle.stl", &data).unwrap());
+    }
+
+    #[test]
+    fn truncated_ascii() {
+        let end = ASCII.find("    endloop").unwrap();
+        let (_, message) = message(parse_stl("triangle.stl", &ASCII.as_bytes()[..end]));
+        assert!(message.contains("ends before endsolid"), "{message}");
+    }
+
+    #[test]
+    fn ascii_too_short_for_a_binary_header() {
+        let data = b"solid empty\nendsolid empty\n";
+        assert!(!is_binary(data));
+        assert!(parse_stl("empty.stl", data).unwrap().faces.is_empty());
+    }
+
+    #[test]
+    fn ascii_whose_count_does_not_match_its_size() {
+        // Bytes 80 to 84 of the text read as a triangle count that the size
+        // of the file does not fit
+        let data = ASCII.repeat(2);
+        assert!(data.len() > HEADER + 4);
+        assert!(!is_binary(data.as_bytes()));
+    }
+}
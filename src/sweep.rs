@@ -0,0 +1,272 @@
+use crate::geometry::Plane;
+use crate::lenses::Lens;
+use crate::light::Light;
+use crate::optimize::{Param, Variable, rms_spot};
+use crate::paraxial::System;
+use crate::focus::{find_focus, exit_ray};
+use crate::world::World;
+use crate::units;
+use crate::error::{Error, Result};
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use std::io::{self, Write};
+
+/// Parameters to step through and metrics to record at every point. With
+/// several axes every combination of their values is traced.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sweep {
+    pub axes: Vec<SweepAxis>,
+    pub metrics: Vec<Metric>,
+}
+
+/// A parameter stepped evenly from `from` to `to` in `steps` values
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SweepAxis {
+    #[serde(flatten)]
+    pub knob: Knob,
+    #[serde(deserialize_with = "units::length")]
+    pub from: f32,
+    #[serde(deserialize_with = "units::length")]
+    pub to: f32,
+    pub steps: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Knob {
+    Lens { lens: usize, param: Param },
+    Light { light: usize, axis: Axis },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Axis { X, Y, Z }
+
+impl Sweep {
+    /// Check that every axis steps a lens or light of the scene, `path` is
+    /// the sweep file named in errors
+    pub fn check(&self, path: &str, lenses: usize, lights: usize) -> Result<()> {
+        for (i, axis) in self.axes.iter().enumerate() {
+            let missing = match axis.knob {
+                Knob::Lens { lens, .. } => (lens >= lenses).then(|| format!("there is no lens {lens}, the scene has {lenses}")),
+                Knob::Light { light, .. } => (light >= lights).then(|| format!("there is no light {light}, the scene has {lights}")),
+            };
+
+            if let Some(message) = missing {
+                return Err(Error::Scene(format!("{path}: axes[{i}] ({}): {message}", axis.name())));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SweepAxis {
+    pub fn values(&self) -> Vec<f32> {
+        let n = self.steps.max(1);
+        (0..n)
+            .map(|i| {
+                let t = if n > 1 { i as f32 / (n - 1) as f32 } else { 0.0 };
+                self.from + t * (self.to - self.from)
+            })
+            .collect()
+    }
+
+    pub fn name(&self) -> String {
+        match self.knob {
+            Knob::Lens { lens, param } => format!("lens{lens}.{param:?}"),
+            Knob::Light { light, axis } => format!("light{light}.{axis:?}"),
+        }
+    }
+
+    fn set(&self, lenses: &mut [Lens], lights: &mut [Light], v: f32) -> Result<()> {
+        match self.knob {
+            Knob::Lens { lens, param } => {
+                Variable { lens, param, min: None, max: None }.set(lenses, v)
+            }
+            Knob::Light { light, axis } => {
+                lights[light].pos_mut()[axis as usize] = v;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum Metric {
+    /// Mean distance from the centre of the lens to the best focus of every
+    /// field
+    #[serde(alias = "FocalDistance")]
+    LensDistance,
+    /// Mean distance from the rear vertex to the best focus of every field
+    BackFocalDistance,
+    /// Paraxial effective focal length of the system
+    FocalLength,
+    /// RMS spot radius on the detector, or at best focus without one
+    RmsSpot,
+    /// Fraction of traced rays that make it back out of the glass
+    Transmitted,
+}
+
+impl Metric {
+    /// Whether the metric is a length in the scene's unit
+    pub fn is_length(&self) -> bool {
+        !matches!(self, Self::Transmitted)
+    }
+
+    pub fn measure(&self, world: &World, lenses: &[Lens], detector: Option<&Plane>) -> f32 {
+        match self {
+            Self::LensDistance | Self::BackFocalDistance => {
+                let foci = find_focus(world);
+                let rays = foci.iter().map(|f| f.rays).sum::<usize>();
+                let sum = foci.iter()
+                    .map(|f| f.rays as f32 * if let Self::LensDistance = self { f.lens_distance } else { f.bfl })
+                    .sum::<f32>();
+
+                if rays > 0 { sum / rays as f32 } else { f32::NAN }
+            }
+            Self::FocalLength => System::new(lenses)
+                .map(|s| s.first_order().efl)
+                .unwrap_or(f32::NAN),
+            Self::RmsSpot => rms_spot(world, detector),
+            Self::Transmitted => {
+                let out = world.paths.iter()
+                    .filter(|p| exit_ray(p).is_some())
+                    .count();
+
+                out as f32 / world.paths.len().max(1) as f32
+            }
+        }
+    }
+}
+
+/// The parameter values at one point of a sweep and the metrics measured there
+pub struct Row {
+    pub values: Vec<f32>,
+    pub metrics: Vec<f32>,
+}
+
+/// Trace every point of the sweep, building the world for each with `build`
+pub fn run<B>(sweep: &Sweep, lenses: &[Lens], lights: &[Light], detector: Option<&Plane>, mut build: B) -> Result<Vec<Row>>
+where
+    B: FnMut(&[Lens], &[Light]) -> Result<World>
+{
+    let axes = sweep.axes.iter().map(|a| a.values()).collect::<Vec<_>>();
+    let total = axes.iter().map(|a| a.len()).product::<usize>();
+
+    (0..total)
+        .map(|mut k| {
+            // Decompose k into an index along every axis, last axis fastest
+            let mut idx = vec![0; axes.len()];
+            for (i, a) in axes.iter().enumerate().rev() {
+                idx[i] = k % a.len();
+                k /= a.len();
+            }
+
+            let mut lenses = lenses.to_vec();
+            let mut lights = lights.to_vec();
+            let values = idx.iter().enumerate()
+                .map(|(i, &j)| {
+                    let v = axes[i][j];
+                    sweep.axes[i].set(&mut lenses, &mut lights, v)?;
+                    Ok(v)
+                })
+                .collect::<Result<_>>()?;
+
+            let world = build(&lenses, &lights)?;
+            let metrics = sweep.metrics.iter()
+                .map(|m| m.measure(&world, &lenses, detector))
+                .collect();
+
+            Ok(Row { values, metrics })
+        })
+        .collect()
+}
+
+pub fn write_csv<W: Write>(sweep: &Sweep, rows: &[Row], mut w: W) -> io::Result<()> {
+    let header = sweep.axes.iter().map(|a| a.name())
+        .chain(sweep.metrics.iter().map(|m| format!("{m:?}")))
+        .collect::<Vec<_>>();
+    writeln!(w, "{}", header.join(","))?;
+
+    for r in rows {
+        let row = r.values.iter().chain(r.metrics.iter())
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+        writeln!(w, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenses::LensSide;
+    use crate::world::testing::lens;
+
+    fn axis(knob: Knob, from: f32, to: f32, steps: usize) -> SweepAxis {
+        SweepAxis { knob, from, to, steps }
+    }
+
+    #[test]
+    fn steps_from_one_end_to_the_other() {
+        let x = |steps| axis(Knob::Light { light: 0, axis: Axis::X }, 0.1, 0.5, steps);
+
+        let values = x(5).values();
+        assert_eq!(values.len(), 5);
+        assert_eq!((values[0], values[4]), (0.1, 0.5));
+        assert!((values[2] - 0.3).abs() < 1e-6);
+        assert_eq!(x(1).values(), [0.1]);
+        assert_eq!(x(0).values(), [0.1]);
+    }
+
+    #[test]
+    fn runs_every_combination_with_the_last_axis_fastest() {
+        let lenses = [lens(1.0, LensSide::Convex(0.1), LensSide::Flat, Some(0.3), [0.0; 3])];
+        let lights = [Light::Laser([2.0, 0.0, 0.0], [-1.0, 0.0, 0.0])];
+        let sweep = Sweep {
+            axes: vec![
+                axis(Knob::Lens { lens: 0, param: Param::X }, 0.0, 1.0, 3),
+                axis(Knob::Light { light: 0, axis: Axis::Y }, -0.5, 0.5, 2),
+            ],
+            metrics: vec![Metric::FocalLength, Metric::Transmitted],
+        };
+
+        let mut built = vec![];
+        let rows = run(&sweep, &lenses, &lights, None, |lenses, lights| {
+            built.push((lenses[0].pos[0], lights[0].spawn().origin.y));
+            Ok(World::new())
+        }).unwrap();
+
+        let expected = [(0.0, -0.5), (0.0, 0.5), (0.5, -0.5), (0.5, 0.5), (1.0, -0.5), (1.0, 0.5)];
+        assert_eq!(built, expected);
+        assert_eq!(rows.len(), 6);
+        for (row, (x, y)) in rows.iter().zip(expected) {
+            assert_eq!(row.values, [x, y]);
+            // Moving the lens does not change its focal length, and nothing
+            // was traced to transmit
+            assert!((row.metrics[0] - rows[0].metrics[0]).abs() < 1e-6);
+            assert_eq!(row.metrics[1], 0.0);
+        }
+
+        let mut out = vec![];
+        write_csv(&sweep, &rows, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().next(), Some("lens0.X,light0.Y,FocalLength,Transmitted"));
+        assert_eq!(out.lines().count(), 7);
+    }
+
+    #[test]
+    fn rejects_axes_of_missing_lenses_and_lights() {
+        let sweep = |knob| Sweep { axes: vec![axis(knob, 0.0, 1.0, 2)], metrics: vec![] };
+
+        assert!(sweep(Knob::Lens { lens: 0, param: Param::Y }).check("s.yaml", 1, 0).is_ok());
+        let Err(Error::Scene(message)) = sweep(Knob::Lens { lens: 1, param: Param::Y }).check("s.yaml", 1, 0) else {
+            panic!("expected a scene error");
+        };
+        assert_eq!(message, "s.yaml: axes[0] (lens1.Y): there is no lens 1, the scene has 1");
+        assert!(sweep(Knob::Light { light: 0, axis: Axis::Z }).check("s.yaml", 1, 0).is_err());
+    }
+}
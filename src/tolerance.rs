@@ -0,0 +1,348 @@
+use crate::geometry::Plane;
+use crate::lenses::{Lens, LensSide};
+use crate::sweep::Metric;
+use crate::world::World;
+use crate::units;
+use crate::error::{Error, Result};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use std::io::{self, Write};
+
+/// Tolerances of the lenses and how to judge the perturbed designs
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Tolerancing {
+    pub tolerances: Vec<Tolerance>,
+    pub metric: Metric,
+    #[serde(default = "default_trials")]
+    pub trials: usize,
+    /// Seed for the perturbations, random when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// A trial passes when the metric is at most this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
+    /// A trial passes when the metric is within this of the nominal design
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_change: Option<f32>,
+}
+
+fn default_trials() -> usize {
+    100
+}
+
+/// A parameter of one lens allowed to vary by up to `tol` either way
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(try_from = "WrittenTolerance")]
+pub struct Tolerance {
+    pub lens: usize,
+    pub param: Toleranced,
+    /// A length for the radii, thickness and decenters, degrees for the
+    /// tilts and a plain number for the index
+    #[schemars(with = "units::Length")]
+    pub tol: f32,
+    #[serde(default)]
+    pub distribution: Distribution,
+}
+
+/// A tolerance as written, before `tol` is read by what its parameter is
+#[derive(Deserialize)]
+struct WrittenTolerance {
+    lens: usize,
+    param: Toleranced,
+    tol: units::Written,
+    #[serde(default)]
+    distribution: Distribution,
+}
+
+impl TryFrom<WrittenTolerance> for Tolerance {
+    type Error = String;
+
+    fn try_from(t: WrittenTolerance) -> std::result::Result<Self, String> {
+        let tol = if t.param.is_length() { t.tol.length() } else { t.tol.number() }?;
+        Ok(Self { lens: t.lens, param: t.param, tol, distribution: t.distribution })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub enum Toleranced {
+    /// Radius of curvature of the left side
+    LeftRadius,
+    /// Radius of curvature of the right side
+    RightRadius,
+    Thickness,
+    /// Shift across the axis
+    DecenterY,
+    DecenterZ,
+    /// Tilt of the axis towards y in degrees, a rotation about z. Changes
+    /// `tilt[0]` of the lens.
+    TiltY,
+    /// Tilt of the axis towards z in degrees, a rotation about y. Changes
+    /// `tilt[1]` of the lens.
+    TiltZ,
+    Index,
+}
+
+impl Toleranced {
+    /// Whether the parameter is a length in the scene's unit
+    pub fn is_length(&self) -> bool {
+        !matches!(self, Self::TiltY | Self::TiltZ | Self::Index)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema)]
+pub enum Distribution {
+    /// Uniform over the whole range
+    #[default]
+    Uniform,
+    /// Normal with the tolerance at three standard deviations, clipped to it
+    Normal,
+}
+
+impl Distribution {
+    fn sample<R: Rng>(&self, rng: &mut R, tol: f32) -> f32 {
+        match self {
+            Self::Uniform => tol * rng.gen_range(-1.0..=1.0),
+            Self::Normal => {
+                // Box-Muller
+                let u = rng.gen::<f32>().max(f32::MIN_POSITIVE);
+                let v = rng.gen::<f32>();
+                let n = (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos();
+                (tol * n / 3.0).clamp(-tol, tol)
+            }
+        }
+    }
+}
+
+impl Tolerance {
+    /// Change the parameter of its lens by `d`
+    pub fn perturb(&self, lenses: &mut [Lens], d: f32) -> Result<()> {
+        let count = lenses.len();
+        let lens = lenses.get_mut(self.lens)
+            .ok_or_else(|| Error::Scene(format!("tolerance of lens {}, the scene has {count} lenses", self.lens)))?;
+        let radius = lens.radius;
+        let side = |s: LensSide| LensSide::from_radius_of_curvature(s.radius_of_curvature(radius) + d, radius);
+
+        match self.param {
+            Toleranced::LeftRadius => lens.left = side(lens.left),
+            Toleranced::RightRadius => lens.right = side(lens.right),
+            Toleranced::Thickness => lens.thickness = Some((lens.center_thickness() + d).max(0.0)),
+            Toleranced::DecenterY => lens.pos[1] += d,
+            Toleranced::DecenterZ => lens.pos[2] += d,
+            Toleranced::TiltY => lens.tilt[0] += d,
+            Toleranced::TiltZ => lens.tilt[1] += d,
+            Toleranced::Index => lens.index = (lens.index + d).max(1.0),
+        }
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        format!("lens{}.{:?}", self.lens, self.param)
+    }
+}
+
+/// One perturbed design, with the change applied to every tolerance
+pub struct Trial {
+    pub changes: Vec<f32>,
+    pub value: f32,
+}
+
+/// Outcome of a tolerance run
+pub struct Report {
+    pub nominal: f32,
+    pub trials: Vec<Trial>,
+}
+
+impl Report {
+    /// Metric values of the trials that could be evaluated, sorted
+    pub fn values(&self) -> Vec<f32> {
+        let mut v = self.trials.iter()
+            .map(|t| t.value)
+            .filter(|v| v.is_finite())
+            .collect::<Vec<_>>();
+        v.sort_by(|a, b| a.total_cmp(b));
+        v
+    }
+
+    pub fn mean(&self) -> f32 {
+        let v = self.values();
+        v.iter().sum::<f32>() / v.len().max(1) as f32
+    }
+
+    pub fn std_dev(&self) -> f32 {
+        let v = self.values();
+        let mean = self.mean();
+        (v.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / v.len().max(1) as f32).sqrt()
+    }
+
+    /// Value below which fraction `p` of the trials fall
+    pub fn percentile(&self, p: f32) -> f32 {
+        let v = self.values();
+        if v.is_empty() {
+            return f32::NAN;
+        }
+
+        v[((p * (v.len() - 1) as f32).round() as usize).min(v.len() - 1)]
+    }
+
+    /// Fraction of all trials that pass the limits of `tol`
+    pub fn yield_fraction(&self, tol: &Tolerancing) -> f32 {
+        let pass = self.trials.iter()
+            .filter(|t| {
+                t.value.is_finite()
+                    && tol.max.is_none_or(|m| t.value <= m)
+                    && tol.max_change.is_none_or(|m| (t.value - self.nominal).abs() <= m)
+            })
+            .count();
+
+        pass as f32 / self.trials.len().max(1) as f32
+    }
+}
+
+/// Trace the nominal design and `trials` randomly perturbed copies of it,
+/// building the world for each with `build`
+pub fn monte_carlo<B>(tol: &Tolerancing, lenses: &[Lens], detector: Option<&Plane>, mut build: B) -> Result<Report>
+where
+    B: FnMut(&[Lens]) -> Result<World>
+{
+    let mut rng = match tol.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let world = build(lenses)?;
+    let nominal = tol.metric.measure(&world, lenses, detector);
+
+    let trials = (0..tol.trials)
+        .map(|_| {
+            let mut trial = lenses.to_vec();
+            let changes = tol.tolerances.iter()
+                .map(|t| {
+                    let d = t.distribution.sample(&mut rng, t.tol);
+                    t.perturb(&mut trial, d)?;
+                    Ok(d)
+                })
+                .collect::<Result<_>>()?;
+
+            let world = build(&trial)?;
+            Ok(Trial { changes, value: tol.metric.measure(&world, &trial, detector) })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Report { nominal, trials })
+}
+
+pub fn write_csv<W: Write>(tol: &Tolerancing, report: &Report, mut w: W) -> io::Result<()> {
+    let header = tol.tolerances.iter().map(|t| t.name())
+        .chain([format!("{:?}", tol.metric), "change".to_string()])
+        .collect::<Vec<_>>();
+    writeln!(w, "{}", header.join(","))?;
+
+    for t in report.trials.iter() {
+        let row = t.changes.iter()
+            .chain([t.value, t.value - report.nominal].iter())
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+        writeln!(w, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Unit;
+    use crate::world::testing::lens;
+
+    fn tolerancing(tolerances: Vec<Tolerance>, seed: u64) -> Tolerancing {
+        Tolerancing { tolerances, metric: Metric::FocalLength, trials: 50, seed: Some(seed), max: None, max_change: Some(0.05) }
+    }
+
+    fn index(tol: f32, distribution: Distribution) -> Tolerance {
+        Tolerance { lens: 0, param: Toleranced::Index, tol, distribution }
+    }
+
+    #[test]
+    fn seeded_runs_repeat_and_measure_each_change() {
+        let lenses = [lens(1.0, LensSide::Convex(0.2), LensSide::Flat, Some(0.3), [0.0; 3])];
+        let r = lenses[0].left.radius_of_curvature(1.0);
+        let tol = tolerancing(vec![index(0.02, Distribution::Uniform)], 7);
+
+        // Only the paraxial focal length is measured, so nothing needs tracing
+        let report = monte_carlo(&tol, &lenses, None, |_| Ok(World::new())).unwrap();
+        let again = monte_carlo(&tol, &lenses, None, |_| Ok(World::new())).unwrap();
+        let other = monte_carlo(&tolerancing(tol.tolerances.clone(), 8), &lenses, None, |_| Ok(World::new())).unwrap();
+
+        assert!((report.nominal - r / 0.5).abs() < 1e-4);
+        assert_eq!(report.trials.len(), 50);
+        let changes = |r: &Report| r.trials.iter().map(|t| t.changes[0]).collect::<Vec<_>>();
+        assert_eq!(changes(&report), changes(&again));
+        assert_ne!(changes(&report), changes(&other));
+
+        // A plano-convex lens has f = R / (n - 1)
+        for t in report.trials.iter() {
+            assert!(t.changes[0].abs() <= 0.02);
+            assert!((t.value - r / (0.5 + t.changes[0])).abs() < 1e-3, "{} {}", t.changes[0], t.value);
+        }
+
+        // Raising the index shortens the focal length
+        let values = report.values();
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        assert!(report.percentile(0.0) > r / 0.52 - 1e-3 && report.percentile(1.0) < r / 0.48 + 1e-3);
+
+        let mean = values.iter().sum::<f32>() / 50.0;
+        let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / 50.0;
+        assert!((report.mean() - mean).abs() < 1e-5);
+        assert!((report.std_dev() - var.sqrt()).abs() < 1e-5);
+
+        let pass = report.trials.iter().filter(|t| (t.value - report.nominal).abs() <= 0.05).count();
+        assert_eq!(report.yield_fraction(&tol), pass as f32 / 50.0);
+    }
+
+    #[test]
+    fn summarises_only_the_trials_that_could_be_evaluated() {
+        let report = Report {
+            nominal: 1.0,
+            trials: [3.0, f32::NAN, 1.0, 2.0, 1.02].into_iter()
+                .map(|value| Trial { changes: vec![], value })
+                .collect(),
+        };
+
+        assert_eq!(report.values(), [1.0, 1.02, 2.0, 3.0]);
+        assert!((report.mean() - 1.755).abs() < 1e-5);
+        assert_eq!(report.percentile(0.5), 2.0);
+
+        let mut tol = tolerancing(vec![], 0);
+        assert_eq!(report.yield_fraction(&tol), 2.0 / 5.0);
+        tol.max_change = None;
+        tol.max = Some(2.5);
+        assert_eq!(report.yield_fraction(&tol), 3.0 / 5.0);
+    }
+
+    #[test]
+    fn normal_samples_put_the_tolerance_at_three_deviations() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = (0..20000).map(|_| Distribution::Normal.sample(&mut rng, 0.3)).collect::<Vec<_>>();
+
+        assert!(samples.iter().all(|s| s.abs() <= 0.3));
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        let sd = (samples.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / samples.len() as f32).sqrt();
+        assert!(mean.abs() < 0.005, "{mean}");
+        assert!((sd - 0.1).abs() < 0.005, "{sd}");
+    }
+
+    #[test]
+    fn reads_tolerances_by_what_their_parameter_is() {
+        let read = |source: &str| units::reading(Some(Unit::Cm), None, || serde_yaml::from_str::<Tolerance>(source));
+
+        assert_eq!(read("{ lens: 0, param: Thickness, tol: 1mm }").unwrap().tol, 0.1);
+        assert_eq!(read("{ lens: 0, param: TiltY, tol: 2 }").unwrap().tol, 2.0);
+        assert!(read("{ lens: 0, param: TiltY, tol: 2mm }").is_err());
+        assert!(read("{ lens: 0, param: Index, tol: 0.01mm }").is_err());
+    }
+}
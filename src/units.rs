@@ -0,0 +1,210 @@
+use std::cell::Cell;
+use std::fmt;
+
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{self, Visitor};
+use schemars::JsonSchema;
+
+/// Unit of the lengths in a scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Mm,
+    Cm,
+    M,
+    Inch,
+}
+
+impl Unit {
+    pub fn metres(self) -> f64 {
+        match self {
+            Self::Mm => 0.001,
+            Self::Cm => 0.01,
+            Self::M => 1.0,
+            Self::Inch => 0.0254,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mm => "mm",
+            Self::Cm => "cm",
+            Self::M => "m",
+            Self::Inch => "inch",
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Size of the scene's length unit relative to the units of scenes that do
+/// not give one. Those are laid out as if in centimetres, so the enclosure
+/// and the viewer's camera keep their size in any unit.
+pub fn scale(unit: Option<Unit>) -> f32 {
+    unit.map_or(1.0, |u| (Unit::Cm.metres() / u.metres()) as f32)
+}
+
+/// The unit written after a length, with a leading space, or nothing for
+/// scenes without a unit
+pub fn suffix(unit: Option<Unit>) -> String {
+    unit.map(|u| format!(" {u}")).unwrap_or_default()
+}
+
+/// Length of a unit suffix in metres
+fn suffix_metres(suffix: &str) -> Option<f64> {
+    Some(match suffix {
+        "nm" => 1e-9,
+        "um" | "µm" => 1e-6,
+        "mm" => 0.001,
+        "cm" => 0.01,
+        "m" => 1.0,
+        "in" | "inch" | "\"" => 0.0254,
+        _ => return None,
+    })
+}
+
+/// Parse a length like `25`, `25mm` or `1.5 inch` into `unit`. Plain numbers
+/// are already in `unit`, a length with a suffix needs a unit to convert to.
+pub fn parse_length(s: &str, unit: Option<Unit>) -> Result<f32, String> {
+    let s = s.trim();
+    let split = s.trim_end_matches(|c: char| c.is_alphabetic() || c == '"').len();
+    let (number, suffix) = (s[..split].trim(), s[split..].trim());
+
+    let value = number.parse::<f64>().map_err(|_| format!("`{s}` is not a length"))?;
+    if suffix.is_empty() {
+        return Ok(value as f32);
+    }
+
+    let metres = suffix_metres(suffix).ok_or_else(|| format!("`{suffix}` in `{s}` is not a unit of length"))?;
+    let unit = unit.ok_or_else(|| format!("`{s}` has a unit but the scene does not set one"))?;
+    Ok((value * metres / unit.metres()) as f32)
+}
+
+thread_local! {
+    /// Unit of the file being read and the unit its lengths are converted to
+    static UNITS: Cell<(Option<Unit>, Option<Unit>)> = const { Cell::new((None, None)) };
+}
+
+/// Deserialize with plain lengths written in `file` and every length,
+/// suffixed or not, converted to `target`. Without a target lengths are left
+/// in the unit of the file.
+pub fn reading<T>(file: Option<Unit>, target: Option<Unit>, f: impl FnOnce() -> T) -> T {
+    let previous = UNITS.with(|u| u.replace((file, target.or(file))));
+    let out = f();
+    UNITS.with(|u| u.set(previous));
+    out
+}
+
+/// A default length of scenes without a unit in the unit lengths are being
+/// read into, so defaults keep their size in any unit as `scale` does
+pub fn default_length(length: f32) -> f32 {
+    length * scale(UNITS.with(Cell::get).1)
+}
+
+fn convert(value: f64) -> f32 {
+    match UNITS.with(Cell::get) {
+        (Some(file), Some(target)) => (value * file.metres() / target.metres()) as f32,
+        _ => value as f32,
+    }
+}
+
+struct LengthVisitor;
+
+impl<'de> Visitor<'de> for LengthVisitor {
+    type Value = f32;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a length, as a number or with a unit like \"25mm\"")
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<f32, E> {
+        Ok(convert(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<f32, E> {
+        Ok(convert(v as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<f32, E> {
+        Ok(convert(v as f64))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<f32, E> {
+        let (_, target) = UNITS.with(Cell::get);
+        parse_length(v, target).map_err(E::custom)
+    }
+}
+
+/// A length in the scene's unit, from a number or a string with a unit
+/// suffix
+pub fn length<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+    d.deserialize_any(LengthVisitor)
+}
+
+pub fn optional_length<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(transparent)]
+    struct Wrapped(#[serde(deserialize_with = "length")] f32);
+
+    Ok(Option::<Wrapped>::deserialize(d)?.map(|Wrapped(l)| l))
+}
+
+/// A point with every coordinate a length
+pub fn point<'de, D: Deserializer<'de>>(d: D) -> Result<[f32; 3], D::Error> {
+    #[derive(Deserialize)]
+    #[serde(transparent)]
+    struct Wrapped(#[serde(deserialize_with = "length")] f32);
+
+    let [Wrapped(x), Wrapped(y), Wrapped(z)] = <[Wrapped; 3]>::deserialize(d)?;
+    Ok([x, y, z])
+}
+
+pub fn optional_point<'de, D: Deserializer<'de>>(d: D) -> Result<Option<[f32; 3]>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(transparent)]
+    struct Wrapped(#[serde(deserialize_with = "point")] [f32; 3]);
+
+    Ok(Option::<Wrapped>::deserialize(d)?.map(|Wrapped(p)| p))
+}
+
+/// A value that is a length for some settings and a plain number for others,
+/// as written, to be converted once the setting is known. Convert it while the
+/// file is still being read, as `length` does.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Written {
+    Number(f64),
+    Text(String),
+}
+
+impl Written {
+    /// The value as a length in the scene's unit
+    pub fn length(&self) -> Result<f32, String> {
+        match self {
+            Self::Number(v) => Ok(convert(*v)),
+            Self::Text(s) => parse_length(s, UNITS.with(Cell::get).1),
+        }
+    }
+
+    /// The value as a number without a unit
+    pub fn number(&self) -> Result<f32, String> {
+        match self {
+            Self::Number(v) => Ok(*v as f32),
+            Self::Text(s) => s.trim().parse().map_err(|_| format!("`{s}` is not a number, only lengths take a unit")),
+        }
+    }
+}
+
+/// How a length is written, for the JSON Schema
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+#[serde(untagged)]
+pub enum Length {
+    Number(f32),
+    /// A number followed by one of nm, um, mm, cm, m or inch
+    WithUnit(String),
+}
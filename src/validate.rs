@@ -0,0 +1,584 @@
+use crate::lenses::{Lens, LensSide};
+use crate::light::Light;
+use crate::geometry::Plane;
+use crate::enclosure::Enclosure;
+use crate::world::Material;
+use crate::mesh::Mesh;
+use crate::optimize::Optimize;
+use crate::tolerance::{Tolerancing, Toleranced};
+use crate::repair;
+
+use std::path::Path;
+
+use std::fmt;
+
+/// Number of heights at which the surfaces of coaxial lenses are compared
+const OVERLAP_SAMPLES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The scene can be traced but probably not as intended
+    Warning,
+    /// The scene would trace to NaNs or to a wrong result
+    Error,
+}
+
+/// A problem with one value of a scene
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Path of the value in the scene, like `lenses[0].left`
+    pub path: String,
+    /// Line of the value in the scene file, when it could be found
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn error(path: String, message: String) -> Self {
+        Self { severity: Severity::Error, path, line: None, message }
+    }
+
+    pub(crate) fn warning(path: String, message: String) -> Self {
+        Self { severity: Severity::Warning, path, line: None, message }
+    }
+
+    /// The diagnostic prefixed with the file and line it refers to
+    pub fn describe(&self, file: &str) -> String {
+        match self.line {
+            Some(line) => format!("{file}:{line}: {self}"),
+            None => format!("{file}: {self}"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Check the lenses, lights and detector of a scene for values that can not
+/// be traced or that give a degenerate trace. Lines are not filled in, see
+/// [`locate_all`].
+pub fn check(lenses: &[Lens], lights: &[Light], detector: Option<&Plane>) -> Vec<Diagnostic> {
+    let mut out = vec![];
+
+    let mut sound = vec![];
+    for (i, lens) in lenses.iter().enumerate() {
+        let before = out.len();
+        check_lens(&format!("lenses[{i}]"), lens, &mut out);
+        if !out[before..].iter().any(|d| d.severity == Severity::Error) {
+            sound.push(i);
+        }
+    }
+
+    // A lens that leaks would let rays out of the glass without refracting
+    for &i in &sound {
+        let report = repair::repair(&mut lenses[i].mesh());
+        if !report.watertight() {
+            out.push(Diagnostic::error(format!("lenses[{i}]"), format!("tessellated surface is not watertight, it has {}", report.describe())));
+        }
+    }
+
+    // Only lenses with a sound shape can be compared with each other
+    for (a, &i) in sound.iter().enumerate() {
+        for &j in &sound[a + 1..] {
+            if overlap(&lenses[i], &lenses[j]) {
+                out.push(Diagnostic::error(format!("lenses[{j}]"), format!("overlaps lenses[{i}]")));
+            }
+        }
+    }
+
+    for (j, light) in lights.iter().enumerate() {
+        let path = format!("lights[{j}]");
+        let pos = match light {
+            Light::Laser(p, _) | Light::Point(p) => *p,
+        };
+
+        if !finite(&pos) {
+            out.push(Diagnostic::error(format!("{path}[0]"), "position is not a finite point".into()));
+            continue;
+        }
+
+        if let Light::Laser(_, dir) = light {
+            if !finite(dir) {
+                out.push(Diagnostic::error(format!("{path}[1]"), "direction is not a finite vector".into()));
+            } else if length(dir) == 0.0 {
+                out.push(Diagnostic::error(format!("{path}[1]"), "direction has zero length".into()));
+            }
+        }
+
+        for &i in &sound {
+            if lenses[i].contains(pos) {
+                out.push(Diagnostic::error(path.clone(), format!("starts inside the glass of lenses[{i}]")));
+            }
+        }
+    }
+
+    if let Some(d) = detector {
+        if !finite(&d.pos) {
+            out.push(Diagnostic::error("detector.pos".into(), "is not a finite point".into()));
+        }
+        if !finite(&d.normal) || length(&d.normal) == 0.0 {
+            out.push(Diagnostic::error("detector.normal".into(), "is not a usable plane normal".into()));
+        }
+    }
+
+    out
+}
+
+/// Check the placement and material of every mesh of a scene
+pub fn check_meshes(meshes: &[Mesh], out: &mut Vec<Diagnostic>) {
+    for (i, mesh) in meshes.iter().enumerate() {
+        let path = format!("meshes[{i}]");
+        if !finite(&mesh.pos) {
+            out.push(Diagnostic::error(format!("{path}.pos"), "is not a finite point".into()));
+        }
+        if !finite(&mesh.rotation) {
+            out.push(Diagnostic::error(format!("{path}.rotation"), "is not a finite rotation".into()));
+        }
+        if !mesh.scale.is_finite() || mesh.scale == 0.0 {
+            out.push(Diagnostic::error(format!("{path}.scale"), format!("must be finite and not zero, not {}", mesh.scale)));
+        }
+        check_material(&format!("{path}.material"), &mesh.material, out);
+    }
+}
+
+/// Check that every variable of the optimizer belongs to one of `lenses`
+pub fn check_optimize(opt: &Optimize, lenses: usize, out: &mut Vec<Diagnostic>) {
+    for (i, v) in opt.variables.iter().enumerate() {
+        if v.lens >= lenses {
+            out.push(Diagnostic::error(format!("optimize.variables[{i}].lens"), format!("there is no lens {}, the scene has {lenses}", v.lens)));
+        }
+    }
+}
+
+/// Check that every tolerance belongs to one of `lenses` and can change it.
+/// The radius of a flat side is infinite, so tolerancing it does nothing.
+pub fn check_tolerance(tol: &Tolerancing, lenses: &[Lens], out: &mut Vec<Diagnostic>) {
+    for (i, t) in tol.tolerances.iter().enumerate() {
+        let path = format!("tolerance.tolerances[{i}]");
+        let Some(lens) = lenses.get(t.lens) else {
+            out.push(Diagnostic::error(format!("{path}.lens"), format!("there is no lens {}, the scene has {}", t.lens, lenses.len())));
+            continue;
+        };
+
+        let side = match t.param {
+            Toleranced::LeftRadius => Some(("left", &lens.left)),
+            Toleranced::RightRadius => Some(("right", &lens.right)),
+            _ => None,
+        };
+        if let Some((name, LensSide::Flat)) = side {
+            out.push(Diagnostic::error(
+                format!("{path}.param"),
+                format!("the {name} side of lens {} is flat, its radius of curvature can not be toleranced", t.lens),
+            ));
+        }
+    }
+}
+
+/// Read the meshes of a scene from `dir`, reporting the files that can not
+/// be read and the glass meshes with holes, which rays can leak through
+pub fn check_mesh_files(meshes: &[Mesh], dir: &Path, out: &mut Vec<Diagnostic>) {
+    for (i, mesh) in meshes.iter().enumerate() {
+        let path = format!("meshes[{i}].file");
+        match mesh.load(dir) {
+            Err(e) => out.push(Diagnostic::error(path, e.to_string())),
+            Ok((_, report)) if !report.watertight() && matches!(mesh.material, Material::Glass(_)) => {
+                out.push(Diagnostic::warning(path, format!("glass mesh is not watertight, it has {}", report.describe())));
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Check the enclosure of a scene and the distance escaped rays are drawn to
+pub fn check_surroundings(enclosure: &Enclosure, far: Option<f32>, out: &mut Vec<Diagnostic>) {
+    let pos = match enclosure {
+        Enclosure::Box { size, pos, material } => {
+            if size.is_some_and(|s| !finite(&s) || s.iter().any(|&x| x <= 0.0)) {
+                out.push(Diagnostic::error("enclosure.size".into(), "every side of the box must be positive".into()));
+            }
+            check_material("enclosure.material", material, out);
+            *pos
+        }
+        Enclosure::Mesh { pos, material, .. } => {
+            check_material("enclosure.material", material, out);
+            Some(*pos)
+        }
+        Enclosure::None => None,
+    };
+
+    if pos.is_some_and(|p| !finite(&p)) {
+        out.push(Diagnostic::error("enclosure.pos".into(), "is not a finite point".into()));
+    }
+
+    if far.is_some_and(|f| !f.is_finite() || f <= 0.0) {
+        out.push(Diagnostic::error("far".into(), "must be a positive distance".into()));
+    }
+}
+
+fn check_material(path: &str, material: &Material, out: &mut Vec<Diagnostic>) {
+    if let Material::Glass(index) = material {
+        if !index.is_finite() || *index <= 0.0 {
+            out.push(Diagnostic::error(path.into(), format!("refractive index must be positive, not {index}")));
+        }
+    }
+}
+
+fn check_lens(path: &str, lens: &Lens, out: &mut Vec<Diagnostic>) {
+    let radius_ok = lens.radius.is_finite() && lens.radius > 0.0;
+    if !radius_ok {
+        out.push(Diagnostic::error(format!("{path}.radius"), format!("radius must be positive, not {}", lens.radius)));
+    }
+
+    let mut sides_ok = true;
+    for (name, side) in [("left", &lens.left), ("right", &lens.right)] {
+        let h = match side {
+            LensSide::Flat => continue,
+            LensSide::Convex(h) | LensSide::Concave(h) => *h,
+        };
+
+        let problem = if !h.is_finite() || h <= 0.0 {
+            Some(format!("sag must be positive, not {h}, use !Flat for a flat side"))
+        } else if radius_ok && h > lens.radius {
+            Some(format!("sag {h} is larger than the lens radius {}, a side can be at most a hemisphere", lens.radius))
+        } else {
+            None
+        };
+
+        if let Some(message) = problem {
+            out.push(Diagnostic::error(format!("{path}.{name}"), message));
+            sides_ok = false;
+        }
+    }
+
+    if let Some(t) = lens.thickness {
+        if !t.is_finite() || t <= 0.0 {
+            out.push(Diagnostic::error(format!("{path}.thickness"), format!("thickness must be positive, not {t}")));
+            sides_ok = false;
+        } else if sides_ok && t < lens.left.sag() + lens.right.sag() {
+            out.push(Diagnostic::warning(
+                format!("{path}.thickness"),
+                format!("thickness {t} is less than the sags of the sides, the lens will be {} thick", lens.center_thickness()),
+            ));
+        }
+    }
+
+    if sides_ok && lens.center_thickness() <= 0.0 {
+        out.push(Diagnostic::error(
+            path.to_string(),
+            format!("the sides cross on the axis, centre thickness is {}", lens.center_thickness()),
+        ));
+    }
+
+    if !lens.index.is_finite() || lens.index <= 0.0 {
+        out.push(Diagnostic::error(format!("{path}.index"), format!("refractive index must be positive, not {}", lens.index)));
+    } else if lens.index < 1.0 {
+        out.push(Diagnostic::warning(format!("{path}.index"), format!("refractive index {} is below that of the surrounding air", lens.index)));
+    }
+
+    if !finite(&lens.pos) {
+        out.push(Diagnostic::error(format!("{path}.pos"), "position is not a finite point".into()));
+    }
+
+    if !lens.tilt.iter().all(|a| a.is_finite()) {
+        out.push(Diagnostic::error(format!("{path}.tilt"), "tilt is not a finite angle".into()));
+    }
+}
+
+/// Whether the glass of two lenses may intersect. Lenses on a shared axis are
+/// compared surface to surface, anything else by bounding cylinders or spheres.
+fn overlap(a: &Lens, b: &Lens) -> bool {
+    let untilted = a.tilt == [0.0, 0.0] && b.tilt == [0.0, 0.0];
+    let axis_distance = ((a.pos[1] - b.pos[1]).powi(2) + (a.pos[2] - b.pos[2]).powi(2)).sqrt();
+
+    if untilted && axis_distance == 0.0 {
+        let r = a.radius.min(b.radius);
+        return (0..=OVERLAP_SAMPLES).any(|k| {
+            let rho = r * k as f32 / OVERLAP_SAMPLES as f32;
+            let (a0, a1) = extent_at(a, rho);
+            let (b0, b1) = extent_at(b, rho);
+            a0 < b1 && b0 < a1
+        });
+    }
+
+    let (a0, a1) = extent_at(a, a.radius);
+    let (b0, b1) = extent_at(b, b.radius);
+    let (a0, a1) = (a0.min(a.pos[0] + a.vertices().1), a1.max(a.pos[0] + a.vertices().0));
+    let (b0, b1) = (b0.min(b.pos[0] + b.vertices().1), b1.max(b.pos[0] + b.vertices().0));
+
+    if untilted {
+        return a0 < b1 && b0 < a1 && axis_distance < a.radius + b.radius;
+    }
+
+    let bound = |l: &Lens, lo: f32, hi: f32| {
+        let half = (hi - l.pos[0]).abs().max((lo - l.pos[0]).abs());
+        (l.radius.powi(2) + half.powi(2)).sqrt()
+    };
+    let centres = length(&[a.pos[0] - b.pos[0], a.pos[1] - b.pos[1], a.pos[2] - b.pos[2]]);
+    centres < bound(a, a0, a1) + bound(b, b0, b1)
+}
+
+/// The x range of the glass of an untilted lens at a distance `rho` from its
+/// axis
+fn extent_at(lens: &Lens, rho: f32) -> (f32, f32) {
+    let offset = (lens.center_thickness() - lens.left.sag() - lens.right.sag()) / 2.0;
+    (
+        lens.pos[0] - offset - lens.right.height(lens.radius, rho),
+        lens.pos[0] + offset + lens.left.height(lens.radius, rho),
+    )
+}
+
+fn finite(v: &[f32; 3]) -> bool {
+    v.iter().all(|x| x.is_finite())
+}
+
+fn length(v: &[f32; 3]) -> f32 {
+    (v[0].powi(2) + v[1].powi(2) + v[2].powi(2)).sqrt()
+}
+
+/// Fill in the line of every diagnostic from the source of the scene
+pub fn locate_all(diagnostics: &mut [Diagnostic], source: &str) {
+    for d in diagnostics {
+        d.line = locate(source, &d.path);
+    }
+}
+
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+fn steps(path: &str) -> Vec<Step<'_>> {
+    let mut out = vec![];
+    for part in path.split('.') {
+        let (key, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if !key.is_empty() {
+            out.push(Step::Key(key));
+        }
+        while let Some(end) = rest.find(']') {
+            if let Ok(i) = rest[1..end].parse() {
+                out.push(Step::Index(i));
+            }
+            rest = &rest[end + 1..];
+        }
+    }
+
+    out
+}
+
+/// The 1 based line of the value at `path` in a block style yaml document, or
+/// of the closest enclosing value that could be found. Values inside flow
+/// collections are placed on the line the collection starts.
+pub fn locate(source: &str, path: &str) -> Option<usize> {
+    let lines = source.lines()
+        .enumerate()
+        .map(|(i, l)| (i, l.split(" #").next().unwrap_or(l).trim_end()))
+        .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#') && *l != "---")
+        .collect::<Vec<_>>();
+
+    // Search from line `at`, starting at column `col` on that line, within
+    // the block of the node indented by `parent`
+    let mut at = 0;
+    let mut col = 0;
+    let mut parent: Option<usize> = None;
+    let mut found = None;
+
+    let indent_of = |k: usize, col: usize| {
+        let text = &lines[k].1[col.min(lines[k].1.len())..];
+        let trimmed = text.trim_start();
+        (col + text.len() - trimmed.len(), trimmed)
+    };
+
+    for step in steps(path) {
+        match step {
+            Step::Key(key) => {
+                let mut block = None;
+                let mut hit = None;
+                for k in at..lines.len() {
+                    let (indent, text) = indent_of(k, if k == at { col } else { 0 });
+                    if parent.is_some_and(|p| indent <= p) || block.is_some_and(|b| indent < b) {
+                        break;
+                    }
+                    if *block.get_or_insert(indent) != indent {
+                        continue;
+                    }
+                    if let Some((name, rest)) = split_key(text) {
+                        if name == key {
+                            hit = Some((k, indent, rest.is_empty()));
+                            break;
+                        }
+                    }
+                }
+
+                let Some((k, indent, nested)) = hit else { break };
+                found = Some(lines[k].0);
+                if !nested {
+                    break;
+                }
+                at = k + 1;
+                col = 0;
+                parent = Some(indent);
+            }
+            Step::Index(n) => {
+                let mut block = None;
+                let mut count = 0;
+                let mut hit = None;
+                for k in at..lines.len() {
+                    let (indent, text) = indent_of(k, if k == at { col } else { 0 });
+                    let item = text == "-" || text.starts_with("- ");
+                    if parent.is_some_and(|p| indent < p) || block.is_some_and(|b| indent < b) {
+                        break;
+                    }
+                    if block.is_none() && !item {
+                        break;
+                    }
+                    if *block.get_or_insert(indent) != indent {
+                        continue;
+                    }
+                    if !item {
+                        break;
+                    }
+                    if count == n {
+                        hit = Some((k, indent, text[1..].trim_start()));
+                        break;
+                    }
+                    count += 1;
+                }
+
+                let Some((k, indent, rest)) = hit else { break };
+                found = Some(lines[k].0);
+                if rest.is_empty() {
+                    at = k + 1;
+                    col = 0;
+                } else if split_key(rest).is_some() {
+                    at = k;
+                    col = indent + 2;
+                } else {
+                    break;
+                }
+                parent = Some(indent);
+            }
+        }
+    }
+
+    found.map(|i| i + 1)
+}
+
+/// Split `key: value` into the key and the rest of the line
+fn split_key(text: &str) -> Option<(&str, &str)> {
+    let i = text.find(':')?;
+    let (key, rest) = (&text[..i], &text[i + 1..]);
+    let plain = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    (plain && (rest.is_empty() || rest.starts_with(' '))).then(|| (key, rest.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = "\
+version: 5
+# the lenses
+lenses:
+  - radius: 0.5
+    left: !Convex 0.1
+    right: Flat
+    pos: [0, -0.1, 0]
+  -
+    radius: 1
+    left: Flat   # a comment
+    right: !Concave 0.2
+lights:
+  - !Laser [[2, 0, 0], [-1, 0, 0]]
+  - !Point
+    - [1, 2, 3]
+optimize:
+  variables:
+    - { lens: 0, param: Left }
+  targets:
+    - metric: Spot
+      weight: 2
+";
+
+    #[test]
+    fn splits_paths_into_steps() {
+        let steps = steps("lenses[2][3].left")
+            .into_iter()
+            .map(|s| match s {
+                Step::Key(k) => k.to_string(),
+                Step::Index(i) => i.to_string(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(steps, ["lenses", "2", "3", "left"]);
+    }
+
+    #[test]
+    fn splits_plain_keys_only() {
+        assert_eq!(split_key("left: !Convex 0.1"), Some(("left", "!Convex 0.1")));
+        assert_eq!(split_key("variables:"), Some(("variables", "")));
+        assert_eq!(split_key("- radius: 1"), None);
+        assert_eq!(split_key("file: http://example.com"), Some(("file", "http://example.com")));
+        assert_eq!(split_key("a:b"), None);
+        assert_eq!(split_key("!Laser [[2, 0, 0], [-1, 0, 0]]"), None);
+    }
+
+    #[test]
+    fn locates_nested_maps() {
+        assert_eq!(locate(SCENE, "version"), Some(1));
+        assert_eq!(locate(SCENE, "optimize"), Some(16));
+        assert_eq!(locate(SCENE, "optimize.targets"), Some(19));
+        assert_eq!(locate(SCENE, "optimize.targets[0].weight"), Some(21));
+    }
+
+    #[test]
+    fn locates_indexed_sequences() {
+        assert_eq!(locate(SCENE, "lenses[0]"), Some(4));
+        assert_eq!(locate(SCENE, "lenses[0].radius"), Some(4));
+        assert_eq!(locate(SCENE, "lenses[0].right"), Some(6));
+        // An item with nothing after its dash
+        assert_eq!(locate(SCENE, "lenses[1]"), Some(8));
+        assert_eq!(locate(SCENE, "lenses[1].left"), Some(10));
+        assert_eq!(locate(SCENE, "lenses[1].right"), Some(11));
+    }
+
+    #[test]
+    fn locates_flow_collections_on_their_first_line() {
+        assert_eq!(locate(SCENE, "lenses[0].pos"), Some(7));
+        assert_eq!(locate(SCENE, "lenses[0].pos[1]"), Some(7));
+        assert_eq!(locate(SCENE, "optimize.variables[0]"), Some(18));
+        assert_eq!(locate(SCENE, "optimize.variables[0].lens"), Some(18));
+    }
+
+    #[test]
+    fn locates_tagged_values() {
+        assert_eq!(locate(SCENE, "lenses[0].left"), Some(5));
+        assert_eq!(locate(SCENE, "lights[0]"), Some(13));
+        assert_eq!(locate(SCENE, "lights[1]"), Some(14));
+    }
+
+    #[test]
+    fn falls_back_to_the_closest_enclosing_value() {
+        assert_eq!(locate(SCENE, "lenses[0].thickness"), Some(4));
+        assert_eq!(locate(SCENE, "lenses[2]"), Some(3));
+        assert_eq!(locate(SCENE, "lenses[1].tilt[0]"), Some(8));
+        // Keys must match whole, and only within their parent
+        assert_eq!(locate(SCENE, "lens"), None);
+        assert_eq!(locate(SCENE, "radius"), None);
+        assert_eq!(locate(SCENE, "optimize.lenses"), Some(16));
+        assert_eq!(locate(SCENE, "detector"), None);
+    }
+
+    #[test]
+    fn fills_in_the_lines_of_diagnostics() {
+        let mut diagnostics = vec![
+            Diagnostic::error("lenses[1].right".into(), "a".into()),
+            Diagnostic::warning("nowhere".into(), "b".into()),
+        ];
+        locate_all(&mut diagnostics, SCENE);
+        assert_eq!(diagnostics[0].line, Some(11));
+        assert_eq!(diagnostics[1].line, None);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::vulkan::{VertexBuffer, IndexBuffer, NormalBuffer, Uniform};
+use crate::vulkan::VulkanState;
+use crate::world::{World, Model};
+use crate::{Vertex, Normal};
+use crate::error::Result;
+
+use std::f32::consts::PI;
+
+use cgmath::{Point3, Vector4, Matrix4, Rad, Vector3, Matrix3};
+
+/// The geometry of a world uploaded to the gpu and the camera looking at it
+pub struct Viewer {
+    pub vertex_buffer: VertexBuffer,
+    pub index_buffer: IndexBuffer,
+    pub normal_buffer: NormalBuffer,
+
+    /// Tesselated traced segments, stored after the world's model data
+    pub lines: Vec<Model>,
+
+    pub fov: f32,
+    pub rotx: f32,
+    pub roty: f32,
+    pub rotz: f32,
+
+    /// Size of a centimetre in scene units, the scene is drawn shrunk by it
+    /// so the camera frames the enclosure in any unit
+    pub scale: f32,
+}
+
+impl Viewer {
+    pub fn new(world: &World, vulkan: &mut VulkanState, scale: f32) -> Result<Self> {
+        // Turn every traced segment into a thin tube
+        let mut tris = world.model_data.clone();
+        let mut lines = vec![];
+        for mut tube in world.tubes(0.005 * scale) {
+            lines.push(Model { index: tris.len() as u32, count: tube.len() as u32 });
+            tris.append(&mut tube);
+        }
+
+        let vs = tris.iter()
+            .flat_map(|t| t.vertices())
+            .collect::<Vec<Vertex>>();
+
+        let is = tris.iter()
+            .enumerate()
+            .flat_map(|(i, t)| t.indices(i as u32 * 3))
+            .collect::<Vec<u32>>();
+
+        let ns = tris.iter()
+            .flat_map(|t| t.normals())
+            .collect::<Vec<Normal>>();
+
+        let (vertex_buffer, index_buffer, normal_buffer) = vulkan.transfer_object_data(vs, is, ns)?;
+
+        Ok(Viewer {
+            vertex_buffer,
+            index_buffer,
+            normal_buffer,
+
+            lines,
+
+            fov: std::f32::consts::FRAC_PI_2,
+            rotx: 0.0,
+            roty: 0.0,
+            rotz: 0.0,
+
+            scale,
+        })
+    }
+
+    pub fn draw(&self, world: &World, vs: &mut VulkanState) -> Result<()> {
+        // Calculate uniforms for each object
+        let ext = vs.logical_size();
+        let aspect_ratio = ext.0 / ext.1;
+
+        let proj = cgmath::perspective(
+            Rad(self.fov),
+            aspect_ratio,
+            0.01,
+            100.0
+        );
+
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, 1.0, -6.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, -1.0, 0.0),
+        );
+
+        let scale = Matrix4::from_scale(1.0 / self.scale);
+
+        let rotation =
+            Matrix3::from_angle_x(Rad(self.rotx)) *
+            Matrix3::from_angle_y(Rad(self.roty)) *
+            Matrix3::from_angle_z(Rad(self.rotz));
+
+
+        let mut uniforms = Vec::with_capacity(world.models.len());
+
+        for _ in 0..self.lines.len() {
+            let world = Matrix4::from(rotation);
+            let uniform_data = Uniform {
+                world: world.into(),
+                view: (view * scale).into(),
+                proj: proj.into(),
+                o_color: Vector4::new(1.0, 1.0, 1.0, 1.0).into()
+            };
+            uniforms.push(uniform_data);
+        }
+
+        for i in 0..world.models.len() {
+            let model = Matrix4::from(rotation) * world.transforms[i].matrix();
+
+            let uniform_data = Uniform {
+                world: model.into(),
+                view: (view * scale).into(),
+                proj: proj.into(),
+                o_color: world.colors[i].into()
+            };
+            uniforms.push(uniform_data);
+        }
+
+        let models = self.lines.iter().chain(world.models.iter());
+
+        vs.draw(
+            self.vertex_buffer.clone(),
+            self.index_buffer.clone(),
+            self.normal_buffer.clone(),
+            models,
+            &uniforms
+        )
+    }
+
+    pub fn zoom(&mut self, amt: f32) {
+        self.fov = (self.fov + amt).clamp(0.00001, 3.0*PI/4.0);
+    }
+
+    pub fn rotate(&mut self, x: f32, y: f32, z: f32) {
+        self.rotx += x;
+        self.roty += y;
+        self.rotz += z;
+    }
+}
@@ -69,18 +69,16 @@ use winit::window::Window;
 use winit::window::WindowBuilder;
 
 use crate::{Vertex, Normal};
+use crate::world::Model;
+use crate::error::{Error, Result};
 
 pub type VertexBuffer = Arc<CpuBufferPoolChunk<Vertex, Arc<StdMemoryPool>>>;
 pub type IndexBuffer = Arc<CpuBufferPoolChunk<u32, Arc<StdMemoryPool>>>;
 pub type NormalBuffer = Arc<CpuBufferPoolChunk<Normal, Arc<StdMemoryPool>>>;
 pub type Uniform = vs::ty::Data;
 
-#[derive(Debug, Clone)]
-pub struct Model {
-    pub index: u32,
-    pub count: u32
-}
-
+vulkano::impl_vertex!(Vertex, position);
+vulkano::impl_vertex!(Normal, normal);
 
 pub struct VulkanState {
     _instance: Arc<Instance>,
@@ -109,7 +107,7 @@ pub struct VulkanState {
 }
 
 impl VulkanState {
-    pub fn new(event_loop: &EventLoop<()>) -> VulkanState {
+    pub fn new(event_loop: &EventLoop<()>) -> Result<VulkanState> {
         // Required extensions for rendering to a window
         let required_extensions = vulkano_win::required_extensions();
 
@@ -120,7 +118,7 @@ impl VulkanState {
                 enumerate_portability: true,
                 ..Default::default()
             }
-        ).unwrap();
+        ).map_err(Error::vulkan)?;
 
         let device_extensions = DeviceExtensions {
             khr_swapchain: true,
@@ -130,7 +128,7 @@ impl VulkanState {
         let surface = WindowBuilder::new()
             .with_resizable(true)
             .build_vk_surface(&event_loop, instance.clone())
-            .unwrap();
+            .map_err(Error::vulkan)?;
 
         let (physical, queue_family) = PhysicalDevice::enumerate(&instance)
             .filter(|&p| {
@@ -152,7 +150,7 @@ impl VulkanState {
                     PhysicalDeviceType::Other => 4,
                 }
             })
-            .expect("No suitable physical device found");
+            .ok_or_else(|| Error::Vulkan("no suitable physical device found".to_string()))?;
 
         let (device, mut queues) = Device::new(
             physical,
@@ -161,24 +159,23 @@ impl VulkanState {
                 queue_create_infos: vec![QueueCreateInfo::family(queue_family)],
                 ..Default::default()
             }
-        ).unwrap();
+        ).map_err(Error::vulkan)?;
 
         // The only queue we need right now is for rendering, may need transfer queue later
-        let queue = queues.next().unwrap();
+        let queue = queues.next().ok_or_else(|| Error::Vulkan("no graphics queue".to_string()))?;
 
         // Load shaders
-        let vs = vs::load(device.clone()).unwrap();
-        let fs = fs::load(device.clone()).unwrap();
+        let vs = vs::load(device.clone()).map_err(Error::vulkan)?;
+        let fs = fs::load(device.clone()).map_err(Error::vulkan)?;
 
         // Create swapchain
         let (swapchain, images) = {
-            let caps = physical.surface_capabilities(&surface, Default::default()).unwrap();
-            //let composite_alpha = caps.supported_composite_alpha.iter().next().unwrap();
+            let caps = physical.surface_capabilities(&surface, Default::default()).map_err(Error::vulkan)?;
             // Internal format for images
             let format = Some(
                 physical
                     .surface_formats(&surface, Default::default())
-                    .unwrap()[0]
+                    .map_err(Error::vulkan)?[0]
                     .0,
             );
 
@@ -194,10 +191,10 @@ impl VulkanState {
                         .supported_composite_alpha
                         .iter()
                         .next()
-                        .unwrap(),
+                        .ok_or_else(|| Error::Vulkan("no supported composite alpha".to_string()))?,
                     ..Default::default()
                 }
-            ).unwrap()
+            ).map_err(Error::vulkan)?
         };
 
         let dimensions: [u32; 2] = surface.window().inner_size().into();
@@ -228,14 +225,14 @@ impl VulkanState {
                 color: [color],
                 depth_stencil: {depth}
             }
-        ).unwrap();
+        ).map_err(Error::vulkan)?;
 
 
         // Actual framebuffers to draw to
-        let (framebuffers, pipeline) = VulkanState::window_size_dependent_setup(device.clone(), &vs, &fs, &images, render_pass.clone());
+        let (framebuffers, pipeline) = VulkanState::window_size_dependent_setup(device.clone(), &vs, &fs, &images, render_pass.clone())?;
         let previous_frame_end = Some(sync::now(device.clone()).boxed());
 
-        VulkanState {
+        Ok(VulkanState {
             _instance: instance,
             device,
             queue,
@@ -255,7 +252,7 @@ impl VulkanState {
             recreate_swapchain: false,
             previous_frame_end,
             clear_color: [0.605, 0.607, 0.795, 1.0],
-        }
+        })
     }
 
     pub fn draw<'a>(
@@ -265,24 +262,27 @@ impl VulkanState {
         normal_buffer: NormalBuffer,
         mut models: impl Iterator<Item=&'a Model>,
         uniforms: &Vec<vs::ty::Data>
-    ) {
-        self.previous_frame_end.as_mut().unwrap().cleanup_finished();
+    ) -> Result<()> {
+        if let Some(previous) = self.previous_frame_end.as_mut() {
+            previous.cleanup_finished();
+        }
 
         if self.recreate_swapchain {
-            self.recreate_swapchain();
+            self.recreate_swapchain()?;
         }
 
         // Descriptor set
-        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let layout = self.pipeline.layout().set_layouts().get(0)
+            .ok_or_else(|| Error::Vulkan("pipeline has no descriptor set layout".to_string()))?;
         // Acquire image from swapchain
         let (image_num, suboptimal, acquire_future) =
             match swapchain::acquire_next_image(self.swapchain.clone(), None) {
                 Ok(r) => r,
                 Err(AcquireError::OutOfDate) => {
                     self.recreate_swapchain = true;
-                    return;
+                    return Ok(());
                 }
-                Err(e) => panic!("Failed to acquire next image: {:?}", e),
+                Err(e) => return Err(Error::vulkan(e)),
             };
 
         if suboptimal {
@@ -294,7 +294,7 @@ impl VulkanState {
             self.queue.family(),
             CommandBufferUsage::OneTimeSubmit,
         )
-        .unwrap();
+        .map_err(Error::vulkan)?;
     
         builder
             .begin_render_pass(
@@ -303,14 +303,14 @@ impl VulkanState {
                     ..RenderPassBeginInfo::framebuffer(self.framebuffers[image_num].clone())
                 },
                 SubpassContents::Inline,
-            ).unwrap()
+            ).map_err(Error::vulkan)?
             .bind_pipeline_graphics(self.pipeline.clone())
             .bind_vertex_buffers(0, (vertex_buffer.clone(), normal_buffer.clone()))
             .bind_index_buffer(index_buffer.clone());
 
         for i in 0..uniforms.len() {
             let uniform_buffer_subbuffer = {
-                self.uniform_buffer_pool.next(uniforms[i]).unwrap()
+                self.uniform_buffer_pool.next(uniforms[i]).map_err(Error::vulkan)?
             };
 
             let set = PersistentDescriptorSet::new(
@@ -318,9 +318,10 @@ impl VulkanState {
                 [
                     WriteDescriptorSet::buffer(0, uniform_buffer_subbuffer),
                 ]
-            ).unwrap();
+            ).map_err(Error::vulkan)?;
 
-            let model = models.next().unwrap();
+            let model = models.next()
+                .ok_or_else(|| Error::Vulkan("fewer models than uniforms".to_string()))?;
 
             builder
             .bind_descriptor_sets(
@@ -330,21 +331,21 @@ impl VulkanState {
                 set.clone()
             )
             .draw_indexed(model.count * 3, 1, model.index * 3, 0, 0)
-            .unwrap();
+            .map_err(Error::vulkan)?;
 
         }
 
         builder
             .end_render_pass()
-            .unwrap();
-        let command_buffer = builder.build().unwrap();
+            .map_err(Error::vulkan)?;
+        let command_buffer = builder.build().map_err(Error::vulkan)?;
 
         let future = self.previous_frame_end
             .take()
-            .unwrap()
+            .unwrap_or_else(|| sync::now(self.device.clone()).boxed())
             .join(acquire_future)
             .then_execute(self.queue.clone(), command_buffer)
-            .unwrap()
+            .map_err(Error::vulkan)?
             .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_num)
             .then_signal_fence_and_flush();
         
@@ -361,13 +362,15 @@ impl VulkanState {
                 self.previous_frame_end = Some(sync::now(self.device.clone()).boxed());
             }
         }
+
+        Ok(())
     }
 
-    pub fn transfer_object_data<I, J, K>(&mut self, vertices: I, indices: J, normals: K) -> (
+    pub fn transfer_object_data<I, J, K>(&mut self, vertices: I, indices: J, normals: K) -> Result<(
         VertexBuffer,
         IndexBuffer,
         NormalBuffer
-    )
+    )>
     where
         I: IntoIterator<Item = Vertex>,
         I::IntoIter: ExactSizeIterator,
@@ -376,14 +379,14 @@ impl VulkanState {
         K: IntoIterator<Item = Normal>,
         K::IntoIter: ExactSizeIterator,
     {
-        let vertex_buffer = self.vertex_buffer_pool.chunk(vertices).unwrap();
-        let index_buffer = self.index_buffer_pool.chunk(indices).unwrap();
-        let normal_buffer = self.normal_buffer_pool.chunk(normals).unwrap();
+        let vertex_buffer = self.vertex_buffer_pool.chunk(vertices).map_err(Error::vulkan)?;
+        let index_buffer = self.index_buffer_pool.chunk(indices).map_err(Error::vulkan)?;
+        let normal_buffer = self.normal_buffer_pool.chunk(normals).map_err(Error::vulkan)?;
 
-        (vertex_buffer, index_buffer, normal_buffer)
+        Ok((vertex_buffer, index_buffer, normal_buffer))
     }
 
-    pub fn recreate_swapchain(&mut self) {
+    pub fn recreate_swapchain(&mut self) -> Result<()> {
         // Get the new dimensions of the window.
         self.dimensions = self.surface.window().inner_size().into();
         let (new_swapchain, new_images) =
@@ -394,15 +397,17 @@ impl VulkanState {
                 Ok(r) => r,
                 // This error tends to happen when the user is manually resizing the window.
                 // Simply restarting the loop is the easiest way to fix this issue.
-                Err(SwapchainCreationError::ImageExtentNotSupported { .. }) => return,
-                Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
+                Err(SwapchainCreationError::ImageExtentNotSupported { .. }) => return Ok(()),
+                Err(e) => return Err(Error::vulkan(e)),
             };
 
         self.swapchain = new_swapchain;
         // Because framebuffers contains an Arc on the old swapchain, we need to
         // recreate framebuffers as well.
-        (self.framebuffers, self.pipeline) = VulkanState::window_size_dependent_setup(self.device.clone(), &self.vs, &self.fs, &new_images,self.render_pass.clone());
+        (self.framebuffers, self.pipeline) = VulkanState::window_size_dependent_setup(self.device.clone(), &self.vs, &self.fs, &new_images,self.render_pass.clone())?;
         self.recreate_swapchain = false;
+
+        Ok(())
     }
 
     fn window_size_dependent_setup(
@@ -411,24 +416,24 @@ impl VulkanState {
         fs: &ShaderModule,
         images: &[Arc<SwapchainImage<Window>>],
         render_pass: Arc<RenderPass>,
-    ) -> (Vec<Arc<Framebuffer>>, Arc<GraphicsPipeline>) {
+    ) -> Result<(Vec<Arc<Framebuffer>>, Arc<GraphicsPipeline>)> {
         let dimensions: [u32; 2] = images[0].dimensions().width_height();
 
         let depth_buffer = ImageView::new_default(
-            AttachmentImage::transient(device.clone(), dimensions, Format::D16_UNORM).unwrap()
-        ).unwrap();
+            AttachmentImage::transient(device.clone(), dimensions, Format::D16_UNORM).map_err(Error::vulkan)?
+        ).map_err(Error::vulkan)?;
 
         let fbs = images.iter()
             .map(|image| {
-                let view = ImageView::new_default(image.clone()).unwrap();
+                let view = ImageView::new_default(image.clone()).map_err(Error::vulkan)?;
                 Framebuffer::new(
                     render_pass.clone(),
                     FramebufferCreateInfo {
                         attachments: vec![view, depth_buffer.clone()],
                         ..Default::default()
                     }
-                ).unwrap()
-            }).collect::<Vec<_>>();
+                ).map_err(Error::vulkan)
+            }).collect::<Result<Vec<_>>>()?;
 
         let pipeline = GraphicsPipeline::start()
         .vertex_input_state(
@@ -436,7 +441,7 @@ impl VulkanState {
                 .vertex::<Vertex>()
                 .vertex::<Normal>(),
         )
-        .vertex_shader(vs.entry_point("main").unwrap(), ())
+        .vertex_shader(vs.entry_point("main").ok_or_else(|| Error::Vulkan("vertex shader has no main".to_string()))?, ())
         .input_assembly_state(InputAssemblyState::new())
         .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant([
             Viewport {
@@ -445,15 +450,16 @@ impl VulkanState {
                 depth_range: 0.0..1.0,
             },
         ]))
-        .fragment_shader(fs.entry_point("main").unwrap(), ())
+        .fragment_shader(fs.entry_point("main").ok_or_else(|| Error::Vulkan("fragment shader has no main".to_string()))?, ())
         .depth_stencil_state(DepthStencilState::simple_depth_test())
         .color_blend_state(ColorBlendState::default().blend_alpha())
         .rasterization_state(RasterizationState::new().cull_mode(CullMode::Back))
-        .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
+        .render_pass(Subpass::from(render_pass.clone(), 0)
+            .ok_or_else(|| Error::Vulkan("render pass has no subpass".to_string()))?)
         .build(device.clone())
-        .unwrap();
+        .map_err(Error::vulkan)?;
 
-        (fbs, pipeline)
+        Ok((fbs, pipeline))
     }
 
     pub fn logical_size(&self) -> (f32, f32) {
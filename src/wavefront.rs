@@ -0,0 +1,260 @@
+use crate::lenses::Lens;
+use crate::linalg::least_squares;
+use crate::paraxial::System;
+use crate::world::World;
+
+use cgmath::prelude::*;
+use cgmath::Vector3;
+use cgmath::dot;
+
+use std::io::{self, Write};
+
+/// Optical path of a single ray up to the exit reference sphere
+#[derive(Debug, Clone, Copy)]
+pub struct WavefrontSample {
+    /// Position of the ray in the plane of the first vertex, relative to the
+    /// lens radius
+    pub pupil: [f32; 2],
+    /// Optical path length from the light to the reference sphere
+    pub opl: f32,
+    /// Optical path difference with piston and tilt removed
+    pub opd: f32,
+}
+
+/// The wavefront leaving a system, sampled on a sphere around the image point
+pub struct Wavefront {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+    pub samples: Vec<WavefrontSample>,
+}
+
+impl Wavefront {
+    pub fn rms(&self) -> f32 {
+        let n = self.samples.len().max(1) as f32;
+        (self.samples.iter().map(|s| s.opd * s.opd).sum::<f32>() / n).sqrt()
+    }
+
+    pub fn peak_to_valley(&self) -> f32 {
+        let (lo, hi) = self.samples.iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), s| (lo.min(s.opd), hi.max(s.opd)));
+
+        (hi - lo).max(0.0)
+    }
+
+    /// Fit the first `terms` Zernike polynomials in Noll order over the
+    /// sampled pupil, scaled to the unit disk
+    pub fn zernike(&self, terms: usize) -> Vec<f32> {
+        let rmax = self.samples.iter()
+            .map(|s| (s.pupil[0] as f64).hypot(s.pupil[1] as f64))
+            .fold(0.0, f64::max);
+        if rmax == 0.0 || terms == 0 {
+            return vec![0.0; terms];
+        }
+
+        let rows = self.samples.iter()
+            .map(|s| {
+                let (x, y) = (s.pupil[0] as f64 / rmax, s.pupil[1] as f64 / rmax);
+                let (rho, theta) = (x.hypot(y), y.atan2(x));
+                (1..=terms).map(|j| zernike(j, rho, theta)).collect()
+            })
+            .collect::<Vec<_>>();
+        let values = self.samples.iter().map(|s| s.opd as f64).collect::<Vec<_>>();
+
+        least_squares(&rows, &values, 0.0)
+            .map(|c| c.into_iter().map(|c| c as f32).collect())
+            .unwrap_or_else(|| vec![0.0; terms])
+    }
+}
+
+/// Radial and azimuthal order of the Zernike polynomial with Noll index `j`
+pub fn noll(j: usize) -> (usize, i32) {
+    let mut n = 0;
+    let mut j1 = j - 1;
+    while j1 > n {
+        n += 1;
+        j1 -= n;
+    }
+
+    // Even indices take the cosine terms and odd the sine terms
+    let m = ((n % 2) + 2 * ((j1 + (n + 1) % 2) / 2)) as i32;
+    match j % 2 {
+        0 => (n, m),
+        _ => (n, -m),
+    }
+}
+
+/// Orthonormal Zernike polynomial with Noll index `j`
+pub fn zernike(j: usize, rho: f64, theta: f64) -> f64 {
+    let (n, m) = noll(j);
+    let ma = m.unsigned_abs() as usize;
+
+    let fact = |k: usize| (1..=k).map(|i| i as f64).product::<f64>();
+    let radial = (0..=(n - ma) / 2)
+        .map(|k| {
+            (-1.0f64).powi(k as i32) * fact(n - k) / (fact(k) * fact((n + ma) / 2 - k) * fact((n - ma) / 2 - k))
+                * rho.powi((n - 2 * k) as i32)
+        })
+        .sum::<f64>();
+
+    match m {
+        0 => ((n + 1) as f64).sqrt() * radial,
+        m if m > 0 => (2.0 * (n + 1) as f64).sqrt() * radial * (ma as f64 * theta).cos(),
+        _ => (2.0 * (n + 1) as f64).sqrt() * radial * (ma as f64 * theta).sin(),
+    }
+}
+
+/// Sample the wavefront of a collimated field traced through the system on a
+/// sphere centred on the image point `center` through the last vertex
+pub fn wavefront(world: &World, lenses: &[Lens], system: &System, center: Vector3<f32>) -> Option<Wavefront> {
+    let first = &lenses[system.order[0]];
+    let vertex = Vector3::new(system.rear, first.pos[1], first.pos[2]);
+    let radius = (center - vertex).magnitude();
+
+    let mut samples = world.paths.iter()
+        .filter_map(|path| {
+            // Pupil position where the ray crosses the plane of the first vertex
+            let first_ray = path.segments.first()?.ray;
+            let start = first_ray.origin
+                + first_ray.dir * ((system.front - first_ray.origin.x) / first_ray.dir.x);
+            let exit = path.exit()?;
+            let last = path.segments[exit].parent?;
+            let exit = path.segments[exit].ray;
+
+            // Extend the exit ray to the nearest crossing of the sphere
+            let oc = exit.origin - center;
+            let b = dot(exit.dir, oc) as f64;
+            let c = (oc.magnitude2() - radius * radius) as f64;
+            let disc = b * b - c;
+            if disc < 0.0 {
+                return None;
+            }
+            let (t0, t1) = (-b - disc.sqrt(), -b + disc.sqrt());
+            let t = if t0.abs() < t1.abs() { t0 } else { t1 };
+
+            let opl = path.ancestry(last).into_iter()
+                .map(|i| path.segments[i].optical_length() as f64)
+                .sum::<f64>() + t;
+
+            Some(WavefrontSample {
+                pupil: [
+                    (start.y - first.pos[1]) / first.radius,
+                    (start.z - first.pos[2]) / first.radius,
+                ],
+                opl: opl as f32,
+                opd: opl as f32,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if samples.len() < 3 {
+        return None;
+    }
+
+    // Remove piston and tilt
+    let mean = samples.iter().map(|s| s.opl as f64).sum::<f64>() / samples.len() as f64;
+    let rows = samples.iter()
+        .map(|s| vec![1.0, s.pupil[0] as f64, s.pupil[1] as f64])
+        .collect::<Vec<_>>();
+    let values = samples.iter().map(|s| s.opl as f64 - mean).collect::<Vec<_>>();
+    let fit = least_squares(&rows, &values, 0.0)?;
+
+    for (s, v) in samples.iter_mut().zip(values) {
+        let plane = fit[0] + fit[1] * s.pupil[0] as f64 + fit[2] * s.pupil[1] as f64;
+        s.opd = (v - plane) as f32;
+    }
+
+    Some(Wavefront { center, radius, samples })
+}
+
+pub fn write_csv<W: Write>(wf: &Wavefront, mut w: W) -> io::Result<()> {
+    writeln!(w, "x,y,opl,opd")?;
+    for s in wf.samples.iter() {
+        writeln!(w, "{},{},{},{}", s.pupil[0], s.pupil[1], s.opl, s.opd)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn noll_indices_follow_noll_order() {
+        let orders = (1..=15).map(noll).collect::<Vec<_>>();
+        assert_eq!(orders, [
+            (0, 0),
+            (1, 1), (1, -1),
+            (2, 0), (2, -2), (2, 2),
+            (3, -1), (3, 1), (3, -3), (3, 3),
+            (4, 0), (4, 2), (4, -2), (4, 4), (4, -4),
+        ]);
+    }
+
+    #[test]
+    fn zernike_polynomials_are_orthonormal_on_the_unit_disk() {
+        let terms = 15;
+        let (nr, nt) = (200, 200);
+        let mut gram = vec![vec![0.0; terms]; terms];
+
+        // Midpoint rule in polar coordinates, normalised by the disk's area
+        for a in 0..nr {
+            let rho = (a as f64 + 0.5) / nr as f64;
+            for b in 0..nt {
+                let theta = 2.0 * PI * (b as f64 + 0.5) / nt as f64;
+                let z = (1..=terms).map(|j| zernike(j, rho, theta)).collect::<Vec<_>>();
+                let w = rho * (1.0 / nr as f64) * (2.0 * PI / nt as f64) / PI;
+                for (row, zi) in gram.iter_mut().zip(&z) {
+                    for (g, zj) in row.iter_mut().zip(&z) {
+                        *g += zi * zj * w;
+                    }
+                }
+            }
+        }
+
+        for (i, row) in gram.iter().enumerate() {
+            for (j, g) in row.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((g - expected).abs() < 1e-3, "<Z{}, Z{}> = {g}", i + 1, j + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn fits_the_terms_a_wavefront_is_made_of() {
+        let n = 21;
+        let samples = (0..n).flat_map(|i| (0..n).map(move |j| (i, j)))
+            .map(|(i, j)| [2.0 * i as f32 / (n - 1) as f32 - 1.0, 2.0 * j as f32 / (n - 1) as f32 - 1.0])
+            .filter(|p| p[0].hypot(p[1]) <= 1.0)
+            .map(|pupil| {
+                let (x, y) = (pupil[0] as f64, pupil[1] as f64);
+                let (rho, theta) = (x.hypot(y), y.atan2(x));
+                let opd = (0.3 * zernike(4, rho, theta) - 0.1 * zernike(8, rho, theta)) as f32;
+                WavefrontSample { pupil, opl: opd, opd }
+            })
+            .collect();
+        let wf = Wavefront { center: Vector3::new(0.0, 0.0, 0.0), radius: 1.0, samples };
+
+        let c = wf.zernike(11);
+        for (j, &c) in c.iter().enumerate() {
+            let expected = match j + 1 {
+                4 => 0.3,
+                8 => -0.1,
+                _ => 0.0,
+            };
+            assert!((c - expected).abs() < 1e-3, "Z{}: {c}", j + 1);
+        }
+    }
+
+    #[test]
+    fn measures_the_spread_of_the_path_difference() {
+        let samples = [-0.2, 0.1, 0.1, 0.0]
+            .map(|opd| WavefrontSample { pupil: [0.0, 0.0], opl: 1.0 + opd, opd })
+            .to_vec();
+        let wf = Wavefront { center: Vector3::new(0.0, 0.0, 0.0), radius: 1.0, samples };
+
+        assert!((wf.rms() - (0.06f32 / 4.0).sqrt()).abs() < 1e-6);
+        assert!((wf.peak_to_valley() - 0.3).abs() < 1e-6);
+    }
+}
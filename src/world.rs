@@ -1,56 +1,166 @@
-use crate::vulkan::{VertexBuffer, IndexBuffer, NormalBuffer, Uniform};
-use crate::{Vertex, Normal};
-use crate::vulkan::VulkanState;
-use crate::geometry::Triangle;
+use crate::geometry::{Triangle, Transform};
 use crate::geometry::Ray;
 
 use cgmath::prelude::*;
 
-use crate::vulkan::Model;
 use crate::kdtree::KDNode;
 use crate::kdtree::build_kdtree;
 use crate::light::Light;
+use crate::error::{Error, Result};
 
-use std::f32::consts::PI;
-
-use cgmath::{Point3, Vector4, Matrix4, Rad, Vector3, Matrix3};
+use cgmath::{Vector4, Vector3};
 use cgmath::dot;
 
-#[derive(Debug, PartialEq)]
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+/// Most segments traced for a single spawned ray
+const MAX_SEGMENTS: usize = 64;
+
+/// A range of triangles in the world's model data
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub index: u32,
+    pub count: u32
+}
+
+/// How a surface treats the rays that hit it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum Material {
     Solid,
     Mirror,
+    /// Glass of the given refractive index
     Glass(f32),
 }
 
+impl Material {
+    /// Colour entities of the material are drawn in
+    pub fn color(&self) -> Vector4<f32> {
+        match self {
+            Self::Solid => Vector4::new(0.2, 0.2, 0.2, 1.0),
+            Self::Mirror => Vector4::new(0.8, 0.8, 0.8, 1.0),
+            Self::Glass(_) => Vector4::new(0.209, 0.282, 0.686, 0.4),
+        }
+    }
+}
+
+/// What happens to a ray at the end of a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Event {
+    /// Passes into or out of glass
+    Refract,
+    /// Bounces off a mirror
+    Reflect,
+    /// Reflects off the inside of glass at too steep an angle to leave it
+    TotalInternalReflection,
+    /// Stops on a solid surface
+    Absorbed,
+    /// Leaves the scene without hitting anything
+    Escaped,
+}
+
+/// A straight piece of a traced ray, from its origin to where it hits
+/// `entity`, or to infinity when it escapes
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub ray: Ray,
+    pub length: f32,
+    /// Segment of the same path this one continues, none for the spawned ray
+    pub parent: Option<usize>,
+    pub entity: Option<usize>,
+    /// Index of the hit triangle among the world triangles
+    pub triangle: Option<usize>,
+    /// Normal of the hit surface, facing against the ray
+    pub normal: Option<Vector3<f32>>,
+    pub event: Event,
+    /// Glass entity the segment travels through, none in air
+    pub medium: Option<usize>,
+    /// Refractive index of the medium the segment travels through
+    pub index: f32,
+}
+
+impl Segment {
+    pub fn end(&self) -> Vector3<f32> {
+        self.ray.origin + self.ray.dir * self.length
+    }
+
+    pub fn optical_length(&self) -> f32 {
+        self.index * self.length
+    }
+}
+
+/// The chain of segments traced from a single ray spawned by `lights[light]`.
+/// The spawned ray is the first segment and every other segment continues
+/// the one before it, its parent, so the last segment is where the ray ends.
+#[derive(Debug, Clone)]
+pub struct RayPath {
+    pub light: usize,
+    pub segments: Vec<Segment>,
+}
+
+impl RayPath {
+    /// The segment continuing segment `i`
+    pub fn next(&self, i: usize) -> Option<usize> {
+        (i + 1 < self.segments.len()).then_some(i + 1)
+    }
+
+    /// Segments from the spawned ray down to segment `i`
+    pub fn ancestry(&self, i: usize) -> Vec<usize> {
+        let mut chain = vec![i];
+        while let Some(p) = self.segments[*chain.last().unwrap()].parent {
+            chain.push(p);
+        }
+
+        chain.reverse();
+        chain
+    }
+
+    /// The segment where the ray ends
+    pub fn last(&self) -> Option<usize> {
+        self.segments.len().checked_sub(1)
+    }
+
+    /// Accumulated optical path length from the spawned ray to the end of
+    /// segment `i`
+    pub fn optical_length_to(&self, i: usize) -> f32 {
+        self.ancestry(i).into_iter().map(|j| self.segments[j].optical_length()).sum()
+    }
+
+    /// The last segment leaving glass, the ray that comes out of the optics
+    pub fn exit(&self) -> Option<usize> {
+        let last = self.segments.iter()
+            .rposition(|s| s.event == Event::Refract && s.medium.is_some())?;
+
+        self.next(last)
+    }
+}
+
 pub struct World {
     // Per entity
     pub models: Vec<Model>,
     pub colors: Vec<Vector4<f32>>,
-    pub positions: Vec<Vector3<f32>>,
-    pub scales: Vec<Vector3<f32>>,
+    pub transforms: Vec<Transform>,
     pub materials: Vec<Material>,
 
-    // Ray rendering
-    pub lines: Vec<Model>,
+    // Results of the last trace
+    pub paths: Vec<RayPath>,
 
     // per model
     pub model_idx: Vec<Model>,
     pub model_data: Vec<Triangle>,
 
-    // global
-    pub vertex_buffer: Option<VertexBuffer>,
-    pub index_buffer: Option<IndexBuffer>,
-    pub normal_buffer: Option<NormalBuffer>,
-
     pub lights: Vec<Light>,
 
     pub kdtree: Option<KDNode>,
 
-    pub fov: f32,
-    pub rotx: f32,
-    pub roty: f32,
-    pub rotz: f32
+    /// Length escaped rays are drawn to
+    pub far: f32,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl World {
@@ -58,40 +168,31 @@ impl World {
         World {
             models: vec![],
             colors: vec![],
-            positions: vec![],
-            scales: vec![],
+            transforms: vec![],
             materials: vec![],
             
-            lines: vec![],
+            paths: vec![],
 
             model_data: vec![],
             model_idx: vec![],
-            vertex_buffer: None,
-            index_buffer: None,
-            normal_buffer: None,
 
             lights: vec![],
             kdtree: None,
 
-            fov: std::f32::consts::FRAC_PI_2,
-            rotx: 0.0,
-            roty: 0.0,
-            rotz: 0.0,
+            far: 10.0,
         }
     }
 
     pub fn add_entity(
         &mut self,
         model: Model,
-        position: Vector3<f32>,
+        transform: Transform,
         material: Material,
-        scale: Vector3<f32>,
         color: Vector4<f32>,
     ) -> usize {
         self.models.push(model);
         self.colors.push(color);
-        self.positions.push(position);
-        self.scales.push(scale);
+        self.transforms.push(transform);
         self.materials.push(material);
 
         self.models.len() - 1
@@ -118,170 +219,94 @@ impl World {
         self.kdtree = Some(build_kdtree(&tris));
     }
 
-    pub fn upload_models(&mut self, vulkan: &mut VulkanState) {
-        let vs = self.model_data.iter()
-            .map(|t| t.vertices())
-            .flatten()
-            .collect::<Vec<Vertex>>();
-
-        let is = self.model_data.iter()
-            .enumerate()
-            .map(|(i, t)| t.indices(i as u32 * 3))
-            .flatten()
-            .collect::<Vec<u32>>();
-
-        let ns = self.model_data.iter()
-            .map(|t| t.normals())
-            .flatten()
-            .collect::<Vec<Normal>>();
-
-        let (vb, ib, nb) = vulkan.transfer_object_data(vs, is, ns);
-        self.vertex_buffer = Some(vb);
-        self.index_buffer = Some(ib);
-        self.normal_buffer = Some(nb);
-    }
-
-    pub fn model_from_tri(&self, tri: u32) -> usize {
+    /// The entity a triangle of `world_tris` belongs to, if any
+    pub fn model_from_tri(&self, tri: u32) -> Option<usize> {
         let mut idx = 0;
         for (i, m) in self.models.iter().enumerate() {
             if idx <= tri && tri < idx+m.count {
-                return i;
+                return Some(i);
             }
 
             idx += m.count;
         }
 
-        panic!("no model with tri: {tri}");
+        None
     }
 
+    /// The triangles of every entity placed by its transform
     pub fn world_tris(&self) -> Vec<Triangle> {
         let mut tris = vec![];
         for i in 0..self.models.len() {
             let Model { index, count } = self.models[i];
 
             for j in index..index+count {
-                let mut t = self.model_data[j as usize].clone();
-                t.v0 += self.positions[i];
-                t.v1 += self.positions[i];
-                t.v2 += self.positions[i];
-
-                tris.push(t);
+                tris.push(self.transforms[i].triangle(&self.model_data[j as usize]));
             }
         }
 
         tris
     }
 
-    pub fn draw(&mut self, vs: &mut VulkanState) {
-        // Calculate uniforms for each object
-        let ext = vs.logical_size();
-        let aspect_ratio = ext.0 as f32 / ext.1 as f32;
-
-        let proj = cgmath::perspective(
-            Rad(self.fov),
-            aspect_ratio,
-            0.01,
-            100.0
-        );
-
-        let view = Matrix4::look_at_rh(
-            Point3::new(0.0, 1.0, -6.0),
-            Point3::new(0.0, 0.0, 0.0),
-            Vector3::new(0.0, -1.0, 0.0),
-        );
-
-        let scale = Matrix4::from_scale(1.00);
-
-        let rotation =
-            Matrix3::from_angle_x(Rad(self.rotx)) *
-            Matrix3::from_angle_y(Rad(self.roty)) *
-            Matrix3::from_angle_z(Rad(self.rotz));
-
-
-        let mut uniforms = Vec::with_capacity(self.models.len());
-
-        for _ in 0..self.lines.len() {
-            let world = Matrix4::from(rotation);
-            let uniform_data = Uniform {
-                world: world.into(),
-                view: (view * scale).into(),
-                proj: proj.into(),
-                o_color: Vector4::new(1.0, 1.0, 1.0, 1.0).into()
-            };
-            uniforms.push(uniform_data);
-        }
-
-        for i in 0..self.models.len() {
-            let mscale = self.scales[i];
-            let mscale = Matrix4::from_nonuniform_scale(mscale.x, mscale.y, mscale.z);
-
-            let world = 
-                Matrix4::from(rotation)
-                * Matrix4::from_translation(self.positions[i])
-                * mscale;
-
-            let uniform_data = Uniform {
-                world: world.into(),
-                view: (view * scale).into(),
-                proj: proj.into(),
-                o_color: self.colors[i].into()
-            };
-            uniforms.push(uniform_data);
-        }
-
-        let models = self.lines.iter().chain(self.models.iter());
-
-        vs.draw(
-            self.vertex_buffer.as_ref().unwrap().clone(),
-            self.index_buffer.as_ref().unwrap().clone(),
-            self.normal_buffer.as_ref().unwrap().clone(),
-            models,
-            &uniforms
-        );
-    }
-
-    pub fn zoom(&mut self, amt: f32) {
-        self.fov = (self.fov + amt)
-            .max(0.00001)
-            .min(3.0*PI/4.0);
-    }
-
-    pub fn rotate(&mut self, x: f32, y: f32, z: f32) {
-        self.rotx += x;
-        self.roty += y;
-        self.rotz += z;
+    /// A square tube `2 * w` wide along every traced segment, escaped
+    /// segments `far` long
+    pub fn tubes(&self, w: f32) -> impl Iterator<Item = Vec<Triangle>> + '_ {
+        self.paths.iter()
+            .flat_map(|p| p.segments.iter())
+            .map(move |s| {
+                let length = if s.event == Event::Escaped { self.far } else { s.length };
+                s.ray.tesselate(length, w)
+            })
     }
 
     pub fn add_light(&mut self, l: Light) {
         self.lights.push(l);
     }
 
-    pub fn intersect(&self, ray: &Ray, ts: &Vec<Triangle>) -> Option<(usize, usize, f32)> {
-        let kdtree = self.kdtree.as_ref().unwrap();
-        let (ti, d) = kdtree.intersect(ray, ts)?;
+    /// The entity, triangle and distance of the first hit of `ray`, if it
+    /// hits anything
+    pub fn intersect(&self, ray: &Ray, ts: &Vec<Triangle>) -> Result<Option<(usize, usize, f32)>> {
+        let kdtree = self.kdtree.as_ref().ok_or(Error::NoKdTree)?;
+        let Some((ti, d)) = kdtree.intersect(ray, ts) else {
+            return Ok(None);
+        };
 
-        let mi = self.model_from_tri(ti as u32);
-        Some((mi, ti, d))
+        let mi = self.model_from_tri(ti as u32)
+            .ok_or_else(|| Error::Scene(format!("triangle {ti} hit by a ray belongs to no entity")))?;
+        Ok(Some((mi, ti, d)))
     }
 
-    pub fn trace(&mut self) {
+    /// Trace every light through the scene, replacing `paths`. The kd-tree
+    /// must have been built.
+    pub fn trace(&mut self) -> Result<()> {
+        if self.kdtree.is_none() {
+            return Err(Error::NoKdTree);
+        }
+
+        self.paths.clear();
+
         let tris = self.world_tris();
 
-        for light in self.lights.clone().into_iter() {
+        for (li, light) in self.lights.clone().into_iter().enumerate() {
             match light {
                 Light::Laser(_, _) => {
                     // For a laser spawn a single ray and always render it
                     let r = light.spawn();
-                    self.trace_ray(&r, &tris);
+                    let mut segments = vec![];
+                    self.trace_ray(&r, &tris, None, None, &mut segments)?;
+                    self.paths.push(RayPath { light: li, segments });
                 }
                 Light::Point(_) => {
                     // for a point light shoot out a bunch of rays, only
                     // displaying those that hit a lens or reflector
                     for _ in 0..1000 {
                         let r = light.spawn();
-                        if let Some((mi, _, _)) = self.intersect(&r, &tris) {
+                        if let Some((mi, _, _)) = self.intersect(&r, &tris)? {
                             match self.materials[mi] {
-                                Material::Glass(_) | Material::Mirror => self.trace_ray(&r, &tris),
+                                Material::Glass(_) | Material::Mirror => {
+                                    let mut segments = vec![];
+                                    self.trace_ray(&r, &tris, None, None, &mut segments)?;
+                                    self.paths.push(RayPath { light: li, segments });
+                                }
                                 _ => {}
                             }
                         }
@@ -289,56 +314,154 @@ impl World {
                 }
             }
         }
+
+        Ok(())
     }
 
-    // trace a ray, always adding it when it hits something
-    fn trace_ray(&mut self, r: &Ray, tris: &Vec<Triangle>) {
-        if let Some((mi, ti, d)) = self.intersect(r, tris) {
-            self.add_ray(r, d);
-            let t = &tris[ti];
-            let n = t.normals()[0];
-            let n: Vector3<f32> = n.normal.into();
-
-            let inside = r.inside;
-
-            // Trace the rest
-            match self.materials[mi] {
-                Material::Glass(eta) => {
-                    let cos_theta = dot(-r.dir, n).max(1.0);
-                    let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
-
-                    let etai_over_etat = if inside {
-                        eta
-                    } else {
-                        1.0 / eta
-                    };
-
-                    if sin_theta * etai_over_etat > 1.0 {
-                        return;
-                    }
+    /// Trace a ray travelling through `medium`, adding a segment for it and
+    /// for everything it turns into
+    fn trace_ray(&self, r: &Ray, tris: &Vec<Triangle>, medium: Option<usize>, parent: Option<usize>, segments: &mut Vec<Segment>) -> Result<()> {
+        let index = match medium.map(|m| &self.materials[m]) {
+            Some(Material::Glass(eta)) => *eta,
+            _ => 1.0,
+        };
+
+        let Some((mi, ti, d)) = self.intersect(r, tris)? else {
+            segments.push(Segment {
+                ray: *r,
+                length: f32::INFINITY,
+                parent,
+                entity: None,
+                triangle: None,
+                normal: None,
+                event: Event::Escaped,
+                medium,
+                index,
+            });
+            return Ok(());
+        };
 
+        let n = tris[ti].normal();
+
+        // Face the normal against the incoming ray, whichever way the
+        // triangle happens to be wound
+        let n = if dot(r.dir, n) > 0.0 { -n } else { n };
+
+        let hit = r.origin + r.dir * d;
+        let reflected = r.dir - 2.0 * dot(r.dir, n) * n;
+
+        // Work out what happens at the hit and where the ray goes next
+        let (event, next) = match self.materials[mi] {
+            Material::Glass(eta) => {
+                let inside = r.inside;
+                let cos_theta = dot(-r.dir, n).min(1.0);
+                let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
+
+                let etai_over_etat = if inside {
+                    eta
+                } else {
+                    1.0 / eta
+                };
+
+                if sin_theta * etai_over_etat > 1.0 {
+                    let mut r = Ray::new(hit, reflected);
+                    r.inside = inside;
+                    (Event::TotalInternalReflection, Some((r, medium)))
+                } else {
                     let r_out_perp = etai_over_etat * (r.dir + cos_theta*n);
                     let r_out_para = -(1.0 - r_out_perp.magnitude2()).abs().sqrt() * n;
 
-                    let mut r = Ray::new(
-                        r.origin + r.dir * d,
-                        r_out_perp + r_out_para
-                    );
+                    let mut r = Ray::new(hit, r_out_perp + r_out_para);
                     r.inside = !inside;
-
-                    self.trace_ray(&r, tris);
+                    (Event::Refract, Some((r, if inside { None } else { Some(mi) })))
                 }
-                Material::Mirror => {}
-                Material::Solid => {return;}
-            };
+            }
+            Material::Mirror => (Event::Reflect, Some((Ray::new(hit, reflected), medium))),
+            Material::Solid => (Event::Absorbed, None),
+        };
+
+        let this = segments.len();
+        segments.push(Segment {
+            ray: *r,
+            length: d,
+            parent,
+            entity: Some(mi),
+            triangle: Some(ti),
+            normal: Some(n),
+            event,
+            medium,
+            index,
+        });
+
+        // Trace the rest, giving up on rays caught bouncing around
+        if let Some((r, medium)) = next {
+            if segments.len() < MAX_SEGMENTS {
+                self.trace_ray(&r, tris, medium, Some(this), segments)?;
+            }
         }
+
+        Ok(())
     }
+}
+
+/// Worlds for the tests of the analyses
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::lenses::{Lens, LensSide};
+
+    /// An untilted lens of glass of index 1.5 at `pos`
+    pub fn lens(radius: f32, left: LensSide, right: LensSide, thickness: Option<f32>, pos: [f32; 3]) -> Lens {
+        Lens { radius, left, right, pos, index: 1.5, thickness, tilt: [0.0, 0.0], min_width: 0.1 }
+    }
+
+    /// The lenses lit by `lights` and traced, with nothing around them
+    pub fn traced(lenses: &[Lens], lights: Vec<Light>) -> World {
+        let mut world = World::new();
+        for lens in lenses {
+            let model = world.add_model(lens.tesselate());
+            let material = Material::Glass(lens.index);
+            world.add_entity(model, lens.transform(), material, material.color());
+        }
+        for light in lights {
+            world.add_light(light);
+        }
+
+        world.build_kdtree();
+        world.trace().unwrap();
+        world
+    }
+}
 
-    fn add_ray(&mut self, r: &Ray, d: f32) {
-        // When adding a ray, create a new model with the tesselated ray
-        // and then add the model to the lines array
-        let ts = r.tesselate(d);
-        let m = self.add_model(ts);
-        self.lines.push(m)
+#[cfg(test)]
+mod tests {
+    use super::testing::{lens, traced};
+    use super::*;
+    use crate::lenses::LensSide;
+
+    #[test]
+    fn a_laser_through_a_lens_refracts_twice_and_escapes() {
+        let l = lens(1.0, LensSide::Convex(0.1), LensSide::Convex(0.1), Some(0.3), [0.0; 3]);
+        let (left, right) = l.vertices();
+        let world = traced(std::slice::from_ref(&l), vec![Light::Laser([2.0, 0.0, 0.0], [-1.0, 0.0, 0.0])]);
+
+        assert_eq!(world.paths.len(), 1);
+        let path = &world.paths[0];
+        let events = path.segments.iter().map(|s| s.event).collect::<Vec<_>>();
+        let media = path.segments.iter().map(|s| s.medium).collect::<Vec<_>>();
+        assert_eq!(events, [Event::Refract, Event::Refract, Event::Escaped]);
+        assert_eq!(media, [None, Some(0), None]);
+        assert_eq!(path.segments.iter().map(|s| s.parent).collect::<Vec<_>>(), [None, Some(0), Some(1)]);
+        assert_eq!(path.last(), Some(2));
+        assert_eq!(path.exit(), Some(2));
+        assert_eq!(path.next(2), None);
+
+        // Along the axis the ray crosses the vertices head on
+        let outside = 2.0 - left;
+        let glass = left - right;
+        assert!((path.segments[1].length - glass).abs() < 1e-4);
+        assert!((path.optical_length_to(0) - outside).abs() < 1e-4);
+        assert!((path.optical_length_to(1) - (outside + l.index * glass)).abs() < 1e-4);
+        assert!(path.optical_length_to(2).is_infinite());
     }
 }